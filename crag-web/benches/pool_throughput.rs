@@ -0,0 +1,79 @@
+//! Throughput of the worker pool on blocking handlers.
+//!
+//! Each request sleeps in its handler, standing in for a database call or
+//! other blocking I/O. With the pool running jobs concurrently, N workers
+//! should serve close to N times the requests per second of one worker.
+//!
+//! Run with `cargo bench -p crag-web --bench pool_throughput`.
+
+use anyhow::Result;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crag_web::{handler, response, server::Server};
+
+/// Time each handler blocks for
+const HANDLER_DELAY: Duration = Duration::from_millis(20);
+/// Concurrent clients, enough to keep the largest pool busy
+const CLIENTS: usize = 16;
+const REQUESTS_PER_CLIENT: usize = 10;
+
+fn main() -> Result<()> {
+    let mut baseline = None;
+    println!("workers  requests/s  speedup");
+    for workers in [1, 2, 4, 8] {
+        let throughput = measure(workers)?;
+        let baseline = *baseline.get_or_insert(throughput);
+        println!(
+            "{workers:>7}  {throughput:>10.1}  {:>6.2}x",
+            throughput / baseline
+        );
+    }
+    Ok(())
+}
+
+/// Requests per second served by a pool of `workers` threads
+fn measure(workers: usize) -> Result<f64> {
+    let server = Server::build()
+        .register_error_handler(handler::default_error_404_handler)?
+        .get("/work", |_req| {
+            thread::sleep(HANDLER_DELAY);
+            Ok(response::Response::Ok(
+                b"done".to_vec(),
+                response::ContentType::PLAIN,
+            ))
+        })?
+        .finalize(("127.0.0.1", 0), workers)?;
+    let addr = server.local_addr()?;
+    let shutdown = server.shutdown_handle();
+    let server = thread::spawn(move || server.run());
+
+    let start = Instant::now();
+    let clients: Vec<_> = (0..CLIENTS)
+        .map(|_| thread::spawn(move || run_client(addr)))
+        .collect();
+    for client in clients {
+        client.join().expect("client panicked")?;
+    }
+    let elapsed = start.elapsed();
+
+    shutdown.shutdown();
+    server.join().expect("server panicked")?;
+    Ok((CLIENTS * REQUESTS_PER_CLIENT) as f64 / elapsed.as_secs_f64())
+}
+
+fn run_client(addr: SocketAddr) -> Result<()> {
+    for _ in 0..REQUESTS_PER_CLIENT {
+        let mut stream = TcpStream::connect(addr)?;
+        stream.write_all(b"GET /work HTTP/1.1\r\nHost: bench\r\nConnection: close\r\n\r\n")?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response)?;
+        anyhow::ensure!(
+            response.starts_with("HTTP/1.1 200"),
+            "Unexpected response: {response}"
+        );
+    }
+    Ok(())
+}
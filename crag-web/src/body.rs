@@ -0,0 +1,366 @@
+use anyhow::{bail, Result};
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+use crate::chunked;
+
+/// Size of the chunks read from readers and files when streaming them
+const BLOCK_SIZE: u64 = 64 * 1024;
+
+/// Iterator of body chunks written with `Transfer-Encoding: chunked`
+pub type ChunkStream = Box<dyn Iterator<Item = Result<Vec<u8>>> + Send + 'static>;
+
+/// Response body that is either held in memory or streamed to the client
+pub enum Body {
+    /// Fully materialized bytes
+    Bytes(Vec<u8>),
+    /// Source of exactly `length` bytes, sent with a Content-Length
+    Reader {
+        reader: Box<dyn Read + Send + 'static>,
+        length: u64,
+    },
+    /// `length` bytes of a file starting at `offset`, sent with a
+    /// Content-Length. Unlike a reader, parts of it can be served for
+    /// range requests without reading what comes before.
+    File {
+        file: File,
+        offset: u64,
+        length: u64,
+    },
+    /// Chunks of unknown total length, sent chunked
+    Chunks(ChunkStream),
+}
+
+impl Body {
+    pub fn empty() -> Body {
+        Body::Bytes(vec![])
+    }
+
+    /// Stream `length` bytes from `reader`, e.g. an open file
+    pub fn from_reader(reader: impl Read + Send + 'static, length: u64) -> Body {
+        Body::Reader {
+            reader: Box::new(reader),
+            length,
+        }
+    }
+
+    /// Stream the whole of an open file
+    pub fn from_file(file: File) -> io::Result<Body> {
+        let length = file.metadata()?.len();
+        Ok(Body::File {
+            file,
+            offset: 0,
+            length,
+        })
+    }
+
+    /// Stream chunks produced by an iterator, e.g. rows of a generated report
+    pub fn from_chunks<I, C>(chunks: I) -> Body
+    where
+        I: IntoIterator<Item = Result<C>>,
+        I::IntoIter: Send + 'static,
+        C: Into<Vec<u8>>,
+    {
+        Body::Chunks(Box::new(
+            chunks.into_iter().map(|chunk| chunk.map(Into::into)),
+        ))
+    }
+
+    /// Length in bytes, `None` for chunked bodies
+    pub fn len(&self) -> Option<u64> {
+        match self {
+            Body::Bytes(bytes) => Some(bytes.len() as u64),
+            Body::Reader { length, .. } | Body::File { length, .. } => Some(*length),
+            Body::Chunks(_) => None,
+        }
+    }
+
+    /// Whether the body is known to be empty
+    pub fn is_empty(&self) -> bool {
+        self.len() == Some(0)
+    }
+
+    /// Bytes of an in-memory body
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            Body::Bytes(bytes) => Some(bytes),
+            _ => None,
+        }
+    }
+
+    /// Read the whole body into memory
+    pub fn into_bytes(self) -> Result<Vec<u8>> {
+        let mut bytes = vec![];
+        match self {
+            Body::Bytes(body) => bytes = body,
+            Body::Reader { reader, length } => copy_exact(reader, length, &mut bytes)?,
+            Body::File {
+                mut file,
+                offset,
+                length,
+            } => {
+                file.seek(SeekFrom::Start(offset))?;
+                copy_exact(file, length, &mut bytes)?
+            }
+            Body::Chunks(chunks) => {
+                for chunk in chunks {
+                    bytes.extend(chunk?);
+                }
+            }
+        }
+        Ok(bytes)
+    }
+
+    /// Turn the body into a stream of chunks, reading readers and files a
+    /// block at a time
+    pub fn into_chunks(self) -> ChunkStream {
+        match self {
+            Body::Bytes(bytes) => Box::new(std::iter::once(Ok(bytes))),
+            Body::Reader { reader, length } => blocks(reader, length),
+            Body::File {
+                mut file,
+                offset,
+                length,
+            } => match file.seek(SeekFrom::Start(offset)) {
+                Ok(_) => blocks(file, length),
+                Err(err) => Box::new(std::iter::once(Err(err.into()))),
+            },
+            Body::Chunks(chunks) => chunks,
+        }
+    }
+
+    /// Like `into_chunks`, with chunked bodies framed as they are sent:
+    /// each chunk encoded, followed by the last chunk
+    pub(crate) fn into_frames(self) -> ChunkStream {
+        match self {
+            Body::Chunks(chunks) => Box::new(
+                chunks
+                    .map(|chunk| {
+                        let mut framed = vec![];
+                        chunked::write_chunk(&mut framed, &chunk?)?;
+                        Ok(framed)
+                    })
+                    .chain(std::iter::once_with(|| {
+                        let mut last = vec![];
+                        chunked::write_last_chunk(&mut last)?;
+                        Ok(last)
+                    })),
+            ),
+            body => body.into_chunks(),
+        }
+    }
+
+    /// Write the body with the framing advertised by the response head
+    pub(crate) fn write_to(self, writer: &mut impl Write) -> Result<()> {
+        match self {
+            Body::Bytes(bytes) => writer.write_all(&bytes)?,
+            Body::Reader { reader, length } => copy_exact(reader, length, writer)?,
+            Body::File {
+                mut file,
+                offset,
+                length,
+            } => {
+                file.seek(SeekFrom::Start(offset))?;
+                copy_exact(file, length, writer)?
+            }
+            body @ Body::Chunks(_) => {
+                for frame in body.into_frames() {
+                    writer.write_all(&frame?)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Copy exactly `length` bytes, erring if the reader ends early since the
+/// client was promised that many bytes
+fn copy_exact(reader: impl Read, length: u64, writer: &mut impl Write) -> Result<()> {
+    let copied = io::copy(&mut reader.take(length), writer)?;
+    if copied != length {
+        bail!("Body reader ended after {copied} of {length} bytes");
+    }
+    Ok(())
+}
+
+/// Chunks of exactly `length` bytes read from `reader`
+fn blocks(mut reader: impl Read + Send + 'static, mut length: u64) -> ChunkStream {
+    Box::new(std::iter::from_fn(move || {
+        if length == 0 {
+            return None;
+        }
+        let mut block = vec![0; length.min(BLOCK_SIZE) as usize];
+        match reader.read_exact(&mut block) {
+            Ok(()) => {
+                length -= block.len() as u64;
+                Some(Ok(block))
+            }
+            Err(err) => {
+                length = 0;
+                Some(Err(err.into()))
+            }
+        }
+    }))
+}
+
+impl Default for Body {
+    fn default() -> Self {
+        Body::empty()
+    }
+}
+
+impl fmt::Debug for Body {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Body::Bytes(bytes) => f.debug_tuple("Bytes").field(&bytes.len()).finish(),
+            Body::Reader { length, .. } => {
+                f.debug_struct("Reader").field("length", length).finish()
+            }
+            Body::File { offset, length, .. } => f
+                .debug_struct("File")
+                .field("offset", offset)
+                .field("length", length)
+                .finish(),
+            Body::Chunks(_) => f.write_str("Chunks"),
+        }
+    }
+}
+
+impl From<Vec<u8>> for Body {
+    fn from(bytes: Vec<u8>) -> Body {
+        Body::Bytes(bytes)
+    }
+}
+
+impl From<&[u8]> for Body {
+    fn from(bytes: &[u8]) -> Body {
+        Body::Bytes(bytes.to_vec())
+    }
+}
+
+impl From<String> for Body {
+    fn from(body: String) -> Body {
+        Body::Bytes(body.into_bytes())
+    }
+}
+
+impl From<&str> for Body {
+    fn from(body: &str) -> Body {
+        Body::Bytes(body.as_bytes().to_vec())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bytes_body() {
+        let body = Body::from("hello");
+        assert_eq!(body.len(), Some(5));
+        assert_eq!(body.as_bytes(), Some(&b"hello"[..]));
+
+        let mut written = vec![];
+        body.write_to(&mut written).unwrap();
+        assert_eq!(written, b"hello");
+    }
+
+    #[test]
+    fn test_reader_body() {
+        let body = Body::from_reader(&b"hello world"[..], 5);
+        assert_eq!(body.len(), Some(5));
+        assert!(body.as_bytes().is_none());
+
+        let mut written = vec![];
+        body.write_to(&mut written).unwrap();
+        assert_eq!(written, b"hello");
+    }
+
+    #[test]
+    fn test_reader_body_too_short() {
+        let body = Body::from_reader(&b"hi"[..], 5);
+        let mut written = vec![];
+        assert!(body.write_to(&mut written).is_err());
+    }
+
+    #[test]
+    fn test_file_body() {
+        let path = std::env::temp_dir().join(format!("crag-web-body-{}", std::process::id()));
+        std::fs::write(&path, "hello world").unwrap();
+
+        let body = Body::from_file(File::open(&path).unwrap()).unwrap();
+        assert_eq!(body.len(), Some(11));
+        assert_eq!(body.into_bytes().unwrap(), b"hello world");
+
+        let body = Body::File {
+            file: File::open(&path).unwrap(),
+            offset: 6,
+            length: 5,
+        };
+        let mut written = vec![];
+        body.write_to(&mut written).unwrap();
+        assert_eq!(written, b"world");
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_into_chunks() {
+        let data = vec![7; BLOCK_SIZE as usize + 1];
+        let chunks: Vec<Vec<u8>> =
+            Body::from_reader(io::Cursor::new(data.clone()), data.len() as u64)
+                .into_chunks()
+                .collect::<Result<_>>()
+                .unwrap();
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(chunks.concat(), data);
+
+        // a reader ending early is an error
+        let mut chunks = Body::from_reader(&b"abc"[..], 5).into_chunks();
+        assert!(chunks.next().unwrap().is_err());
+        assert!(chunks.next().is_none());
+    }
+
+    #[test]
+    fn test_chunked_body() {
+        let body = Body::from_chunks(vec![Ok("Wiki"), Ok("pedia")]);
+        assert_eq!(body.len(), None);
+        assert!(!body.is_empty());
+
+        let mut written = vec![];
+        body.write_to(&mut written).unwrap();
+        assert_eq!(written, b"4\r\nWiki\r\n5\r\npedia\r\n0\r\n\r\n");
+    }
+
+    #[test]
+    fn test_into_frames() {
+        let frames: Vec<Vec<u8>> = Body::from_chunks(vec![Ok("Wiki"), Ok(""), Ok("pedia")])
+            .into_frames()
+            .collect::<Result<_>>()
+            .unwrap();
+        assert_eq!(frames.concat(), b"4\r\nWiki\r\n5\r\npedia\r\n0\r\n\r\n");
+
+        let frames: Vec<Vec<u8>> = Body::from("hello")
+            .into_frames()
+            .collect::<Result<_>>()
+            .unwrap();
+        assert_eq!(frames.concat(), b"hello");
+    }
+
+    #[test]
+    fn test_chunked_body_error() {
+        let body = Body::from_chunks(vec![Ok("Wiki"), Err(anyhow::anyhow!("failed"))]);
+        let mut written = vec![];
+        assert!(body.write_to(&mut written).is_err());
+    }
+
+    #[test]
+    fn test_into_bytes() {
+        let body = Body::from_chunks(vec![Ok("Wiki"), Ok("pedia")]);
+        assert_eq!(body.into_bytes().unwrap(), b"Wikipedia");
+
+        let body = Body::from_reader(&b"hello"[..], 5);
+        assert_eq!(body.into_bytes().unwrap(), b"hello");
+    }
+}
@@ -0,0 +1,347 @@
+use std::io::{self, Write};
+use std::mem;
+
+use crate::error::{Error, Result};
+use crate::headers::Headers;
+
+/// Longest chunk-size line (size plus extensions) or trailer line accepted
+const MAX_LINE_LENGTH: usize = 4096;
+
+/// Most hex digits accepted in a chunk size, enough for a 64-bit size
+const MAX_SIZE_DIGITS: usize = 16;
+
+/// Decoder of a `Transfer-Encoding: chunked` body as described in RFC 9112
+/// section 7.1. It is fed the bytes as they arrive rather than reading
+/// them, so the blocking and async servers decode bodies the same way.
+///
+/// Chunk extensions are ignored. Errors if the decoded body would be larger
+/// than `max_size`, and with `HeaderTooLarge` if there are more than
+/// `max_trailers` trailer fields or they take more than `max_trailer_size`
+/// bytes, line endings included.
+pub struct Decoder {
+    max_size: usize,
+    max_trailers: usize,
+    max_trailer_size: usize,
+    body: Vec<u8>,
+    trailers: Headers,
+    // size of the trailer lines so far
+    trailer_size: usize,
+    line: LineBuffer,
+    state: DecodeState,
+}
+
+enum DecodeState {
+    // reading a chunk-size line
+    Size,
+    // bytes of chunk data still to come
+    Data(usize),
+    // bytes of the CRLF after the chunk data already seen
+    DataEnd(usize),
+    // reading the trailer section after the last chunk
+    Trailers,
+}
+
+impl Decoder {
+    pub fn new(max_size: usize, max_trailers: usize, max_trailer_size: usize) -> Self {
+        Decoder {
+            max_size,
+            max_trailers,
+            max_trailer_size,
+            body: vec![],
+            trailers: Headers::new(),
+            trailer_size: 0,
+            line: LineBuffer::default(),
+            state: DecodeState::Size,
+        }
+    }
+
+    /// Decode `input`, the bytes received and not used yet, which is empty
+    /// at the end of the stream. Returns how many bytes were used and
+    /// whether the body is complete; bytes after its end are left unused.
+    pub fn decode(&mut self, input: &[u8]) -> Result<(usize, bool)> {
+        if input.is_empty() {
+            return Err(match self.state {
+                DecodeState::Size | DecodeState::Trailers => unterminated_line(),
+                DecodeState::Data(_) | DecodeState::DataEnd(_) => {
+                    io::Error::from(io::ErrorKind::UnexpectedEof).into()
+                }
+            });
+        }
+
+        let mut used = 0;
+        while used < input.len() {
+            let rest = &input[used..];
+            match self.state {
+                DecodeState::Size | DecodeState::Trailers => {
+                    let (max_length, too_long) = self.line_limit();
+                    let (taken, line) = self.line.take(rest, max_length, too_long)?;
+                    used += taken;
+                    let Some(line) = line else {
+                        continue;
+                    };
+                    let line_size = line.len();
+                    let line = String::from_utf8(line)
+                        .map_err(|_| Error::malformed("Chunked body line is not valid UTF-8"))?;
+                    let line = line.trim_end_matches(['\r', '\n']);
+                    if let DecodeState::Size = self.state {
+                        self.start_chunk(line)?;
+                    } else if line.is_empty() {
+                        return Ok((used, true));
+                    } else {
+                        if self.trailers.len() >= self.max_trailers {
+                            return Err(Error::HeaderTooLarge);
+                        }
+                        self.trailer_size += line_size;
+                        let (name, value) = Headers::parse_line(line)?;
+                        self.trailers.insert(name, value);
+                    }
+                }
+                DecodeState::Data(remaining) => {
+                    let taken = remaining.min(rest.len());
+                    self.body.extend_from_slice(&rest[..taken]);
+                    used += taken;
+                    self.state = match remaining - taken {
+                        0 => DecodeState::DataEnd(0),
+                        remaining => DecodeState::Data(remaining),
+                    };
+                }
+                DecodeState::DataEnd(seen) => {
+                    if rest[0] != b"\r\n"[seen] {
+                        return Err(Error::malformed("Chunk data not terminated by CRLF"));
+                    }
+                    used += 1;
+                    self.state = match seen {
+                        0 => DecodeState::DataEnd(1),
+                        _ => DecodeState::Size,
+                    };
+                }
+            }
+        }
+        Ok((used, false))
+    }
+
+    /// Longest next line accepted and the error for a longer one
+    fn line_limit(&self) -> (usize, fn() -> Error) {
+        match self.state {
+            DecodeState::Trailers => (self.max_trailer_size - self.trailer_size, || {
+                Error::HeaderTooLarge
+            }),
+            _ => (MAX_LINE_LENGTH, unterminated_line),
+        }
+    }
+
+    /// Decoded bytes and trailer fields of a complete body
+    pub fn finish(self) -> (Vec<u8>, Headers) {
+        (self.body, self.trailers)
+    }
+
+    fn start_chunk(&mut self, line: &str) -> Result<()> {
+        // chunk-size [ BWS ; chunk-ext ], where chunk-size is 1*HEXDIG.
+        // Anything looser, e.g. a sign or leading whitespace, could be read
+        // differently by a proxy in front of the server.
+        let size = match line.split_once(';') {
+            Some((size, _)) => size.trim_end_matches([' ', '\t']),
+            None => line,
+        };
+        let invalid = || Error::malformed(format!("Invalid chunk size: {size:?}"));
+        if size.is_empty()
+            || size.len() > MAX_SIZE_DIGITS
+            || !size.bytes().all(|b| b.is_ascii_hexdigit())
+        {
+            return Err(invalid());
+        }
+        let size = usize::from_str_radix(size, 16).map_err(|_| invalid())?;
+
+        if size == 0 {
+            self.state = DecodeState::Trailers;
+            return Ok(());
+        }
+        if size > self.max_size - self.body.len() {
+            return Err(Error::BodyTooLarge {
+                limit: self.max_size,
+            });
+        }
+        self.body.reserve(size);
+        self.state = DecodeState::Data(size);
+        Ok(())
+    }
+}
+
+fn unterminated_line() -> Error {
+    Error::malformed("Unterminated or too long line in chunked body")
+}
+
+/// Line of a request that may arrive split across several reads
+#[derive(Default)]
+pub struct LineBuffer {
+    line: Vec<u8>,
+}
+
+impl LineBuffer {
+    /// Take bytes from `input` up to and including the next `\n`. Returns
+    /// how many were used and, once it is complete, the line with its line
+    /// ending. Errs with `too_long()` once the line exceeds `max_length`
+    /// bytes.
+    pub fn take(
+        &mut self,
+        input: &[u8],
+        max_length: usize,
+        too_long: impl FnOnce() -> Error,
+    ) -> Result<(usize, Option<Vec<u8>>)> {
+        let (chunk, done) = match input.iter().position(|&b| b == b'\n') {
+            Some(end) => (&input[..=end], true),
+            None => (input, false),
+        };
+        if self.line.len() + chunk.len() > max_length {
+            return Err(too_long());
+        }
+        self.line.extend_from_slice(chunk);
+        Ok((chunk.len(), done.then(|| mem::take(&mut self.line))))
+    }
+
+    /// Bytes of a line the stream ended in the middle of, if any
+    pub fn take_partial(&mut self) -> Option<Vec<u8>> {
+        (!self.line.is_empty()).then(|| mem::take(&mut self.line))
+    }
+}
+
+/// Write one chunk of a chunked body. Empty chunks are skipped since a
+/// zero-length chunk marks the end of the body.
+pub fn write_chunk(writer: &mut impl Write, data: &[u8]) -> Result<()> {
+    if data.is_empty() {
+        return Ok(());
+    }
+    write!(writer, "{:X}\r\n", data.len())?;
+    writer.write_all(data)?;
+    writer.write_all(b"\r\n")?;
+    Ok(())
+}
+
+/// Write the last chunk, with no trailers, ending a chunked body
+pub fn write_last_chunk(writer: &mut impl Write) -> Result<()> {
+    writer.write_all(b"0\r\n\r\n")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader};
+
+    /// Decode a body from a reader, leaving what follows it unread
+    fn decode(reader: &mut impl BufRead, max_size: usize) -> Result<(Vec<u8>, Headers)> {
+        let mut decoder = Decoder::new(max_size, 100, MAX_LINE_LENGTH);
+        loop {
+            let (used, done) = decoder.decode(reader.fill_buf()?)?;
+            reader.consume(used);
+            if done {
+                return Ok(decoder.finish());
+            }
+        }
+    }
+
+    #[test]
+    fn test_decode() {
+        let mut body: &[u8] =
+            b"4\r\nWiki\r\n5\r\npedia\r\nE\r\n in\r\n\r\nchunks.\r\n0\r\n\r\nnext";
+        let (decoded, trailers) = decode(&mut body, 1024).unwrap();
+        assert_eq!(decoded, b"Wikipedia in\r\n\r\nchunks.");
+        assert!(trailers.is_empty());
+        // the rest of the stream is left for the next request
+        assert_eq!(body, b"next");
+    }
+
+    #[test]
+    fn test_decode_extensions_and_trailers() {
+        let mut body: &[u8] =
+            b"3;name=value\r\nabc\r\n0;last\r\nExpires: never\r\nX-Checksum: 123\r\n\r\n";
+        let (decoded, trailers) = decode(&mut body, 1024).unwrap();
+        assert_eq!(decoded, b"abc");
+        assert_eq!(trailers.get("expires"), Some("never"));
+        assert_eq!(trailers.get("X-Checksum"), Some("123"));
+    }
+
+    #[test]
+    fn test_decode_max_size() {
+        let mut body: &[u8] = b"4\r\nWiki\r\n5\r\npedia\r\n0\r\n\r\n";
+        assert!(decode(&mut body, 8).is_err());
+
+        let mut body: &[u8] = b"4\r\nWiki\r\n5\r\npedia\r\n0\r\n\r\n";
+        assert!(decode(&mut body, 9).is_ok());
+    }
+
+    #[test]
+    fn test_encode_round_trip() {
+        let mut encoded = vec![];
+        write_chunk(&mut encoded, b"Hello, ").unwrap();
+        write_chunk(&mut encoded, b"").unwrap();
+        write_chunk(&mut encoded, &[b'x'; 26]).unwrap();
+        write_last_chunk(&mut encoded).unwrap();
+        assert!(encoded.starts_with(b"7\r\nHello, \r\n1A\r\n"));
+
+        let (decoded, _) = decode(&mut encoded.as_slice(), 1024).unwrap();
+        assert_eq!(decoded, [&b"Hello, "[..], &[b'x'; 26]].concat());
+    }
+
+    #[test]
+    fn test_decode_invalid() {
+        let mut body: &[u8] = b"zz\r\nWiki\r\n0\r\n\r\n";
+        assert!(decode(&mut body, 1024).is_err());
+
+        let mut body: &[u8] = b"4\r\nWikiX\r\n0\r\n\r\n";
+        assert!(decode(&mut body, 1024).is_err());
+
+        // stream ends before the last chunk
+        let mut body: &[u8] = b"4\r\nWiki\r\n";
+        assert!(decode(&mut body, 1024).is_err());
+
+        // chunk size overflows
+        let mut body: &[u8] = b"FFFFFFFFFFFFFFFFFFFF\r\n";
+        assert!(decode(&mut body, 1024).is_err());
+
+        // chunk-size is 1*HEXDIG, without a sign or surrounding whitespace
+        for size in ["+a", " a", "a ", "", ";ext", "0x4", "00000000000000004"] {
+            let encoded = format!("{size}\r\n0123456789\r\n0\r\n\r\n");
+            let res = decode(&mut encoded.as_bytes(), 1024);
+            assert!(matches!(res, Err(Error::MalformedRequest(_))), "{size:?}");
+        }
+        // whitespace is only allowed before an extension
+        let mut body: &[u8] = b"4 ;ext\r\nWiki\r\n0\r\n\r\n";
+        assert_eq!(decode(&mut body, 1024).unwrap().0, b"Wiki");
+    }
+
+    #[test]
+    fn test_decode_split_reads() {
+        // bytes arriving one at a time decode the same
+        let encoded: &[u8] =
+            b"3;name=value\r\nabc\r\n4\r\ndefg\r\n0\r\nX-Checksum: 123\r\n\r\nnext";
+        let mut reader = BufReader::with_capacity(1, encoded);
+        let (decoded, trailers) = decode(&mut reader, 1024).unwrap();
+        assert_eq!(decoded, b"abcdefg");
+        assert_eq!(trailers.get("X-Checksum"), Some("123"));
+        assert_eq!(reader.fill_buf().unwrap(), b"n");
+
+        let mut reader = BufReader::with_capacity(1, &b"4\r\nWikiX\r\n0\r\n\r\n"[..]);
+        assert!(decode(&mut reader, 1024).is_err());
+    }
+
+    #[test]
+    fn test_decode_trailer_limits() {
+        let decode_with = |body: &[u8], max_trailers, max_trailer_size| {
+            let mut decoder = Decoder::new(1024, max_trailers, max_trailer_size);
+            decoder.decode(body).map(|_| decoder.finish())
+        };
+
+        let body = b"0\r\nA: 1\r\nB: 2\r\n\r\n";
+        assert!(decode_with(body, 2, 14).is_ok());
+        assert!(matches!(
+            decode_with(body, 1, 14),
+            Err(Error::HeaderTooLarge)
+        ));
+        // the limit includes line endings, and the last line has to fit
+        assert!(matches!(
+            decode_with(body, 2, 13),
+            Err(Error::HeaderTooLarge)
+        ));
+    }
+}
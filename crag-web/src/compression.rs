@@ -0,0 +1,511 @@
+use anyhow::{bail, Result};
+use flate2::write::{GzEncoder, ZlibEncoder};
+use std::io::{self, Write};
+use std::mem;
+
+use crate::body::{Body, ChunkStream};
+use crate::conditional::ETag;
+use crate::headers::Headers;
+use crate::request::RequestHead;
+use crate::response::Response;
+use crate::status::StatusCode;
+
+/// Content codings responses can be compressed with
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Encoding {
+    Brotli,
+    Gzip,
+    Deflate,
+}
+
+impl Encoding {
+    /// Name used in Accept-Encoding and Content-Encoding
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gzip",
+            Encoding::Deflate => "deflate",
+        }
+    }
+
+    /// Extension of precompressed files in this coding, appended to the
+    /// name of the original file
+    pub fn extension(&self) -> Option<&'static str> {
+        match self {
+            Encoding::Brotli => Some("br"),
+            Encoding::Gzip => Some("gz"),
+            Encoding::Deflate => None,
+        }
+    }
+
+    fn matches(&self, coding: &str) -> bool {
+        coding.eq_ignore_ascii_case(self.as_str())
+            || (*self == Encoding::Gzip && coding.eq_ignore_ascii_case("x-gzip"))
+    }
+}
+
+/// Pick the coding of `available` the client prefers according to an
+/// Accept-Encoding value, RFC 9110 section 12.5.3.
+///
+/// Codings with the same weight are chosen in the order of `available`.
+/// Returns `None` when the response should not be compressed: the client
+/// accepts none of the codings or explicitly prefers identity.
+pub fn negotiate(accept_encoding: &str, available: &[Encoding]) -> Option<Encoding> {
+    let mut weights = vec![];
+    for item in accept_encoding.split(',') {
+        let mut params = item.split(';').map(str::trim);
+        let coding = params.next().unwrap_or_default();
+        if coding.is_empty() {
+            continue;
+        }
+        let mut weight = Some(1.0);
+        for param in params {
+            if let Some(q) = param
+                .strip_prefix("q=")
+                .or_else(|| param.strip_prefix("Q="))
+            {
+                weight = q.parse::<f32>().ok().filter(|q| (0.0..=1.0).contains(q));
+            }
+        }
+        // items with an invalid weight are ignored
+        if let Some(weight) = weight {
+            weights.push((coding, weight));
+        }
+    }
+    let weight_of = |matches: &dyn Fn(&str) -> bool| {
+        weights
+            .iter()
+            .find(|(coding, _)| matches(coding))
+            .or_else(|| weights.iter().find(|(coding, _)| *coding == "*"))
+            .map(|(_, weight)| *weight)
+    };
+
+    let mut best: Option<(Encoding, f32)> = None;
+    for encoding in available {
+        let weight = weight_of(&|coding| encoding.matches(coding)).unwrap_or(0.0);
+        if weight > 0.0 && best.is_none_or(|(_, best)| weight > best) {
+            best = Some((*encoding, weight));
+        }
+    }
+    let (encoding, weight) = best?;
+
+    // identity is acceptable unless excluded, but only preferred over a
+    // coding when the client says so
+    match weight_of(&|coding| coding.eq_ignore_ascii_case("identity")) {
+        Some(identity) if identity > weight => None,
+        _ => Some(encoding),
+    }
+}
+
+/// Whether responses of a content type are worth compressing. Images,
+/// video, archives and fonts other than SVG are already compressed.
+pub fn is_compressible(content_type: &str) -> bool {
+    let essence = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    essence.starts_with("text/")
+        || essence.ends_with("+json")
+        || essence.ends_with("+xml")
+        || matches!(
+            essence.as_str(),
+            "application/javascript"
+                | "application/json"
+                | "application/xml"
+                | "application/wasm"
+                | "image/svg+xml"
+                | "image/x-icon"
+                | "font/ttf"
+                | "font/otf"
+        )
+}
+
+/// Add `token` to the Vary header unless it's already listed
+pub(crate) fn add_vary(headers: &mut Headers, token: &str) {
+    if headers.contains_token("Vary", token) || headers.contains_token("Vary", "*") {
+        return;
+    }
+    let vary = match headers.get("Vary") {
+        Some(vary) => format!("{vary}, {token}"),
+        None => token.to_owned(),
+    };
+    headers.set("Vary", vary);
+}
+
+/// Settings for compressing responses, enabled with
+/// `ServerBuilder::compression`
+#[derive(Clone, Debug)]
+pub struct Compression {
+    encodings: Vec<Encoding>,
+    min_size: u64,
+    level: u32,
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Compression::new()
+    }
+}
+
+impl Compression {
+    /// Brotli, gzip and deflate in that order of preference, for bodies of
+    /// at least 1 KiB at level 6
+    pub fn new() -> Self {
+        Compression {
+            encodings: vec![Encoding::Brotli, Encoding::Gzip, Encoding::Deflate],
+            min_size: 1024,
+            level: 6,
+        }
+    }
+
+    /// Codings to offer, most preferred first
+    pub fn encodings(mut self, encodings: &[Encoding]) -> Result<Self> {
+        if encodings.is_empty() {
+            bail!("At least one encoding is required");
+        }
+        self.encodings = encodings.to_vec();
+        Ok(self)
+    }
+
+    /// Smallest body compressed, in bytes. Compressing tiny bodies costs
+    /// more than it saves. Streamed bodies of unknown length are always
+    /// compressed.
+    pub fn min_size(mut self, bytes: u64) -> Self {
+        self.min_size = bytes;
+        self
+    }
+
+    /// Level from 0, fastest, to 9, smallest, used as the quality of brotli
+    pub fn level(mut self, level: u32) -> Result<Self> {
+        if level > 9 {
+            bail!("Compression level must be between 0 and 9, got {level}");
+        }
+        self.level = level;
+        Ok(self)
+    }
+
+    /// Compress the body of a response with the coding negotiated from the
+    /// request's Accept-Encoding, adding `Vary: Accept-Encoding` to every
+    /// response that could have been compressed.
+    ///
+    /// File bodies are sent as they are, since compressing them as a stream
+    /// would lose their length and with it range requests. Static files
+    /// can be served precompressed instead.
+    pub(crate) fn apply(&self, request: &RequestHead, mut response: Response) -> Response {
+        if matches!(response.body, Body::File { .. })
+            || response.status.forbids_body()
+            || response.status == StatusCode::PARTIAL_CONTENT
+            || response.headers.contains("Content-Encoding")
+            || response
+                .headers
+                .contains_token("Cache-Control", "no-transform")
+            || !response.headers.content_type().is_some_and(is_compressible)
+            || response.body.len().is_some_and(|len| len < self.min_size)
+        {
+            return response;
+        }
+        add_vary(&mut response.headers, "Accept-Encoding");
+
+        let Some(encoding) = request
+            .headers
+            .get("Accept-Encoding")
+            .and_then(|accept| negotiate(accept, &self.encodings))
+        else {
+            return response;
+        };
+
+        let body = mem::take(&mut response.body);
+        response.body = match body {
+            Body::Bytes(bytes) => match compress(encoding, self.level, &bytes) {
+                Ok(compressed) => Body::Bytes(compressed),
+                Err(_) => {
+                    response.body = Body::Bytes(bytes);
+                    return response;
+                }
+            },
+            body => Body::Chunks(Box::new(Compressed {
+                chunks: body.into_chunks(),
+                encoder: Some(Encoder::new(encoding, self.level)),
+            })),
+        };
+        response
+            .headers
+            .insert("Content-Encoding", encoding.as_str());
+
+        // the compressed body isn't byte-for-byte the representation a
+        // strong tag promised, but still semantically equivalent
+        if let Some(etag) = response.etag().filter(|etag| !etag.is_weak()) {
+            if let Ok(weak) = ETag::weak(etag.tag()) {
+                response.headers.set("ETag", weak.to_string());
+            }
+        }
+        response
+    }
+}
+
+/// Compress a whole body in memory
+fn compress(encoding: Encoding, level: u32, bytes: &[u8]) -> io::Result<Vec<u8>> {
+    let mut encoder = Encoder::new(encoding, level);
+    let mut compressed = encoder.write(bytes)?;
+    compressed.extend(encoder.finish()?);
+    Ok(compressed)
+}
+
+/// Encoder writing into a buffer that is drained after every write
+enum Encoder {
+    Brotli(Box<brotli::CompressorWriter<Vec<u8>>>),
+    Gzip(GzEncoder<Vec<u8>>),
+    Deflate(ZlibEncoder<Vec<u8>>),
+}
+
+impl Encoder {
+    fn new(encoding: Encoding, level: u32) -> Encoder {
+        let level_of = flate2::Compression::new(level);
+        match encoding {
+            // 4 KiB buffer and the default 4 MiB window
+            Encoding::Brotli => Encoder::Brotli(Box::new(brotli::CompressorWriter::new(
+                vec![],
+                4096,
+                level,
+                22,
+            ))),
+            Encoding::Gzip => Encoder::Gzip(GzEncoder::new(vec![], level_of)),
+            // the deflate coding is the zlib format, RFC 9110 section 8.4.1.2
+            Encoding::Deflate => Encoder::Deflate(ZlibEncoder::new(vec![], level_of)),
+        }
+    }
+
+    /// Compress `data`, returning the output produced so far
+    fn write(&mut self, data: &[u8]) -> io::Result<Vec<u8>> {
+        let output = match self {
+            Encoder::Brotli(encoder) => {
+                encoder.write_all(data)?;
+                encoder.get_mut()
+            }
+            Encoder::Gzip(encoder) => {
+                encoder.write_all(data)?;
+                encoder.get_mut()
+            }
+            Encoder::Deflate(encoder) => {
+                encoder.write_all(data)?;
+                encoder.get_mut()
+            }
+        };
+        Ok(mem::take(output))
+    }
+
+    /// Flush the remaining output and the trailer of the format
+    fn finish(self) -> io::Result<Vec<u8>> {
+        match self {
+            Encoder::Brotli(encoder) => Ok(encoder.into_inner()),
+            Encoder::Gzip(encoder) => encoder.finish(),
+            Encoder::Deflate(encoder) => encoder.finish(),
+        }
+    }
+}
+
+/// Chunks of a streamed body compressed as they are produced
+struct Compressed {
+    chunks: ChunkStream,
+    // taken once the source ends and the trailer was returned
+    encoder: Option<Encoder>,
+}
+
+impl Iterator for Compressed {
+    type Item = Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let encoder = self.encoder.as_mut()?;
+        loop {
+            match self.chunks.next() {
+                Some(Ok(chunk)) => match encoder.write(&chunk) {
+                    // the encoder buffers small inputs
+                    Ok(output) if output.is_empty() => continue,
+                    result => return Some(result.map_err(Into::into)),
+                },
+                Some(Err(err)) => {
+                    self.encoder = None;
+                    return Some(Err(err));
+                }
+                None => {
+                    let encoder = self.encoder.take()?;
+                    return Some(encoder.finish().map_err(Into::into));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::methods::Method;
+    use crate::request::Request;
+    use std::io::Read;
+
+    fn get(accept_encoding: Option<&str>) -> RequestHead {
+        let mut request = Request::new(Method::GET, "/".into());
+        if let Some(accept_encoding) = accept_encoding {
+            request.headers.insert("Accept-Encoding", accept_encoding);
+        }
+        request.head()
+    }
+
+    fn response(content_type: &str, body: impl Into<Body>) -> Response {
+        Response::build()
+            .header("Content-Type", content_type)
+            .body(body)
+            .finalize()
+            .unwrap()
+    }
+
+    fn decode(encoding: &str, bytes: &[u8]) -> String {
+        let mut decoded = String::new();
+        match encoding {
+            "br" => brotli::Decompressor::new(bytes, 4096)
+                .read_to_string(&mut decoded)
+                .unwrap(),
+            "gzip" => flate2::read::GzDecoder::new(bytes)
+                .read_to_string(&mut decoded)
+                .unwrap(),
+            "deflate" => flate2::read::ZlibDecoder::new(bytes)
+                .read_to_string(&mut decoded)
+                .unwrap(),
+            _ => panic!("unexpected encoding {encoding}"),
+        };
+        decoded
+    }
+
+    #[test]
+    fn test_negotiate() {
+        let all = [Encoding::Brotli, Encoding::Gzip, Encoding::Deflate];
+        let cases = [
+            ("gzip, deflate, br", Some(Encoding::Brotli)),
+            ("gzip, br;q=0.5", Some(Encoding::Gzip)),
+            ("deflate;q=0.9, gzip;q=0.1", Some(Encoding::Deflate)),
+            ("x-gzip", Some(Encoding::Gzip)),
+            ("*", Some(Encoding::Brotli)),
+            ("br;q=0, *;q=0.5", Some(Encoding::Gzip)),
+            ("identity", None),
+            ("", None),
+            ("gzip;q=0", None),
+            ("gzip;q=0.5, identity", None),
+            ("gzip;q=2", None),
+        ];
+        for (accept, expected) in cases {
+            assert_eq!(negotiate(accept, &all), expected, "{accept}");
+        }
+        assert_eq!(negotiate("br", &[Encoding::Gzip]), None);
+    }
+
+    #[test]
+    fn test_is_compressible() {
+        assert!(is_compressible("text/html; charset=utf-8"));
+        assert!(is_compressible("application/json"));
+        assert!(is_compressible("application/ld+json"));
+        assert!(is_compressible("image/svg+xml"));
+        assert!(!is_compressible("image/png"));
+        assert!(!is_compressible("application/gzip"));
+    }
+
+    #[test]
+    fn test_add_vary() {
+        let mut headers = Headers::new();
+        add_vary(&mut headers, "Accept-Encoding");
+        add_vary(&mut headers, "Accept-Encoding");
+        assert_eq!(headers.get("Vary"), Some("Accept-Encoding"));
+
+        let mut headers: Headers = [("Vary", "Origin")].into_iter().collect();
+        add_vary(&mut headers, "Accept-Encoding");
+        assert_eq!(headers.get("Vary"), Some("Origin, Accept-Encoding"));
+    }
+
+    #[test]
+    fn test_compresses_bytes() {
+        let body = "hello world ".repeat(200);
+        for encoding in ["br", "gzip", "deflate"] {
+            let response = Compression::new()
+                .apply(&get(Some(encoding)), response("text/plain", body.as_str()));
+            assert_eq!(response.headers.get("Content-Encoding"), Some(encoding));
+            assert_eq!(response.headers.get("Vary"), Some("Accept-Encoding"));
+            let compressed = response.body.as_bytes().unwrap();
+            assert!(compressed.len() < body.len());
+            assert_eq!(decode(encoding, compressed), body);
+        }
+    }
+
+    #[test]
+    fn test_compresses_streams() {
+        let chunks = (0..100).map(|i| Ok(format!("line {i}\n")));
+        let response = Compression::new().apply(
+            &get(Some("gzip")),
+            response("text/csv", Body::from_chunks(chunks)),
+        );
+        assert_eq!(response.headers.get("Content-Encoding"), Some("gzip"));
+        assert_eq!(response.body.len(), None);
+
+        let compressed = response.body.into_bytes().unwrap();
+        let expected: String = (0..100).map(|i| format!("line {i}\n")).collect();
+        assert_eq!(decode("gzip", &compressed), expected);
+    }
+
+    #[test]
+    fn test_skipped_responses() {
+        let compression = Compression::new().min_size(10);
+        let long = "x".repeat(100);
+
+        // below the threshold
+        let skipped = compression.apply(&get(Some("gzip")), response("text/plain", "short"));
+        assert!(!skipped.headers.contains("Content-Encoding"));
+        assert!(!skipped.headers.contains("Vary"));
+
+        // not compressible
+        let skipped = compression.apply(&get(Some("gzip")), response("image/png", long.as_str()));
+        assert!(!skipped.headers.contains("Content-Encoding"));
+
+        // not accepted, but the response varies on the header
+        let skipped = compression.apply(&get(None), response("text/plain", long.as_str()));
+        assert!(!skipped.headers.contains("Content-Encoding"));
+        assert_eq!(skipped.headers.get("Vary"), Some("Accept-Encoding"));
+
+        // already encoded
+        let mut encoded = response("text/plain", long.as_str());
+        encoded.headers.insert("Content-Encoding", "br");
+        let encoded = compression.apply(&get(Some("gzip")), encoded);
+        assert_eq!(encoded.headers.get("Content-Encoding"), Some("br"));
+
+        // files keep their length, so ranges of them can still be served
+        let path = std::env::temp_dir().join(format!("crag-web-compress-{}", std::process::id()));
+        std::fs::write(&path, &long).unwrap();
+        let file = Response::build()
+            .header("Content-Type", "text/plain")
+            .body(Body::from_file(std::fs::File::open(&path).unwrap()).unwrap())
+            .finalize()
+            .unwrap();
+        let file = compression.apply(&get(Some("gzip")), file);
+        assert!(!file.headers.contains("Content-Encoding"));
+        assert_eq!(file.body.len(), Some(100));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_weakens_strong_etag() {
+        let response = Response::build()
+            .header("Content-Type", "text/plain")
+            .etag(&ETag::strong("v1").unwrap())
+            .body("x".repeat(2000))
+            .finalize()
+            .unwrap();
+        let response = Compression::new().apply(&get(Some("gzip")), response);
+        assert_eq!(response.headers.get("ETag"), Some("W/\"v1\""));
+    }
+
+    #[test]
+    fn test_builder_validation() {
+        assert!(Compression::new().encodings(&[]).is_err());
+        assert!(Compression::new().level(10).is_err());
+        assert!(Compression::new().level(9).is_ok());
+    }
+}
@@ -0,0 +1,441 @@
+use anyhow::{bail, Result};
+use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::date;
+use crate::headers::Headers;
+use crate::methods::Method;
+use crate::request::RequestHead;
+use crate::response::Response;
+use crate::status::StatusCode;
+
+/// Entity tag validator sent in the ETag header, RFC 9110 section 8.8.3
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ETag {
+    weak: bool,
+    tag: String,
+}
+
+impl ETag {
+    /// Strong tag, for representations that are byte-for-byte identical
+    /// whenever the tag is
+    pub fn strong(tag: impl Into<String>) -> Result<ETag> {
+        ETag::new(false, tag.into())
+    }
+
+    /// Weak tag, for representations that are only semantically equivalent
+    /// whenever the tag is
+    pub fn weak(tag: impl Into<String>) -> Result<ETag> {
+        ETag::new(true, tag.into())
+    }
+
+    fn new(weak: bool, tag: String) -> Result<ETag> {
+        // etagc: any visible character except DQUOTE
+        if !tag.bytes().all(|b| b == b'!' || (b'#'..=b'~').contains(&b)) {
+            bail!("Invalid entity tag: {tag:?}");
+        }
+        Ok(ETag { weak, tag })
+    }
+
+    /// Strong tag derived from a hash of the body
+    pub fn from_bytes(bytes: &[u8]) -> ETag {
+        // FNV-1a, stable across builds unlike the std hasher
+        let hash = bytes.iter().fold(0xcbf29ce484222325_u64, |hash, byte| {
+            (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+        });
+        ETag {
+            weak: false,
+            tag: format!("{hash:016x}"),
+        }
+    }
+
+    /// Weak tag derived from the size and modification time of a file
+    pub fn from_metadata(length: u64, modified: SystemTime) -> ETag {
+        let modified = modified.duration_since(UNIX_EPOCH).unwrap_or_default();
+        ETag {
+            weak: true,
+            tag: format!("{length:x}-{:x}", modified.as_secs()),
+        }
+    }
+
+    /// Parse a single `"tag"` or `W/"tag"`
+    pub fn parse(value: &str) -> Result<ETag> {
+        match parse_list(value.trim()) {
+            Some(mut tags) if tags.len() == 1 => Ok(tags.remove(0)),
+            _ => bail!("Invalid entity tag: {value:?}"),
+        }
+    }
+
+    pub fn is_weak(&self) -> bool {
+        self.weak
+    }
+
+    pub fn tag(&self) -> &str {
+        &self.tag
+    }
+
+    /// Strong comparison: both tags are strong and equal
+    pub fn strong_eq(&self, other: &ETag) -> bool {
+        !self.weak && !other.weak && self.tag == other.tag
+    }
+
+    /// Weak comparison: the tags are equal, ignoring weakness
+    pub fn weak_eq(&self, other: &ETag) -> bool {
+        self.tag == other.tag
+    }
+}
+
+impl fmt::Display for ETag {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.weak {
+            f.write_str("W/")?;
+        }
+        write!(f, "\"{}\"", self.tag)
+    }
+}
+
+/// Parse a comma-separated list of entity tags. Tags may contain commas, so
+/// the list can't simply be split.
+fn parse_list(mut value: &str) -> Option<Vec<ETag>> {
+    let mut tags = vec![];
+    loop {
+        value = value.trim_start_matches([' ', '\t', ',']);
+        if value.is_empty() {
+            return Some(tags);
+        }
+        let weak = value.starts_with("W/");
+        if weak {
+            value = &value[2..];
+        }
+        let rest = value.strip_prefix('"')?;
+        let end = rest.find('"')?;
+        tags.push(ETag::new(weak, rest[..end].to_owned()).ok()?);
+        value = &rest[end + 1..];
+        if !value.is_empty() && !value.starts_with([' ', '\t', ',']) {
+            return None;
+        }
+    }
+}
+
+/// Whether any If-Match or If-None-Match value matches `etag`, `*` matching
+/// any current representation. Unparsable values match nothing.
+fn matches(headers: &Headers, name: &str, etag: Option<&ETag>, strong: bool) -> bool {
+    headers.get_all(name).any(|value| {
+        if value.trim() == "*" {
+            return true;
+        }
+        let (Some(etag), Some(tags)) = (etag, parse_list(value)) else {
+            return false;
+        };
+        tags.iter().any(|tag| {
+            if strong {
+                tag.strong_eq(etag)
+            } else {
+                tag.weak_eq(etag)
+            }
+        })
+    })
+}
+
+/// Evaluate the preconditions of a request against the validators of the
+/// selected representation, in the order of RFC 9110 section 13.2.2.
+///
+/// Returns the status to answer with instead of the representation: 412
+/// when a precondition fails and 304 when a GET or HEAD is fresh in the
+/// client's cache. Only GET and HEAD responses carrying an ETag or
+/// Last-Modified are checked automatically, after the handler ran. Handlers
+/// of state-changing methods must call this themselves before making
+/// changes, so a failed precondition doesn't commit a lost update.
+pub fn evaluate(
+    method: &Method,
+    headers: &Headers,
+    etag: Option<&ETag>,
+    last_modified: Option<SystemTime>,
+) -> Option<StatusCode> {
+    let is_get = matches!(method, Method::GET | Method::HEAD);
+
+    if headers.contains("If-Match") {
+        if !matches(headers, "If-Match", etag, true) {
+            return Some(StatusCode::PRECONDITION_FAILED);
+        }
+    } else if let (Some(since), Some(modified)) = (
+        headers
+            .get("If-Unmodified-Since")
+            .and_then(date::parse_http_date),
+        last_modified,
+    ) {
+        if modified > since {
+            return Some(StatusCode::PRECONDITION_FAILED);
+        }
+    }
+
+    if headers.contains("If-None-Match") {
+        if matches(headers, "If-None-Match", etag, false) {
+            return Some(if is_get {
+                StatusCode::NOT_MODIFIED
+            } else {
+                StatusCode::PRECONDITION_FAILED
+            });
+        }
+    } else if let (true, Some(since), Some(modified)) = (
+        is_get,
+        headers
+            .get("If-Modified-Since")
+            .and_then(date::parse_http_date),
+        last_modified,
+    ) {
+        if modified <= since {
+            return Some(StatusCode::NOT_MODIFIED);
+        }
+    }
+
+    None
+}
+
+/// Headers a 304 response repeats from the response it replaces
+const NOT_MODIFIED_HEADERS: [&str; 7] = [
+    "Cache-Control",
+    "Content-Location",
+    "Date",
+    "ETag",
+    "Expires",
+    "Last-Modified",
+    "Vary",
+];
+
+/// Replace a successful response to a GET or HEAD with 304 or 412 if the
+/// request's preconditions say so. The original body is dropped without
+/// being read.
+///
+/// Other methods are left alone: their handler already made its changes,
+/// so answering 412 afterwards would report a committed change as failed.
+pub(crate) fn apply(request: &RequestHead, response: Response) -> Response {
+    if !response.status.is_success() || !matches!(request.method, Method::GET | Method::HEAD) {
+        return response;
+    }
+
+    let etag = response.etag();
+    let status = evaluate(
+        &request.method,
+        &request.headers,
+        etag.as_ref(),
+        response.last_modified(),
+    );
+    match status {
+        Some(StatusCode::NOT_MODIFIED) => {
+            let mut not_modified = Response::new(StatusCode::NOT_MODIFIED);
+            for (name, value) in response.headers.iter() {
+                if NOT_MODIFIED_HEADERS
+                    .iter()
+                    .any(|header| header.eq_ignore_ascii_case(name))
+                {
+                    not_modified.headers.insert(name, value);
+                }
+            }
+            not_modified
+        }
+        Some(status) => Response::new(status),
+        None => response,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn headers(pairs: &[(&str, &str)]) -> Headers {
+        pairs.iter().copied().collect()
+    }
+
+    fn time(secs: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn test_etag_display_and_parse() {
+        let etag = ETag::strong("abc").unwrap();
+        assert_eq!(etag.to_string(), "\"abc\"");
+        assert_eq!(ETag::parse("\"abc\"").unwrap(), etag);
+
+        let etag = ETag::weak("a,b").unwrap();
+        assert_eq!(etag.to_string(), "W/\"a,b\"");
+        assert_eq!(ETag::parse(" W/\"a,b\" ").unwrap(), etag);
+
+        assert!(ETag::strong("has\"quote").is_err());
+        assert!(ETag::parse("abc").is_err());
+        assert!(ETag::parse("\"a\", \"b\"").is_err());
+    }
+
+    #[test]
+    fn test_etag_comparison() {
+        let strong = ETag::strong("1").unwrap();
+        let weak = ETag::weak("1").unwrap();
+        assert!(strong.strong_eq(&strong));
+        assert!(!strong.strong_eq(&weak));
+        assert!(!weak.strong_eq(&weak));
+        assert!(weak.weak_eq(&strong));
+        assert!(!weak.weak_eq(&ETag::weak("2").unwrap()));
+    }
+
+    #[test]
+    fn test_generated_etags() {
+        assert_eq!(ETag::from_bytes(b"hello"), ETag::from_bytes(b"hello"));
+        assert_ne!(ETag::from_bytes(b"hello"), ETag::from_bytes(b"world"));
+        assert!(!ETag::from_bytes(b"hello").is_weak());
+
+        let etag = ETag::from_metadata(255, time(16));
+        assert!(etag.is_weak());
+        assert_eq!(etag.to_string(), "W/\"ff-10\"");
+    }
+
+    #[test]
+    fn test_parse_list() {
+        let tags = parse_list("\"a\", W/\"b\",\"c,d\"").unwrap();
+        assert_eq!(tags.len(), 3);
+        assert!(tags[1].is_weak());
+        assert_eq!(tags[2].tag(), "c,d");
+
+        assert!(parse_list("\"a\"x").is_none());
+        assert!(parse_list("\"unterminated").is_none());
+    }
+
+    #[test]
+    fn test_if_none_match() {
+        let etag = ETag::weak("v1").unwrap();
+        let evaluate_with = |value: &str, method: Method| {
+            evaluate(
+                &method,
+                &headers(&[("If-None-Match", value)]),
+                Some(&etag),
+                None,
+            )
+        };
+
+        assert_eq!(
+            evaluate_with("\"v1\"", Method::GET),
+            Some(StatusCode::NOT_MODIFIED)
+        );
+        assert_eq!(
+            evaluate_with("\"v0\", W/\"v1\"", Method::HEAD),
+            Some(StatusCode::NOT_MODIFIED)
+        );
+        assert_eq!(evaluate_with("\"v2\"", Method::GET), None);
+        assert_eq!(
+            evaluate_with("*", Method::PUT),
+            Some(StatusCode::PRECONDITION_FAILED)
+        );
+    }
+
+    #[test]
+    fn test_if_match() {
+        let strong = ETag::strong("v1").unwrap();
+        let weak = ETag::weak("v1").unwrap();
+        let request = headers(&[("If-Match", "\"v1\"")]);
+
+        assert_eq!(evaluate(&Method::PUT, &request, Some(&strong), None), None);
+        // If-Match uses strong comparison
+        assert_eq!(
+            evaluate(&Method::PUT, &request, Some(&weak), None),
+            Some(StatusCode::PRECONDITION_FAILED)
+        );
+        assert_eq!(
+            evaluate(&Method::PUT, &request, None, None),
+            Some(StatusCode::PRECONDITION_FAILED)
+        );
+        assert_eq!(
+            evaluate(&Method::PUT, &headers(&[("If-Match", "*")]), None, None),
+            None
+        );
+    }
+
+    #[test]
+    fn test_modified_since() {
+        let modified = Some(time(784111777));
+        let request = headers(&[("If-Modified-Since", "Sun, 06 Nov 1994 08:49:37 GMT")]);
+        assert_eq!(
+            evaluate(&Method::GET, &request, None, modified),
+            Some(StatusCode::NOT_MODIFIED)
+        );
+        assert_eq!(
+            evaluate(&Method::GET, &request, None, Some(time(784111778))),
+            None
+        );
+        // only applies to GET and HEAD
+        assert_eq!(evaluate(&Method::POST, &request, None, modified), None);
+
+        // a date too far in the future is ignored instead of panicking
+        let request = headers(&[(
+            "If-Modified-Since",
+            "Sun, 06 Nov 99999999999999999 08:49:37 GMT",
+        )]);
+        assert_eq!(evaluate(&Method::GET, &request, None, modified), None);
+
+        // If-None-Match takes precedence
+        let request = headers(&[
+            ("If-None-Match", "\"other\""),
+            ("If-Modified-Since", "Sun, 06 Nov 1994 08:49:37 GMT"),
+        ]);
+        let etag = ETag::strong("current").unwrap();
+        assert_eq!(
+            evaluate(&Method::GET, &request, Some(&etag), modified),
+            None
+        );
+    }
+
+    #[test]
+    fn test_unmodified_since() {
+        let request = headers(&[("If-Unmodified-Since", "Sun, 06 Nov 1994 08:49:37 GMT")]);
+        assert_eq!(
+            evaluate(&Method::DELETE, &request, None, Some(time(784111777))),
+            None
+        );
+        assert_eq!(
+            evaluate(&Method::DELETE, &request, None, Some(time(784111778))),
+            Some(StatusCode::PRECONDITION_FAILED)
+        );
+    }
+
+    #[test]
+    fn test_apply_not_modified() {
+        let mut request = crate::request::Request::new(Method::GET, "/".into());
+        request.headers.insert("If-None-Match", "\"v1\"");
+
+        let response = Response::build()
+            .etag(&ETag::strong("v1").unwrap())
+            .header("Cache-Control", "max-age=60")
+            .header("Content-Type", "text/plain")
+            .body("hello")
+            .finalize()
+            .unwrap();
+
+        let response = apply(&request.head(), response);
+        assert_eq!(response.status, StatusCode::NOT_MODIFIED);
+        assert_eq!(response.headers.get("ETag"), Some("\"v1\""));
+        assert_eq!(response.headers.get("Cache-Control"), Some("max-age=60"));
+        assert!(!response.headers.contains("Content-Type"));
+        assert!(response.body.is_empty());
+    }
+
+    #[test]
+    fn test_apply_skips_unsafe_methods() {
+        let mut request = crate::request::Request::new(Method::PUT, "/".into());
+        request.headers.insert("If-Match", "\"v1\"");
+
+        let response = Response::build()
+            .etag(&ETag::strong("v2").unwrap())
+            .finalize()
+            .unwrap();
+        let response = apply(&request.head(), response);
+        assert_eq!(response.status, StatusCode::OK);
+    }
+
+    #[test]
+    fn test_apply_skips_errors() {
+        let mut request = crate::request::Request::new(Method::GET, "/".into());
+        request.headers.insert("If-Match", "\"v1\"");
+
+        let response = apply(&request.head(), Response::new(StatusCode::NOT_FOUND));
+        assert_eq!(response.status, StatusCode::NOT_FOUND);
+    }
+}
@@ -0,0 +1,150 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const WEEKDAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+/// Format a time as an IMF-fixdate, e.g. `Sun, 06 Nov 1994 08:49:37 GMT`,
+/// the format used by Last-Modified and other HTTP date headers.
+///
+/// Times before 1970 are formatted as the epoch.
+pub fn format_http_date(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let days = secs / 86400;
+    let (year, month, day) = civil_from_days(days as i64);
+    let secs_of_day = secs % 86400;
+
+    format!(
+        "{}, {day:02} {} {year:04} {:02}:{:02}:{:02} GMT",
+        WEEKDAYS[(days % 7) as usize],
+        MONTHS[month as usize - 1],
+        secs_of_day / 3600,
+        secs_of_day / 60 % 60,
+        secs_of_day % 60,
+    )
+}
+
+/// Parse an HTTP date in any of the three formats of RFC 9110 section
+/// 5.6.7: IMF-fixdate, the obsolete RFC 850 format and asctime.
+pub fn parse_http_date(date: &str) -> Option<SystemTime> {
+    let parts: Vec<&str> = date.split_whitespace().collect();
+    let (year, month, day, time) = match parts.as_slice() {
+        // Sun, 06 Nov 1994 08:49:37 GMT
+        [_, day, month, year, time, "GMT"] => (year.parse().ok()?, *month, *day, *time),
+        // Sunday, 06-Nov-94 08:49:37 GMT
+        [_, date, time, "GMT"] => {
+            let mut date = date.split('-');
+            let (day, month, year) = (date.next()?, date.next()?, date.next()?);
+            let year: i64 = year.parse().ok()?;
+            // two digit years more than 50 years in the future are in the past
+            let year = if year < 70 { 2000 + year } else { 1900 + year };
+            (year, month, day, *time)
+        }
+        // Sun Nov  6 08:49:37 1994
+        [_, month, day, time, year] => (year.parse().ok()?, *month, *day, *time),
+        _ => return None,
+    };
+
+    let month = MONTHS.iter().position(|m| *m == month)? as u32 + 1;
+    let day: u32 = day.parse().ok()?;
+    // four digit years, which keeps the arithmetic below from overflowing
+    if !(1..=31).contains(&day) || !(1970..=9999).contains(&year) {
+        return None;
+    }
+
+    let mut time = time.split(':').map(|part| part.parse::<u64>().ok());
+    let (hour, minute, second) = (time.next()??, time.next()??, time.next()??);
+    if time.next().is_some() || hour > 23 || minute > 59 || second > 60 {
+        return None;
+    }
+
+    let days = u64::try_from(days_from_civil(year, month, day)).ok()?;
+    let secs = days
+        .checked_mul(86400)?
+        .checked_add(hour * 3600 + minute * 60 + second)?;
+    UNIX_EPOCH.checked_add(Duration::from_secs(secs))
+}
+
+// Conversions between days since 1970-01-01 and civil dates, from Howard
+// Hinnant's "chrono-Compatible Low-Level Date Algorithms".
+
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year.rem_euclid(400);
+    let month = month as i64;
+    let day_of_year =
+        (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days.rem_euclid(146097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Sun, 06 Nov 1994 08:49:37 GMT
+    const EXAMPLE: u64 = 784111777;
+
+    #[test]
+    fn test_format_http_date() {
+        let time = UNIX_EPOCH + Duration::from_secs(EXAMPLE);
+        assert_eq!(format_http_date(time), "Sun, 06 Nov 1994 08:49:37 GMT");
+        assert_eq!(
+            format_http_date(UNIX_EPOCH),
+            "Thu, 01 Jan 1970 00:00:00 GMT"
+        );
+
+        // leap day
+        let time = UNIX_EPOCH + Duration::from_secs(951782400);
+        assert_eq!(format_http_date(time), "Tue, 29 Feb 2000 00:00:00 GMT");
+    }
+
+    #[test]
+    fn test_parse_http_date_formats() {
+        let expected = Some(UNIX_EPOCH + Duration::from_secs(EXAMPLE));
+        assert_eq!(parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT"), expected);
+        assert_eq!(parse_http_date("Sunday, 06-Nov-94 08:49:37 GMT"), expected);
+        assert_eq!(parse_http_date("Sun Nov  6 08:49:37 1994"), expected);
+    }
+
+    #[test]
+    fn test_parse_http_date_invalid() {
+        assert!(parse_http_date("").is_none());
+        assert!(parse_http_date("Sun, 06 Nov 1994 08:49:37 PST").is_none());
+        assert!(parse_http_date("Sun, 06 Foo 1994 08:49:37 GMT").is_none());
+        assert!(parse_http_date("Sun, 06 Nov 1994 25:49:37 GMT").is_none());
+        assert!(parse_http_date("Sun, 06 Nov 1994 08:49 GMT").is_none());
+        // out of range years are rejected rather than overflowing
+        assert!(parse_http_date("Sun, 06 Nov 99999999999999999 08:49:37 GMT").is_none());
+        assert!(parse_http_date("Sun, 06 Nov 10000 08:49:37 GMT").is_none());
+        assert!(parse_http_date("Sun Nov  6 08:49:37 9223372036854775807").is_none());
+    }
+
+    #[test]
+    fn test_round_trip() {
+        // up to the last second of year 9999
+        for secs in [0, 68169600, 951868800, 1700000000, 4102444800, 253402300799] {
+            let time = UNIX_EPOCH + Duration::from_secs(secs);
+            assert_eq!(parse_http_date(&format_http_date(time)), Some(time));
+        }
+    }
+}
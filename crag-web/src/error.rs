@@ -0,0 +1,133 @@
+use std::error;
+use std::fmt;
+use std::io;
+
+use crate::status::StatusCode;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Errors raised while reading a request or serving a connection
+#[derive(Debug)]
+pub enum Error {
+    /// The request line, a header or the body framing could not be parsed
+    MalformedRequest(String),
+    /// The request-target is longer than the server accepts
+    UriTooLong,
+    /// The request uses an HTTP version other than HTTP/1.1
+    UnsupportedVersion(String),
+    /// The method is not a valid token
+    UnknownMethod(String),
+    /// A header line or the header section is larger than the server accepts
+    HeaderTooLarge,
+    /// The body is larger than `limit` bytes
+    BodyTooLarge { limit: usize },
+    /// The client was too slow to send the request
+    Timeout,
+    /// Reading from or writing to the connection failed
+    Io(io::Error),
+    /// A handler, middleware or streamed response body failed
+    Handler(anyhow::Error),
+}
+
+impl Error {
+    /// Status of the response sent to the client for this error
+    pub fn status(&self) -> StatusCode {
+        match self {
+            // a method that isn't a token is a malformed request line, valid
+            // methods without a handler are answered 405 by the router
+            Error::MalformedRequest(_) | Error::UnknownMethod(_) => StatusCode::BAD_REQUEST,
+            Error::UriTooLong => StatusCode::URI_TOO_LONG,
+            Error::UnsupportedVersion(_) => StatusCode::HTTP_VERSION_NOT_SUPPORTED,
+            Error::HeaderTooLarge => StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE,
+            Error::BodyTooLarge { .. } => StatusCode::PAYLOAD_TOO_LARGE,
+            Error::Timeout => StatusCode::REQUEST_TIMEOUT,
+            Error::Io(_) | Error::Handler(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    pub(crate) fn malformed(message: impl Into<String>) -> Self {
+        Error::MalformedRequest(message.into())
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::MalformedRequest(message) => write!(f, "Malformed request: {message}"),
+            Error::UriTooLong => f.write_str("Request-target too long"),
+            Error::UnsupportedVersion(version) => {
+                write!(f, "Unsupported HTTP version: {version}")
+            }
+            Error::UnknownMethod(method) => write!(f, "Unrecognized method: {method}"),
+            Error::HeaderTooLarge => f.write_str("Request header fields too large"),
+            Error::BodyTooLarge { limit } => {
+                write!(f, "Body exceeds maximum size of {limit} bytes")
+            }
+            Error::Timeout => f.write_str("Timed out reading request"),
+            Error::Io(err) => write!(f, "I/O error: {err}"),
+            Error::Handler(err) => write!(f, "Handler error: {err}"),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Error::Io(err) => Some(err),
+            Error::Handler(err) => Some(err.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        match err.kind() {
+            // reads on the connection time out with either kind depending
+            // on the platform
+            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => Error::Timeout,
+            _ => Error::Io(err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_status() {
+        let cases = [
+            (Error::malformed("bad"), 400),
+            (Error::UnknownMethod("G(T".into()), 400),
+            (Error::BodyTooLarge { limit: 1 }, 413),
+            (Error::UriTooLong, 414),
+            (Error::HeaderTooLarge, 431),
+            (Error::UnsupportedVersion("HTTP/1.0".into()), 505),
+            (Error::Timeout, 408),
+            (Error::Io(io::ErrorKind::BrokenPipe.into()), 500),
+            (Error::Handler(anyhow::anyhow!("failed")), 500),
+        ];
+        for (error, status) in cases {
+            assert_eq!(error.status().as_u16(), status, "{error}");
+        }
+    }
+
+    #[test]
+    fn test_from_io_error() {
+        let timeout: Error = io::Error::from(io::ErrorKind::WouldBlock).into();
+        assert!(matches!(timeout, Error::Timeout));
+
+        let eof: Error = io::Error::from(io::ErrorKind::UnexpectedEof).into();
+        assert!(matches!(eof, Error::Io(_)));
+    }
+
+    #[test]
+    fn test_converts_into_anyhow() {
+        let err: anyhow::Error = Error::HeaderTooLarge.into();
+        assert!(matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::HeaderTooLarge)
+        ));
+    }
+}
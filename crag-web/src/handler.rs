@@ -1,5 +1,14 @@
-use crate::request::Request;
+use std::error;
+use std::fmt;
+#[cfg(feature = "async")]
+use std::future::Future;
+#[cfg(feature = "async")]
+use std::pin::Pin;
+use tracing::error;
+
+use crate::request::{Request, RequestHead};
 use crate::response;
+use crate::status::StatusCode;
 
 pub trait Handler {
     fn handle(&self, request: Request) -> anyhow::Result<response::Response>;
@@ -17,13 +26,90 @@ where
 
 pub type BoxedHandler = Box<dyn Handler + Send + Sync + 'static>;
 
+/// Future returned by an `AsyncHandler`
+#[cfg(feature = "async")]
+pub type HandlerFuture =
+    Pin<Box<dyn Future<Output = anyhow::Result<response::Response>> + Send + 'static>>;
+
+/// Handler producing its response asynchronously, served on tokio by
+/// `server::AsyncServer`
+#[cfg(feature = "async")]
+pub trait AsyncHandler {
+    fn handle(&self, request: Request) -> HandlerFuture;
+}
+
+// blanket implementation for all Fn that take a Request and return a future
+#[cfg(feature = "async")]
+impl<F, Fut> AsyncHandler for F
+where
+    F: Fn(Request) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = anyhow::Result<response::Response>> + Send + 'static,
+{
+    fn handle(&self, request: Request) -> HandlerFuture {
+        Box::pin(self(request))
+    }
+}
+
+#[cfg(feature = "async")]
+pub type BoxedAsyncHandler = Box<dyn AsyncHandler + Send + Sync + 'static>;
+
+/// Turns an error returned by a handler or middleware into the response
+/// sent to the client
+pub trait ErrorHandler {
+    fn handle(&self, error: &anyhow::Error, request: &RequestHead) -> response::Response;
+}
+
+// blanket implementation for all Fn that take an error and the request head
+impl<F> ErrorHandler for F
+where
+    F: Fn(&anyhow::Error, &RequestHead) -> response::Response + Send + Sync + 'static,
+{
+    fn handle(&self, error: &anyhow::Error, request: &RequestHead) -> response::Response {
+        self(error, request)
+    }
+}
+
+pub type BoxedErrorHandler = Box<dyn ErrorHandler + Send + Sync + 'static>;
+
+/// Error a handler returns with its request to have it answered by the
+/// error handler registered with `ServerBuilder::register_error_handler`,
+/// as if no route had matched, e.g. when a file it serves doesn't exist
+#[derive(Debug)]
+pub struct NotFound(pub Request);
+
+impl fmt::Display for NotFound {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Not found: {}", self.0.route.route)
+    }
+}
+
+impl error::Error for NotFound {}
+
 const DEFAULT_404: &[u8] = include_bytes!("../static/html/404.html");
+const DEFAULT_500: &[u8] = include_bytes!("../static/html/500.html");
 
 /// Default handler for 404 errors
 pub fn default_error_404_handler(_request: Request) -> anyhow::Result<response::Response> {
     Ok(response::Response::NotFound(DEFAULT_404.into()))
 }
 
+/// Default handler for errors returned by handlers, logs the error and
+/// answers with a generic 500 page that does not leak its details
+pub fn default_server_error_handler(
+    err: &anyhow::Error,
+    request: &RequestHead,
+) -> response::Response {
+    error!(
+        "Error handling {} {}: {:?}",
+        request.method, request.route.route, err
+    );
+    response::Response::with_content(
+        StatusCode::INTERNAL_SERVER_ERROR,
+        DEFAULT_500.into(),
+        response::ContentType::HTML,
+    )
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -34,10 +120,21 @@ mod tests {
             default_error_404_handler(Request::new(crate::methods::Method::GET, "/".into()));
         let expected_body: Vec<u8> = include_bytes!("../static/html/404.html").into();
 
-        assert!(matches!(response, Ok(response::Response::NotFound(_))));
+        let response = response.unwrap();
+        assert_eq!(response.status, crate::status::StatusCode::NOT_FOUND);
+        assert_eq!(response.body.as_bytes(), Some(expected_body.as_slice()));
+    }
+
+    #[test]
+    fn test_default_server_error_handler() {
+        let request = Request::new(crate::methods::Method::GET, "/".into());
+        let response =
+            default_server_error_handler(&anyhow::anyhow!("secret detail"), &request.head());
 
-        if let response::Response::NotFound(body) = response.unwrap() {
-            assert_eq!(body, expected_body);
-        }
+        assert_eq!(response.status, StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(response.headers.content_type(), Some("text/html"));
+        let body = response.body.as_bytes().unwrap();
+        assert_eq!(body, DEFAULT_500);
+        assert!(!String::from_utf8_lossy(body).contains("secret detail"));
     }
 }
@@ -0,0 +1,226 @@
+use crate::error::{Error, Result};
+
+/// Case-insensitive, multi-value collection of HTTP headers.
+///
+/// Headers keep the order and casing they were inserted with, but every
+/// lookup compares names case-insensitively as required by RFC 9110.
+#[derive(Eq, PartialEq, Debug, Default, Clone)]
+pub struct Headers {
+    entries: Vec<(String, String)>,
+}
+
+impl Headers {
+    pub fn new() -> Self {
+        Headers::default()
+    }
+
+    /// Parse a single `Name: value` header line
+    pub fn parse_line(line: impl AsRef<str>) -> Result<(String, String)> {
+        let line = line.as_ref().trim_end_matches(['\r', '\n']);
+
+        let (name, value) = line
+            .split_once(':')
+            .ok_or_else(|| Error::malformed("Invalid header line: missing ':'"))?;
+
+        // whitespace between the field name and colon is not allowed
+        if name.is_empty() || name.contains(char::is_whitespace) {
+            return Err(Error::malformed(format!("Invalid header name: {name:?}")));
+        }
+
+        Ok((name.to_owned(), value.trim().to_owned()))
+    }
+
+    /// Append a value for `name`, keeping any values already present
+    pub fn insert(&mut self, name: impl Into<String>, value: impl Into<String>) {
+        self.entries.push((name.into(), value.into()));
+    }
+
+    /// Replace every value for `name` with a single value
+    pub fn set(&mut self, name: impl Into<String>, value: impl Into<String>) {
+        let name = name.into();
+        self.remove(&name);
+        self.entries.push((name, value.into()));
+    }
+
+    /// Remove every value for `name`
+    pub fn remove(&mut self, name: impl AsRef<str>) {
+        let name = name.as_ref();
+        self.entries.retain(|(n, _)| !n.eq_ignore_ascii_case(name));
+    }
+
+    /// First value for `name`, if any
+    pub fn get(&self, name: impl AsRef<str>) -> Option<&str> {
+        let name = name.as_ref();
+        self.entries
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// All values for `name` in the order they were received
+    pub fn get_all<'a>(&'a self, name: impl AsRef<str> + 'a) -> impl Iterator<Item = &'a str> {
+        self.entries
+            .iter()
+            .filter(move |(n, _)| n.eq_ignore_ascii_case(name.as_ref()))
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn contains(&self, name: impl AsRef<str>) -> bool {
+        self.get(name).is_some()
+    }
+
+    /// Whether any value of the comma-separated header `name` contains
+    /// `token`, e.g. `Connection: keep-alive, Upgrade`
+    pub fn contains_token(&self, name: impl AsRef<str>, token: &str) -> bool {
+        self.get_all(name)
+            .flat_map(|value| value.split(','))
+            .any(|t| t.trim().eq_ignore_ascii_case(token))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.entries.iter().map(|(n, v)| (n.as_str(), v.as_str()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn host(&self) -> Option<&str> {
+        self.get("Host")
+    }
+
+    pub fn content_type(&self) -> Option<&str> {
+        self.get("Content-Type")
+    }
+
+    pub fn accept(&self) -> Option<&str> {
+        self.get("Accept")
+    }
+
+    pub fn authorization(&self) -> Option<&str> {
+        self.get("Authorization")
+    }
+
+    pub fn user_agent(&self) -> Option<&str> {
+        self.get("User-Agent")
+    }
+
+    /// Parsed Content-Length, erring if the header is present but invalid
+    /// or repeated with different values
+    pub fn content_length(&self) -> Result<Option<usize>> {
+        let mut length = None;
+        for value in self.get_all("Content-Length") {
+            let invalid = || Error::malformed(format!("Invalid Content-Length: {value:?}"));
+            // 1*DIGIT, `parse` would also take a sign that a proxy in front
+            // of the server may read differently
+            if value.is_empty() || !value.bytes().all(|b| b.is_ascii_digit()) {
+                return Err(invalid());
+            }
+            let parsed = value.parse::<usize>().map_err(|_| invalid())?;
+            if length.is_some_and(|l| l != parsed) {
+                return Err(Error::malformed("Conflicting Content-Length headers"));
+            }
+            length = Some(parsed);
+        }
+        Ok(length)
+    }
+}
+
+impl<N, V> FromIterator<(N, V)> for Headers
+where
+    N: Into<String>,
+    V: Into<String>,
+{
+    fn from_iter<T: IntoIterator<Item = (N, V)>>(iter: T) -> Self {
+        let mut headers = Headers::new();
+        for (name, value) in iter {
+            headers.insert(name, value);
+        }
+        headers
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_line() {
+        let (name, value) = Headers::parse_line("Host: localhost:8010\r\n").unwrap();
+        assert_eq!(name, "Host");
+        assert_eq!(value, "localhost:8010");
+    }
+
+    #[test]
+    fn test_parse_line_invalid() {
+        assert!(Headers::parse_line("no colon here").is_err());
+        assert!(Headers::parse_line(": empty name").is_err());
+        assert!(Headers::parse_line("Host : space before colon").is_err());
+    }
+
+    #[test]
+    fn test_get_is_case_insensitive() {
+        let mut headers = Headers::new();
+        headers.insert("Content-Type", "text/html");
+        assert_eq!(headers.get("content-type"), Some("text/html"));
+        assert_eq!(headers.get("CONTENT-TYPE"), Some("text/html"));
+        assert_eq!(headers.content_type(), Some("text/html"));
+        assert!(headers.get("Accept").is_none());
+    }
+
+    #[test]
+    fn test_multiple_values() {
+        let mut headers = Headers::new();
+        headers.insert("Accept", "text/html");
+        headers.insert("accept", "application/json");
+        let values: Vec<&str> = headers.get_all("ACCEPT").collect();
+        assert_eq!(values, vec!["text/html", "application/json"]);
+        assert_eq!(headers.accept(), Some("text/html"));
+    }
+
+    #[test]
+    fn test_set_and_remove() {
+        let mut headers = Headers::new();
+        headers.insert("Accept", "text/html");
+        headers.insert("Accept", "application/json");
+        headers.set("accept", "*/*");
+        assert_eq!(headers.get_all("Accept").count(), 1);
+        assert_eq!(headers.accept(), Some("*/*"));
+
+        headers.remove("ACCEPT");
+        assert!(headers.is_empty());
+    }
+
+    #[test]
+    fn test_contains_token() {
+        let headers: Headers = [("Connection", "keep-alive, Upgrade")]
+            .into_iter()
+            .collect();
+        assert!(headers.contains_token("connection", "upgrade"));
+        assert!(headers.contains_token("Connection", "Keep-Alive"));
+        assert!(!headers.contains_token("Connection", "close"));
+    }
+
+    #[test]
+    fn test_content_length() {
+        let headers: Headers = [("Content-Length", "13")].into_iter().collect();
+        assert_eq!(headers.content_length().unwrap(), Some(13));
+
+        let headers = Headers::new();
+        assert_eq!(headers.content_length().unwrap(), None);
+
+        for value in ["abc", "+13", "-1", "", " 13", "1_000"] {
+            let headers: Headers = [("Content-Length", value)].into_iter().collect();
+            assert!(headers.content_length().is_err(), "{value:?}");
+        }
+
+        let headers: Headers = [("Content-Length", "1"), ("Content-Length", "2")]
+            .into_iter()
+            .collect();
+        assert!(headers.content_length().is_err());
+    }
+}
@@ -1,7 +1,21 @@
+pub mod body;
+mod chunked;
+#[cfg(feature = "compression")]
+pub mod compression;
+pub mod conditional;
+pub mod date;
+pub mod error;
 pub mod handler;
+pub mod headers;
 pub mod methods;
+pub mod middleware;
+pub mod query;
+pub mod range;
 pub mod request;
 pub mod response;
 pub mod routes;
 pub mod server;
-mod threadpool;
+pub mod state;
+pub mod static_files;
+pub mod status;
+pub mod threadpool;
@@ -1,5 +1,117 @@
-#[derive(Eq, PartialEq, Debug)]
+use std::fmt;
+
+use crate::error::{Error, Result};
+
+#[derive(Eq, PartialEq, Hash, Clone, Debug)]
 pub enum Method {
     GET,
     POST,
+    PUT,
+    PATCH,
+    DELETE,
+    HEAD,
+    OPTIONS,
+    CONNECT,
+    TRACE,
+    /// Any other method token, e.g. `PROPFIND`
+    Extension(String),
+}
+
+impl Method {
+    /// Parse a method token. Unknown but well-formed tokens become
+    /// `Method::Extension`; methods are case-sensitive.
+    pub fn parse(method: impl AsRef<str>) -> Result<Method> {
+        let method = method.as_ref();
+        let parsed = match method {
+            "GET" => Method::GET,
+            "POST" => Method::POST,
+            "PUT" => Method::PUT,
+            "PATCH" => Method::PATCH,
+            "DELETE" => Method::DELETE,
+            "HEAD" => Method::HEAD,
+            "OPTIONS" => Method::OPTIONS,
+            "CONNECT" => Method::CONNECT,
+            "TRACE" => Method::TRACE,
+            _ if is_token(method) => Method::Extension(method.to_owned()),
+            _ => return Err(Error::UnknownMethod(method.to_owned())),
+        };
+        Ok(parsed)
+    }
+
+    pub fn as_str(&self) -> &str {
+        match self {
+            Method::GET => "GET",
+            Method::POST => "POST",
+            Method::PUT => "PUT",
+            Method::PATCH => "PATCH",
+            Method::DELETE => "DELETE",
+            Method::HEAD => "HEAD",
+            Method::OPTIONS => "OPTIONS",
+            Method::CONNECT => "CONNECT",
+            Method::TRACE => "TRACE",
+            Method::Extension(method) => method,
+        }
+    }
+}
+
+impl fmt::Display for Method {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// RFC 9110 token: one or more visible characters excluding delimiters
+fn is_token(s: &str) -> bool {
+    !s.is_empty()
+        && s.bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_method_display() {
+        assert_eq!(Method::GET.to_string(), "GET");
+        assert_eq!(Method::POST.to_string(), "POST");
+        assert_eq!(Method::Extension("PURGE".into()).to_string(), "PURGE");
+    }
+
+    #[test]
+    fn test_method_parse() {
+        for method in [
+            Method::GET,
+            Method::POST,
+            Method::PUT,
+            Method::PATCH,
+            Method::DELETE,
+            Method::HEAD,
+            Method::OPTIONS,
+            Method::CONNECT,
+            Method::TRACE,
+        ] {
+            assert_eq!(Method::parse(method.as_str()).unwrap(), method);
+        }
+    }
+
+    #[test]
+    fn test_method_parse_extension() {
+        assert_eq!(
+            Method::parse("PROPFIND").unwrap(),
+            Method::Extension("PROPFIND".into())
+        );
+        // methods are case-sensitive
+        assert_eq!(
+            Method::parse("get").unwrap(),
+            Method::Extension("get".into())
+        );
+    }
+
+    #[test]
+    fn test_method_parse_invalid() {
+        assert!(Method::parse("").is_err());
+        assert!(Method::parse("GE(T").is_err());
+        assert!(Method::parse("G\"T").is_err());
+    }
 }
@@ -0,0 +1,154 @@
+use std::time::Instant;
+use tracing::info;
+
+use crate::request::Request;
+use crate::response::Response;
+
+/// Layer wrapped around request handling.
+///
+/// A middleware receives the request and the rest of the chain as `next`.
+/// It may rewrite the request before calling `next.run(request)`, return a
+/// response without calling `next` at all to short-circuit, or post-process
+/// the response returned by `next`.
+pub trait Middleware {
+    fn handle(&self, request: Request, next: Next<'_>) -> anyhow::Result<Response>;
+}
+
+// blanket implementation for all Fn that take a Request and the rest of the chain
+impl<F> Middleware for F
+where
+    F: Fn(Request, Next<'_>) -> anyhow::Result<Response> + Send + Sync + 'static,
+{
+    fn handle(&self, request: Request, next: Next<'_>) -> anyhow::Result<Response> {
+        self(request, next)
+    }
+}
+
+pub type BoxedMiddleware = Box<dyn Middleware + Send + Sync + 'static>;
+
+type Endpoint<'a> = &'a dyn Fn(Request) -> anyhow::Result<Response>;
+
+/// Remaining middleware in the chain followed by the handler
+pub struct Next<'a> {
+    middleware: &'a [BoxedMiddleware],
+    endpoint: Endpoint<'a>,
+}
+
+impl<'a> Next<'a> {
+    pub(crate) fn new(middleware: &'a [BoxedMiddleware], endpoint: Endpoint<'a>) -> Self {
+        Next {
+            middleware,
+            endpoint,
+        }
+    }
+
+    /// Pass the request to the next middleware, or the handler at the end
+    pub fn run(self, request: Request) -> anyhow::Result<Response> {
+        match self.middleware.split_first() {
+            Some((first, rest)) => first.handle(request, Next::new(rest, self.endpoint)),
+            None => (self.endpoint)(request),
+        }
+    }
+}
+
+/// Middleware that logs the method, path, status and duration of every
+/// request through `tracing`
+pub fn logger(request: Request, next: Next<'_>) -> anyhow::Result<Response> {
+    let method = request.method.clone();
+    let path = request.route.route.clone();
+    let start = Instant::now();
+
+    let response = next.run(request)?;
+    info!(
+        "{method} {path} {} {:?}",
+        response.status.as_u16(),
+        start.elapsed()
+    );
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::methods::Method;
+    use crate::response::ContentType;
+    use crate::status::StatusCode;
+
+    fn echo_route(request: Request) -> anyhow::Result<Response> {
+        Ok(Response::Ok(
+            request.route.route.into_bytes(),
+            ContentType::PLAIN,
+        ))
+    }
+
+    fn run(middleware: &[BoxedMiddleware], path: &str) -> Response {
+        Next::new(middleware, &echo_route)
+            .run(Request::new(Method::GET, path.into()))
+            .unwrap()
+    }
+
+    #[test]
+    fn test_empty_chain_runs_endpoint() {
+        let response = run(&[], "/foo");
+        assert_eq!(response.body.as_bytes(), Some(&b"/foo"[..]));
+    }
+
+    #[test]
+    fn test_chain_order() {
+        let middleware: Vec<BoxedMiddleware> = vec![
+            Box::new(|request: Request, next: Next<'_>| {
+                let mut response = next.run(request)?;
+                response.headers.insert("X-Order", "outer");
+                Ok(response)
+            }),
+            Box::new(|request: Request, next: Next<'_>| {
+                let mut response = next.run(request)?;
+                response.headers.insert("X-Order", "inner");
+                Ok(response)
+            }),
+        ];
+
+        let response = run(&middleware, "/");
+        assert_eq!(
+            response.headers.get_all("X-Order").collect::<Vec<_>>(),
+            vec!["inner", "outer"]
+        );
+    }
+
+    #[test]
+    fn test_rewrite_request() {
+        let middleware: Vec<BoxedMiddleware> =
+            vec![Box::new(|mut request: Request, next: Next<'_>| {
+                request.route = "/rewritten".into();
+                next.run(request)
+            })];
+
+        let response = run(&middleware, "/original");
+        assert_eq!(response.body.as_bytes(), Some(&b"/rewritten"[..]));
+    }
+
+    #[test]
+    fn test_short_circuit() {
+        let middleware: Vec<BoxedMiddleware> = vec![
+            Box::new(|_request: Request, _next: Next<'_>| {
+                Ok(Response::new(StatusCode::UNAUTHORIZED))
+            }),
+            Box::new(
+                |_request: Request, _next: Next<'_>| -> anyhow::Result<Response> {
+                    panic!("inner middleware should not run")
+                },
+            ),
+        ];
+
+        let response = run(&middleware, "/");
+        assert_eq!(response.status, StatusCode::UNAUTHORIZED);
+    }
+
+    #[test]
+    fn test_logger_passes_response_through() {
+        let middleware: Vec<BoxedMiddleware> = vec![Box::new(logger)];
+        let response = run(&middleware, "/logged");
+        assert_eq!(response.status, StatusCode::OK);
+        assert_eq!(response.body.as_bytes(), Some(&b"/logged"[..]));
+    }
+}
@@ -0,0 +1,173 @@
+use crate::error::{Error, Result};
+
+/// Decode `%XX` escapes in a path or query component.
+///
+/// When `plus_as_space` is set, `+` is decoded to a space as done by
+/// `application/x-www-form-urlencoded` query strings.
+pub fn percent_decode(input: &str, plus_as_space: bool) -> Result<String> {
+    let bytes = input.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' => {
+                let hex = bytes
+                    .get(i + 1..i + 3)
+                    .and_then(|hex| std::str::from_utf8(hex).ok())
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                    .ok_or_else(|| {
+                        Error::malformed(format!("Invalid percent-encoding in {input:?}"))
+                    })?;
+                decoded.push(hex);
+                i += 3;
+            }
+            b'+' if plus_as_space => {
+                decoded.push(b' ');
+                i += 1;
+            }
+            byte => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+
+    String::from_utf8(decoded)
+        .map_err(|_| Error::malformed(format!("Percent-decoded {input:?} is not valid UTF-8")))
+}
+
+/// Percent-encode every byte except the unreserved characters of RFC 3986
+/// section 2.3, e.g. to put a file name into a link
+pub fn percent_encode(input: &str) -> String {
+    let mut encoded = String::with_capacity(input.len());
+    for byte in input.bytes() {
+        if byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{byte:02X}"));
+        }
+    }
+    encoded
+}
+
+/// Decoded query string parameters. Keys may repeat, e.g. `?tag=a&tag=b`.
+#[derive(Eq, PartialEq, Debug, Default, Clone)]
+pub struct Query {
+    pairs: Vec<(String, String)>,
+}
+
+impl Query {
+    pub fn new() -> Self {
+        Query::default()
+    }
+
+    /// Parse the part of a request-target after the `?`. Pairs without a
+    /// name or with an invalid percent-encoding are skipped, so a bad
+    /// parameter doesn't fail requests to routes that never read it.
+    pub fn parse(query: &str) -> Query {
+        let mut pairs = vec![];
+        for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            match (percent_decode(key, true), percent_decode(value, true)) {
+                (Ok(key), Ok(value)) if !key.is_empty() => pairs.push((key, value)),
+                _ => {}
+            }
+        }
+        Query { pairs }
+    }
+
+    /// First value for `key`, if any
+    pub fn get(&self, key: impl AsRef<str>) -> Option<&str> {
+        let key = key.as_ref();
+        self.pairs
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// All values for `key` in the order they appear
+    pub fn get_all<'a>(&'a self, key: &'a str) -> impl Iterator<Item = &'a str> {
+        self.pairs
+            .iter()
+            .filter(move |(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn contains(&self, key: impl AsRef<str>) -> bool {
+        self.get(key).is_some()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.pairs.iter().map(|(k, v)| (k.as_str(), v.as_str()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.pairs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pairs.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_percent_decode() {
+        assert_eq!(percent_decode("/a%20b", false).unwrap(), "/a b");
+        assert_eq!(percent_decode("a+b", false).unwrap(), "a+b");
+        assert_eq!(percent_decode("a+b", true).unwrap(), "a b");
+        assert_eq!(percent_decode("caf%C3%A9", false).unwrap(), "café");
+    }
+
+    #[test]
+    fn test_percent_decode_invalid() {
+        assert!(percent_decode("%", false).is_err());
+        assert!(percent_decode("%2", false).is_err());
+        assert!(percent_decode("%zz", false).is_err());
+        assert!(percent_decode("%FF", false).is_err());
+    }
+
+    #[test]
+    fn test_percent_encode() {
+        assert_eq!(percent_encode("a b/c.txt"), "a%20b%2Fc.txt");
+        assert_eq!(percent_encode("café"), "caf%C3%A9");
+        let name = "50% off & more";
+        assert_eq!(percent_decode(&percent_encode(name), false).unwrap(), name);
+    }
+
+    #[test]
+    fn test_query_parse() {
+        let query = Query::parse("q=rust+lang&page=2&flag");
+        assert_eq!(query.get("q"), Some("rust lang"));
+        assert_eq!(query.get("page"), Some("2"));
+        assert_eq!(query.get("flag"), Some(""));
+        assert!(query.get("missing").is_none());
+        assert_eq!(query.len(), 3);
+    }
+
+    #[test]
+    fn test_query_repeated_keys() {
+        let query = Query::parse("tag=a&tag=b%26c");
+        assert_eq!(query.get_all("tag").collect::<Vec<_>>(), vec!["a", "b&c"]);
+        assert_eq!(query.get("tag"), Some("a"));
+    }
+
+    #[test]
+    fn test_query_empty() {
+        assert!(Query::parse("").is_empty());
+        assert!(Query::parse("&&").is_empty());
+    }
+
+    #[test]
+    fn test_query_skips_malformed_pairs() {
+        let query = Query::parse("=x&a=1&b=%&c=%FF&d=%zz&e=2");
+        assert_eq!(
+            query.iter().collect::<Vec<_>>(),
+            vec![("a", "1"), ("e", "2")]
+        );
+    }
+}
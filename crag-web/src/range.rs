@@ -0,0 +1,461 @@
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::ops::Range;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::body::Body;
+use crate::conditional::ETag;
+use crate::date;
+use crate::methods::Method;
+use crate::request::RequestHead;
+use crate::response::Response;
+use crate::status::StatusCode;
+
+/// Requests asking for more ranges than this are answered in full, so a
+/// client can't make the server produce huge multipart bodies from a small
+/// representation
+const MAX_RANGES: usize = 32;
+
+/// Size of the blocks a multipart body reads from a file
+const BLOCK_SIZE: usize = 64 * 1024;
+
+/// Parse a Range header value such as `bytes=0-499, -100` against a
+/// representation of `length` bytes, RFC 9110 section 14.1.
+///
+/// Returns `None` when the header should be ignored: it isn't valid, uses
+/// a unit other than bytes or asks for too many ranges. Otherwise returns
+/// the satisfiable ranges in ascending order with overlapping and adjacent
+/// ranges merged, which is empty when none of them are satisfiable.
+pub fn parse_range(value: &str, length: u64) -> Option<Vec<Range<u64>>> {
+    let (unit, specs) = value.trim().split_once('=')?;
+    if !unit.trim().eq_ignore_ascii_case("bytes") {
+        return None;
+    }
+
+    let mut ranges = vec![];
+    let mut count = 0;
+    for spec in specs.split(',').map(str::trim) {
+        if spec.is_empty() {
+            continue;
+        }
+        count += 1;
+        if count > MAX_RANGES {
+            return None;
+        }
+
+        let (first, last) = spec.split_once('-')?;
+        let range = if first.is_empty() {
+            // suffix range: the last `last` bytes
+            let suffix = parse_number(last)?;
+            (suffix > 0 && length > 0).then(|| length.saturating_sub(suffix)..length)
+        } else {
+            let first = parse_number(first)?;
+            let last = if last.is_empty() {
+                u64::MAX
+            } else {
+                parse_number(last)?
+            };
+            if last < first {
+                return None;
+            }
+            (first < length).then(|| first..last.min(length - 1) + 1)
+        };
+        ranges.extend(range);
+    }
+    if count == 0 {
+        return None;
+    }
+
+    ranges.sort_by_key(|range| range.start);
+    let mut merged: Vec<Range<u64>> = Vec::with_capacity(ranges.len());
+    for range in ranges {
+        match merged.last_mut() {
+            Some(last) if range.start <= last.end => last.end = last.end.max(range.end),
+            _ => merged.push(range),
+        }
+    }
+    Some(merged)
+}
+
+fn parse_number(value: &str) -> Option<u64> {
+    if value.is_empty() || !value.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    value.parse().ok()
+}
+
+/// Whether an If-Range value still identifies the selected representation.
+/// Entity tags use strong comparison and dates must match Last-Modified
+/// exactly.
+fn if_range_matches(value: &str, etag: Option<&ETag>, last_modified: Option<SystemTime>) -> bool {
+    let value = value.trim();
+    if value.starts_with('"') || value.starts_with("W/") {
+        match (ETag::parse(value), etag) {
+            (Ok(tag), Some(etag)) => tag.strong_eq(etag),
+            _ => false,
+        }
+    } else {
+        match (date::parse_http_date(value), last_modified) {
+            (Some(date), Some(modified)) => {
+                // Last-Modified only has second precision
+                let secs = |time: SystemTime| {
+                    time.duration_since(UNIX_EPOCH)
+                        .map(|duration| duration.as_secs())
+                };
+                secs(date).ok() == secs(modified).ok()
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Advertise byte range support on successful responses to GET and HEAD
+/// and answer a GET's Range header with 206 or 416.
+///
+/// Only in-memory and file bodies are sliced; responses streamed from a
+/// reader or in chunks, and responses that already set Accept-Ranges or
+/// Content-Range, are left alone.
+pub(crate) fn apply(request: &RequestHead, mut response: Response) -> Response {
+    let is_get = matches!(request.method, Method::GET);
+    if response.status != StatusCode::OK
+        || !(is_get || matches!(request.method, Method::HEAD))
+        || !matches!(response.body, Body::Bytes(_) | Body::File { .. })
+        || response.headers.contains("Accept-Ranges")
+        || response.headers.contains("Content-Range")
+    {
+        return response;
+    }
+    response.headers.insert("Accept-Ranges", "bytes");
+
+    let Some(value) = request.headers.get("Range").filter(|_| is_get) else {
+        return response;
+    };
+    if let Some(if_range) = request.headers.get("If-Range") {
+        if !if_range_matches(if_range, response.etag().as_ref(), response.last_modified()) {
+            return response;
+        }
+    }
+
+    let length = response.body.len().unwrap_or_default();
+    let Some(ranges) = parse_range(value, length) else {
+        return response;
+    };
+    match ranges.as_slice() {
+        [] => {
+            let mut unsatisfiable = Response::new(StatusCode::RANGE_NOT_SATISFIABLE);
+            unsatisfiable
+                .headers
+                .insert("Content-Range", format!("bytes */{length}"));
+            unsatisfiable
+        }
+        [range] => {
+            let range = range.clone();
+            response.status = StatusCode::PARTIAL_CONTENT;
+            response
+                .headers
+                .insert("Content-Range", content_range(&range, length));
+            response.body = slice(response.body, range);
+            response
+        }
+        _ => multipart(response, ranges, length),
+    }
+}
+
+fn content_range(range: &Range<u64>, length: u64) -> String {
+    format!("bytes {}-{}/{length}", range.start, range.end - 1)
+}
+
+fn slice(body: Body, range: Range<u64>) -> Body {
+    match body {
+        Body::Bytes(bytes) => Body::Bytes(bytes[range.start as usize..range.end as usize].to_vec()),
+        Body::File { file, offset, .. } => Body::File {
+            file,
+            offset: offset + range.start,
+            length: range.end - range.start,
+        },
+        body => body,
+    }
+}
+
+/// Boundary separating the parts of a multipart body. It only has to be
+/// unlikely to appear in the representation.
+fn boundary() -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .subsec_nanos();
+    format!(
+        "crag-web-{nanos:08x}{:08x}",
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    )
+}
+
+/// Replace the body with a `multipart/byteranges` body holding each range
+fn multipart(mut response: Response, ranges: Vec<Range<u64>>, length: u64) -> Response {
+    let boundary = boundary();
+    let content_type = response.headers.get("Content-Type").map(str::to_owned);
+
+    let parts = ranges
+        .into_iter()
+        .enumerate()
+        .map(|(i, range)| {
+            let mut head = if i == 0 { "" } else { "\r\n" }.to_owned();
+            head += &format!("--{boundary}\r\n");
+            if let Some(content_type) = &content_type {
+                head += &format!("Content-Type: {content_type}\r\n");
+            }
+            head += &format!("Content-Range: {}\r\n\r\n", content_range(&range, length));
+            (head.into_bytes(), range)
+        })
+        .collect::<Vec<_>>();
+    let closing = format!("\r\n--{boundary}--\r\n").into_bytes();
+
+    response.body = match response.body {
+        Body::Bytes(bytes) => {
+            let mut body = vec![];
+            for (head, range) in parts {
+                body.extend(head);
+                body.extend(&bytes[range.start as usize..range.end as usize]);
+            }
+            body.extend(closing);
+            Body::Bytes(body)
+        }
+        Body::File { file, offset, .. } => Body::Chunks(Box::new(FileParts {
+            file,
+            offset,
+            parts: parts.into_iter(),
+            current: None,
+            closing: Some(closing),
+        })),
+        body => body,
+    };
+    response.status = StatusCode::PARTIAL_CONTENT;
+    response.headers.set(
+        "Content-Type",
+        format!("multipart/byteranges; boundary={boundary}"),
+    );
+    response
+}
+
+/// Chunks of a multipart body whose parts are read from a file one block
+/// at a time
+struct FileParts {
+    file: File,
+    offset: u64,
+    parts: std::vec::IntoIter<(Vec<u8>, Range<u64>)>,
+    /// Part of the file still to be sent from the current range
+    current: Option<Range<u64>>,
+    closing: Option<Vec<u8>>,
+}
+
+impl FileParts {
+    fn read_block(&mut self, range: &mut Range<u64>) -> std::io::Result<Vec<u8>> {
+        let size = (range.end - range.start).min(BLOCK_SIZE as u64) as usize;
+        let mut block = vec![0; size];
+        self.file.seek(SeekFrom::Start(self.offset + range.start))?;
+        self.file.read_exact(&mut block)?;
+        range.start += size as u64;
+        Ok(block)
+    }
+}
+
+impl Iterator for FileParts {
+    type Item = anyhow::Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(mut range) = self.current.take() {
+            if !range.is_empty() {
+                let block = self.read_block(&mut range);
+                self.current = Some(range);
+                return Some(block.map_err(Into::into));
+            }
+        }
+        if let Some((head, range)) = self.parts.next() {
+            self.current = Some(range);
+            return Some(Ok(head));
+        }
+        self.closing.take().map(Ok)
+    }
+}
+
+#[cfg(test)]
+// expected results are lists of ranges, not ranges collected into lists
+#[allow(clippy::single_range_in_vec_init)]
+mod tests {
+    use super::*;
+    use crate::request::Request;
+    use std::time::Duration;
+
+    fn get(headers: &[(&str, &str)]) -> RequestHead {
+        let mut request = Request::new(Method::GET, "/".into());
+        for (name, value) in headers {
+            request.headers.insert(*name, *value);
+        }
+        request.head()
+    }
+
+    fn text(body: &str) -> Response {
+        Response::build()
+            .header("Content-Type", "text/plain")
+            .etag(&ETag::strong("v1").unwrap())
+            .last_modified(UNIX_EPOCH + Duration::from_secs(784111777))
+            .body(body)
+            .finalize()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_parse_range() {
+        assert_eq!(parse_range("bytes=0-499", 1000), Some(vec![0..500]));
+        assert_eq!(parse_range("bytes=500-", 1000), Some(vec![500..1000]));
+        assert_eq!(parse_range("bytes=-200", 1000), Some(vec![800..1000]));
+        assert_eq!(parse_range("bytes=-2000", 1000), Some(vec![0..1000]));
+        assert_eq!(parse_range("bytes=900-1999", 1000), Some(vec![900..1000]));
+        assert_eq!(
+            parse_range("bytes=500-599, 0-99", 1000),
+            Some(vec![0..100, 500..600])
+        );
+        // overlapping and adjacent ranges are merged
+        assert_eq!(
+            parse_range("bytes=0-99, 50-149, 150-199", 1000),
+            Some(vec![0..200])
+        );
+    }
+
+    #[test]
+    fn test_parse_range_unsatisfiable() {
+        assert_eq!(parse_range("bytes=1000-", 1000), Some(vec![]));
+        assert_eq!(parse_range("bytes=-0", 1000), Some(vec![]));
+        assert_eq!(parse_range("bytes=-1", 0), Some(vec![]));
+        // satisfiable ranges are kept
+        assert_eq!(parse_range("bytes=2000-, 0-0", 1000), Some(vec![0..1]));
+    }
+
+    #[test]
+    fn test_parse_range_invalid() {
+        for value in [
+            "",
+            "bytes=",
+            "bytes=5-1",
+            "bytes=a-b",
+            "bytes=+1-2",
+            "bytes=1",
+            "items=0-1",
+        ] {
+            assert_eq!(parse_range(value, 1000), None, "{value}");
+        }
+        let many = format!("bytes={}", vec!["0-0"; MAX_RANGES + 1].join(","));
+        assert_eq!(parse_range(&many, 1000), None);
+    }
+
+    #[test]
+    fn test_single_range() {
+        let response = apply(&get(&[("Range", "bytes=6-")]), text("hello world"));
+        assert_eq!(response.status, StatusCode::PARTIAL_CONTENT);
+        assert_eq!(response.headers.get("Content-Range"), Some("bytes 6-10/11"));
+        assert_eq!(response.headers.get("Content-Type"), Some("text/plain"));
+        assert_eq!(response.body.into_bytes().unwrap(), b"world");
+    }
+
+    #[test]
+    fn test_accept_ranges() {
+        let response = apply(&get(&[]), text("hello"));
+        assert_eq!(response.status, StatusCode::OK);
+        assert_eq!(response.headers.get("Accept-Ranges"), Some("bytes"));
+
+        // streamed bodies can't be sliced
+        let response = Response::build()
+            .body(Body::from_reader(&b"hello"[..], 5))
+            .finalize()
+            .unwrap();
+        let response = apply(&get(&[("Range", "bytes=0-0")]), response);
+        assert_eq!(response.status, StatusCode::OK);
+        assert!(!response.headers.contains("Accept-Ranges"));
+
+        // nor are ranges served for other methods
+        let mut request = Request::new(Method::POST, "/".into());
+        request.headers.insert("Range", "bytes=0-0");
+        let response = apply(&request.head(), text("hello"));
+        assert_eq!(response.status, StatusCode::OK);
+    }
+
+    #[test]
+    fn test_unsatisfiable_range() {
+        let response = apply(&get(&[("Range", "bytes=20-")]), text("hello"));
+        assert_eq!(response.status, StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(response.headers.get("Content-Range"), Some("bytes */5"));
+    }
+
+    #[test]
+    fn test_multipart_ranges() {
+        let response = apply(&get(&[("Range", "bytes=0-0, -1")]), text("hello world"));
+        assert_eq!(response.status, StatusCode::PARTIAL_CONTENT);
+        let content_type = response.headers.get("Content-Type").unwrap().to_owned();
+        let boundary = content_type
+            .strip_prefix("multipart/byteranges; boundary=")
+            .unwrap();
+
+        let expected = format!(
+            "--{boundary}\r\nContent-Type: text/plain\r\nContent-Range: bytes 0-0/11\r\n\r\nh\
+             \r\n--{boundary}\r\nContent-Type: text/plain\r\nContent-Range: bytes 10-10/11\r\n\r\nd\
+             \r\n--{boundary}--\r\n"
+        );
+        assert_eq!(
+            String::from_utf8(response.body.into_bytes().unwrap()).unwrap(),
+            expected
+        );
+    }
+
+    #[test]
+    fn test_if_range() {
+        let cases = [
+            ("\"v1\"", StatusCode::PARTIAL_CONTENT),
+            ("\"v0\"", StatusCode::OK),
+            // weak tags never match
+            ("W/\"v1\"", StatusCode::OK),
+            ("Sun, 06 Nov 1994 08:49:37 GMT", StatusCode::PARTIAL_CONTENT),
+            ("Sun, 06 Nov 1994 08:49:38 GMT", StatusCode::OK),
+        ];
+        for (if_range, status) in cases {
+            let request = get(&[("Range", "bytes=0-0"), ("If-Range", if_range)]);
+            let response = apply(&request, text("hello"));
+            assert_eq!(response.status, status, "{if_range}");
+        }
+    }
+
+    #[test]
+    fn test_file_ranges() {
+        let path = std::env::temp_dir().join(format!("crag-web-range-{}", std::process::id()));
+        let data = "0123456789".repeat(10_000);
+        std::fs::write(&path, &data).unwrap();
+        let file_response = || {
+            Response::build()
+                .body(Body::from_file(File::open(&path).unwrap()).unwrap())
+                .finalize()
+                .unwrap()
+        };
+
+        let response = apply(&get(&[("Range", "bytes=99995-")]), file_response());
+        assert_eq!(
+            response.headers.get("Content-Range"),
+            Some("bytes 99995-99999/100000")
+        );
+        assert_eq!(response.body.into_bytes().unwrap(), b"56789");
+
+        let response = apply(&get(&[("Range", "bytes=3-4, 10-70009")]), file_response());
+        let body = response.body.into_bytes().unwrap();
+        let body = String::from_utf8(body).unwrap();
+        assert!(body.contains("Content-Range: bytes 3-4/100000\r\n\r\n34\r\n"));
+        // the second part spans several blocks
+        let part = format!(
+            "Content-Range: bytes 10-70009/100000\r\n\r\n{}\r\n--",
+            &data[10..70010]
+        );
+        assert!(body.contains(&part));
+        assert!(body.ends_with("--\r\n"));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
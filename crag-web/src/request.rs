@@ -1,15 +1,41 @@
+use crate::error::{self, Error};
+use crate::headers::Headers;
 use crate::methods::Method;
-use crate::routes::Route;
+use crate::query::{self, Query};
+use crate::routes::{Params, Route};
+use crate::state::State;
 
-use anyhow::{bail, Result};
+use std::borrow::Cow;
 
-// TODO: Add enumerated error values to not test based on strings
+use anyhow::{bail, Result};
 
 #[derive(Eq, PartialEq, Debug)]
 pub struct Request {
     pub method: Method,
+    /// Path of the request-target as sent, still percent-encoded, see
+    /// `path` for the decoded path. Route parameters captured from it are
+    /// decoded.
     pub route: Route,
+    /// Parsed query string of the request-target
+    pub query: Query,
+    pub headers: Headers,
+    /// Values captured by `:name` and `*name` segments of the matched route
+    pub params: Params,
     pub body: Option<String>,
+    /// Trailer fields sent after a chunked body
+    pub trailers: Headers,
+    /// Application state registered on the server
+    pub state: State,
+}
+
+/// Method, path, query and headers of a request, kept for error reporting
+/// after the request itself was handed to a handler
+#[derive(Eq, PartialEq, Debug, Clone)]
+pub struct RequestHead {
+    pub method: Method,
+    pub route: Route,
+    pub query: Query,
+    pub headers: Headers,
 }
 
 impl Request {
@@ -17,56 +43,106 @@ impl Request {
         Request {
             method,
             route,
+            query: Query::new(),
+            headers: Headers::new(),
+            params: Params::new(),
             body: None,
+            trailers: Headers::new(),
+            state: State::new(),
         }
     }
 
+    /// Copy of the request's method, path, query and headers
+    pub fn head(&self) -> RequestHead {
+        RequestHead {
+            method: self.method.clone(),
+            route: self.route.clone(),
+            query: self.query.clone(),
+            headers: self.headers.clone(),
+        }
+    }
+
+    /// Percent-decoded path of the request-target. Unlike routing, which
+    /// decodes each segment on its own, an encoded `/` can't be told apart
+    /// from a separator here. A path that can't be decoded, e.g. of a
+    /// request built by hand, is returned as it is.
+    pub fn path(&self) -> Cow<'_, str> {
+        let route = self.route.route.as_str();
+        if !route.contains('%') {
+            return Cow::Borrowed(route);
+        }
+        query::percent_decode(route, false)
+            .map(Cow::Owned)
+            .unwrap_or(Cow::Borrowed(route))
+    }
+
+    /// First value of the query string parameter `name`
+    pub fn query_param(&self, name: impl AsRef<str>) -> Option<&str> {
+        self.query.get(name)
+    }
+
+    /// Value captured for the route parameter `name`
+    pub fn param(&self, name: impl AsRef<str>) -> Option<&str> {
+        self.params.get(name.as_ref()).map(String::as_str)
+    }
+
+    /// Application state of type `T` registered with
+    /// `ServerBuilder::with_state`
+    pub fn state<T: Send + Sync + 'static>(&self) -> Option<&T> {
+        self.state.get()
+    }
+
+    /// First value of the header `name`, compared case-insensitively
+    pub fn header(&self, name: impl AsRef<str>) -> Option<&str> {
+        self.headers.get(name)
+    }
+
     // should this be from implementation instead?
-    pub fn parse(request_line: impl AsRef<str>) -> Result<Request> {
+    pub fn parse(request_line: impl AsRef<str>) -> error::Result<Request> {
         let request_line = request_line.as_ref();
-        println!("{request_line}");
 
         let mut parts = request_line.split_whitespace();
 
         let method = parts
             .next()
-            .ok_or_else(|| anyhow::anyhow!("No method found"))?;
+            .ok_or_else(|| Error::malformed("No method found"))?;
 
-        let route = parts
+        let target = parts
             .next()
-            .ok_or_else(|| anyhow::anyhow!("No URI found"))?;
+            .ok_or_else(|| Error::malformed("No URI found"))?;
 
         let protocol = parts
             .next()
-            .ok_or_else(|| anyhow::anyhow!("No protocol found"))?;
+            .ok_or_else(|| Error::malformed("No protocol found"))?;
 
         if parts.next().is_some() {
-            bail!("Invalid request line: extra values after parts");
+            return Err(Error::malformed(
+                "Invalid request line: extra values after parts",
+            ));
         }
 
         if protocol != "HTTP/1.1" {
-            bail!("Server can only work with HTTP/1.1");
+            return Err(Error::UnsupportedVersion(protocol.to_owned()));
         }
 
-        let method = match method {
-            "GET" => Method::GET,
-            "POST" => Method::POST,
-            _ => bail!("Unrecognized method: {method}"),
-        };
+        let method = Method::parse(method)?;
+
+        // split the request-target into the path used for routing and the query
+        let (path, raw_query) = target.split_once('?').unwrap_or((target, ""));
+        // routing splits the path before decoding its segments, so it is
+        // only checked for valid percent-encoding here
+        query::percent_decode(path, false)?;
+        let mut req = Request::new(method, path.into());
+        req.query = Query::parse(raw_query);
 
-        Ok(Request::new(method, route.into()))
+        Ok(req)
     }
 
     pub fn add_body(&mut self, body: String) -> Result<(), anyhow::Error> {
-        if let &mut Method::POST = &mut self.method {
-            if self.body.is_none() {
-                self.body = Some(body);
-            } else {
-                bail!("Body already exists in request")
-            }
-            // TODO: Figure out if need to bail here?
-            // is it valid for get reqs to ever have a body?
+        if self.body.is_some() {
+            bail!("Body already exists in request")
         }
+        self.body = Some(body);
         Ok(())
     }
 }
@@ -77,85 +153,120 @@ mod tests {
 
     #[test]
     fn test_request_parser_happy_path() {
-        let req = Request::parse(&String::from("GET / HTTP/1.1")).unwrap();
+        let req = Request::parse(String::from("GET / HTTP/1.1")).unwrap();
         assert_eq!(req.method, Method::GET);
         assert_eq!(req.route, "/".into(),);
     }
 
     #[test]
     fn test_missing_verb() {
-        let req = Request::parse(&String::from(""));
+        let req = Request::parse(String::from(""));
         assert!(req.is_err(), "Returned request is: {req:?}");
-        assert!(req.err().unwrap().to_string().contains("No method found"));
+        assert!(
+            matches!(req, Err(Error::MalformedRequest(message)) if message == "No method found")
+        );
+    }
+
+    #[test]
+    fn test_request_parser_all_methods() {
+        for method in ["PUT", "PATCH", "DELETE", "HEAD", "OPTIONS", "TRACE"] {
+            let req = Request::parse(format!("{method} / HTTP/1.1")).unwrap();
+            assert_eq!(req.method.as_str(), method);
+        }
+
+        let req = Request::parse(String::from("PURGE / HTTP/1.1")).unwrap();
+        assert_eq!(req.method, Method::Extension("PURGE".to_owned()));
     }
 
     #[test]
     fn test_request_parser_bad_verbs() {
-        let req = Request::parse(&String::from("FOO / HTTP/1.1"));
+        let req = Request::parse(String::from("F(O / HTTP/1.1"));
         assert!(req.is_err(), "Returned request is: {req:?}");
-        assert!(req
-            .err()
-            .unwrap()
-            .to_string()
-            .contains("Unrecognized method"));
+        assert!(matches!(req, Err(Error::UnknownMethod(method)) if method == "F(O"));
     }
 
     #[test]
     fn test_missing_uri() {
-        let req = Request::parse(&String::from("GET"));
+        let req = Request::parse(String::from("GET"));
         assert!(req.is_err(), "Returned request is: {req:?}");
-        assert!(req.err().unwrap().to_string().contains("No URI found"));
+        assert!(matches!(req, Err(Error::MalformedRequest(message)) if message == "No URI found"));
     }
 
     #[test]
     fn test_missing_protocol() {
-        let req = Request::parse(&String::from("GET /"));
+        let req = Request::parse(String::from("GET /"));
         assert!(req.is_err(), "Returned request is: {req:?}");
-        assert!(req.err().unwrap().to_string().contains("No protocol found"));
+        assert!(
+            matches!(req, Err(Error::MalformedRequest(message)) if message == "No protocol found")
+        );
     }
 
     #[test]
     fn test_bad_protocol_name() {
-        let req = Request::parse(&String::from("GET / HTTP/1.0"));
+        let req = Request::parse(String::from("GET / HTTP/1.0"));
         assert!(req.is_err(), "Returned request is: {req:?}");
-        assert!(req
-            .err()
-            .unwrap()
-            .to_string()
-            .contains("Server can only work with HTTP/1.1"));
+        assert!(matches!(req, Err(Error::UnsupportedVersion(version)) if version == "HTTP/1.0"));
     }
 
     #[test]
     fn test_good_paths() {
-        let req = Request::parse(&String::from("GET / HTTP/1.1")).unwrap();
+        let req = Request::parse(String::from("GET / HTTP/1.1")).unwrap();
         assert_eq!(req.method, Method::GET);
         assert_eq!(req.route, "/".into(),);
 
-        let req = Request::parse(&String::from("GET /foo HTTP/1.1")).unwrap();
+        let req = Request::parse(String::from("GET /foo HTTP/1.1")).unwrap();
         assert_eq!(req.method, Method::GET);
         assert_eq!(req.route, "/foo".into());
 
-        let req = Request::parse(&String::from("GET /foo/bar HTTP/1.1")).unwrap();
+        let req = Request::parse(String::from("GET /foo/bar HTTP/1.1")).unwrap();
         assert_eq!(req.method, Method::GET);
         assert_eq!(req.route, "/foo/bar".into());
+        assert!(matches!(req.path(), Cow::Borrowed("/foo/bar")));
+    }
+
+    #[test]
+    fn test_query_string_split_from_route() {
+        let req = Request::parse("GET /search?q=rust&tag=a&tag=b HTTP/1.1").unwrap();
+        assert_eq!(req.route, "/search".into());
+        assert_eq!(req.query_param("q"), Some("rust"));
+        assert_eq!(req.query.get_all("tag").collect::<Vec<_>>(), vec!["a", "b"]);
+    }
+
+    #[test]
+    fn test_percent_encoded_path_is_kept() {
+        let req = Request::parse("GET /a%20b?name=caf%C3%A9 HTTP/1.1").unwrap();
+        assert_eq!(req.route, "/a%20b".into());
+        assert_eq!(req.path(), "/a b");
+        assert!(matches!(req.path(), Cow::Owned(_)));
+        assert_eq!(req.query_param("name"), Some("café"));
+    }
+
+    #[test]
+    fn test_bad_query_pair_is_skipped() {
+        let req = Request::parse("GET /search?=x&q=rust&bad=%zz HTTP/1.1").unwrap();
+        assert_eq!(req.query_param("q"), Some("rust"));
+        assert_eq!(req.query.len(), 1);
+    }
+
+    #[test]
+    fn test_bad_percent_encoding() {
+        let req = Request::parse("GET /a%zz HTTP/1.1");
+        assert!(req.is_err(), "Returned request is: {req:?}");
+        assert!(matches!(req, Err(Error::MalformedRequest(_))));
     }
 
     #[test]
     fn test_bad_missing_path() {
-        let req = Request::parse(&String::from("GET"));
+        let req = Request::parse(String::from("GET"));
         assert!(req.is_err(), "Returned request is: {req:?}");
-        assert!(req.err().unwrap().to_string().contains("No URI found"));
+        assert!(matches!(req, Err(Error::MalformedRequest(message)) if message == "No URI found"));
     }
 
     #[test]
     fn test_extra_content_in_request() {
-        let req = Request::parse(&String::from("GET / HTTP/1.1 foo"));
+        let req = Request::parse(String::from("GET / HTTP/1.1 foo"));
         assert!(req.is_err(), "Returned request is: {req:?}");
-        assert!(req
-            .err()
-            .unwrap()
-            .to_string()
-            .contains("Invalid request line: extra values after parts"));
+        assert!(matches!(req, Err(Error::MalformedRequest(_))));
     }
 
     #[test]
@@ -163,7 +274,7 @@ mod tests {
         let mut req = Request::new(Method::GET, "/".into());
         let res = req.add_body(String::from("Hello, World!"));
         assert!(res.is_ok());
-        assert!(req.body.is_none());
+        assert_eq!(req.body, Some(String::from("Hello, World!")));
     }
 
     #[test]
@@ -1,8 +1,24 @@
-pub enum Response {
-    Ok(Vec<u8>, ContentType),
-    NotFound(Vec<u8>),
+use anyhow::{bail, Result};
+use std::io::Write;
+use std::time::SystemTime;
+use tracing::warn;
+
+use crate::body::Body;
+use crate::conditional::ETag;
+use crate::date;
+use crate::headers::Headers;
+use crate::methods::Method;
+use crate::status::StatusCode;
+
+/// HTTP response returned by handlers
+#[derive(Debug)]
+pub struct Response {
+    pub status: StatusCode,
+    pub headers: Headers,
+    pub body: Body,
 }
 
+#[derive(Eq, PartialEq, Clone, Copy, Debug)]
 pub enum ContentType {
     HTML,
     CSS,
@@ -23,53 +39,319 @@ impl From<ContentType> for &'static str {
     }
 }
 
-impl From<Response> for Vec<u8> {
-    fn from(res: Response) -> Vec<u8> {
-        match res {
-            Response::Ok(body, content_type) => {
-                const STATUS_LINE: &str = "HTTP/1.0 200 OK";
-                format_response(STATUS_LINE, content_type.into(), body)
-            }
-            Response::NotFound(body) => {
-                const STATUS_LINE: &str = "HTTP/1.0 404 Not Found";
-                format_response(STATUS_LINE, ContentType::HTML.into(), body)
-            }
+// The constructors below keep the names of the original `Response` enum
+// variants so existing handlers keep compiling.
+#[allow(non_snake_case)]
+impl Response {
+    /// 200 response with a body of the given content type
+    pub fn Ok(body: Vec<u8>, content_type: ContentType) -> Response {
+        Response::with_content(StatusCode::OK, body, content_type)
+    }
+
+    /// 404 response with an HTML body
+    pub fn NotFound(body: Vec<u8>) -> Response {
+        Response::with_content(StatusCode::NOT_FOUND, body, ContentType::HTML)
+    }
+
+    /// 405 response listing the methods allowed for the route
+    pub fn MethodNotAllowed(allowed: Vec<Method>) -> Response {
+        let allow = allowed
+            .iter()
+            .map(Method::as_str)
+            .collect::<Vec<_>>()
+            .join(", ");
+        let mut response =
+            Response::with_content(StatusCode::METHOD_NOT_ALLOWED, vec![], ContentType::PLAIN);
+        response.headers.insert("Allow", allow);
+        response
+    }
+}
+
+impl Response {
+    /// Response with the given status, no headers and an empty body
+    pub fn new(status: StatusCode) -> Response {
+        Response {
+            status,
+            headers: Headers::new(),
+            body: Body::empty(),
+        }
+    }
+
+    /// Start building a response with a 200 status
+    pub fn build() -> ResponseBuilder {
+        ResponseBuilder {
+            response: Response::new(StatusCode::OK),
+        }
+    }
+
+    /// Redirect to `location` with a 301, 302, 303, 307 or 308 status
+    pub fn redirect(status: StatusCode, location: impl Into<String>) -> Result<Response> {
+        if !status.is_redirection() {
+            bail!("{status} is not a redirect status");
+        }
+        Response::build()
+            .status(status)
+            .header("Location", location)
+            .finalize()
+    }
+
+    pub(crate) fn with_content(
+        status: StatusCode,
+        body: Vec<u8>,
+        content_type: ContentType,
+    ) -> Response {
+        let mut response = Response::new(status);
+        response
+            .headers
+            .insert("Content-Type", <&str>::from(content_type));
+        response.body = body.into();
+        response
+    }
+
+    /// Entity tag from the ETag header, if set and valid
+    pub fn etag(&self) -> Option<ETag> {
+        self.headers
+            .get("ETag")
+            .and_then(|etag| ETag::parse(etag).ok())
+    }
+
+    /// Time from the Last-Modified header, if set and valid
+    pub fn last_modified(&self) -> Option<SystemTime> {
+        self.headers
+            .get("Last-Modified")
+            .and_then(date::parse_http_date)
+    }
+
+    /// Status line and headers, including the Content-Length of the body
+    /// or `Transfer-Encoding: chunked` when its length is unknown
+    pub(crate) fn head_bytes(&self) -> Vec<u8> {
+        format_head(self.status, &self.headers, self.body.len())
+    }
+
+    /// Serialize the response, streaming the body into `writer`
+    pub fn write_to(self, writer: &mut impl Write) -> Result<()> {
+        writer.write_all(&self.head_bytes())?;
+        if !self.status.forbids_body() {
+            self.body.write_to(writer)?;
+        }
+        Ok(())
+    }
+}
+
+/// Builder for responses with arbitrary status codes and headers
+pub struct ResponseBuilder {
+    response: Response,
+}
+
+impl ResponseBuilder {
+    pub fn status(mut self, status: StatusCode) -> Self {
+        self.response.status = status;
+        self
+    }
+
+    /// Append a header, keeping any existing values with the same name
+    pub fn header(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.response.headers.insert(name, value);
+        self
+    }
+
+    pub fn content_type(mut self, content_type: ContentType) -> Self {
+        self.response
+            .headers
+            .set("Content-Type", <&str>::from(content_type));
+        self
+    }
+
+    /// Set the ETag validator, used to answer conditional requests
+    pub fn etag(mut self, etag: &ETag) -> Self {
+        self.response.headers.set("ETag", etag.to_string());
+        self
+    }
+
+    /// Set the Last-Modified validator, used to answer conditional requests
+    pub fn last_modified(mut self, modified: SystemTime) -> Self {
+        self.response
+            .headers
+            .set("Last-Modified", date::format_http_date(modified));
+        self
+    }
+
+    pub fn body(mut self, body: impl Into<Body>) -> Self {
+        self.response.body = body.into();
+        self
+    }
+
+    /// Finalize the builder, erring if a header could be used to inject
+    /// additional header lines or the status forbids the body that was set
+    pub fn finalize(self) -> Result<Response> {
+        for (name, value) in self.response.headers.iter() {
+            check_header(name, value)?;
+        }
+        if self.response.status.forbids_body() && !self.response.body.is_empty() {
+            bail!("{} responses cannot have a body", self.response.status);
         }
+        Ok(self.response)
     }
 }
 
-fn format_response(status_line: &str, html_type: &str, body: Vec<u8>) -> Vec<u8> {
-    let mut response = format!(
-        "{status_line}\r\nContent-Type: {html_type}\r\nContent-Length: {len}\r\n\r\n",
-        status_line = status_line,
-        html_type = html_type,
-        len = body.len(),
-    )
-    .into_bytes();
-
-    response.extend(body);
-    response
+/// Err if the header could be used to inject additional header lines
+fn check_header(name: &str, value: &str) -> Result<()> {
+    if name.is_empty() || name.contains(|c: char| c.is_whitespace() || c == ':') {
+        bail!("Invalid header name: {name:?}");
+    }
+    if value.contains(['\r', '\n']) {
+        bail!("Invalid value for header {name}");
+    }
+    Ok(())
+}
+
+fn format_head(status: StatusCode, headers: &Headers, content_length: Option<u64>) -> Vec<u8> {
+    let mut head = format!("HTTP/1.1 {status}\r\n");
+    for (name, value) in headers.iter() {
+        // framing headers are always derived from the body
+        if name.eq_ignore_ascii_case("Content-Length")
+            || name.eq_ignore_ascii_case("Transfer-Encoding")
+        {
+            continue;
+        }
+        // headers added after `finalize`, e.g. by middleware, were not
+        // checked yet
+        if let Err(err) = check_header(name, value) {
+            warn!("Dropping response header: {err}");
+            continue;
+        }
+        head.push_str(&format!("{name}: {value}\r\n"));
+    }
+    if !status.forbids_body() {
+        match content_length {
+            Some(length) => head.push_str(&format!("Content-Length: {length}\r\n")),
+            None => head.push_str("Transfer-Encoding: chunked\r\n"),
+        }
+    }
+    head.push_str("\r\n");
+    head.into_bytes()
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
 
+    fn to_bytes(response: Response) -> Vec<u8> {
+        let mut bytes = vec![];
+        response.write_to(&mut bytes).unwrap();
+        bytes
+    }
+
     #[test]
-    fn test_format_response() {
-        let status_line = "status";
-        let html_type = "content";
-        let body = vec![1, 2, 3];
-        let mut expected = format!(
-            "{status_line}\r\nContent-Type: {html_type}\r\nContent-Length: {len}\r\n\r\n",
-            status_line = status_line,
-            html_type = html_type,
-            len = body.len(),
-        )
-        .into_bytes();
-        expected.extend(body.clone());
+    fn test_format_head() {
+        let headers: Headers = [("Content-Type", "content"), ("Location", "/foo")]
+            .into_iter()
+            .collect();
+        let expected = "HTTP/1.1 201 Created\r\nContent-Type: content\r\nLocation: /foo\r\nContent-Length: 3\r\n\r\n";
+        assert_eq!(
+            expected.as_bytes(),
+            format_head(StatusCode::CREATED, &headers, Some(3))
+        );
+    }
+
+    #[test]
+    fn test_format_head_drops_injected_headers() {
+        let mut response = Response::Ok(b"ok".to_vec(), ContentType::PLAIN);
+        response
+            .headers
+            .insert("Location", "/foo\r\nSet-Cookie: evil=1");
+        response.headers.insert("Bad Name", "value");
+        response.headers.insert("X-Good", "1");
+        let head = String::from_utf8(response.head_bytes()).unwrap();
+        assert!(!head.contains("evil"));
+        assert!(!head.contains("Bad Name"));
+        assert!(head.contains("X-Good: 1\r\n"));
+    }
+
+    #[test]
+    fn test_format_head_without_body() {
+        let headers: Headers = [("Content-Length", "10")].into_iter().collect();
+        let expected = "HTTP/1.1 204 No Content\r\n\r\n";
+        assert_eq!(
+            expected.as_bytes(),
+            format_head(StatusCode::NO_CONTENT, &headers, Some(0))
+        );
+    }
+
+    #[test]
+    fn test_chunked_response() {
+        let response = Response::build()
+            .content_type(ContentType::PLAIN)
+            .header("Transfer-Encoding", "gzip")
+            .body(Body::from_chunks(vec![Ok("Wiki"), Ok("pedia")]))
+            .finalize()
+            .unwrap();
+
+        let expected = "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nTransfer-Encoding: chunked\r\n\r\n4\r\nWiki\r\n5\r\npedia\r\n0\r\n\r\n";
+        assert_eq!(to_bytes(response), expected.as_bytes());
+    }
+
+    #[test]
+    fn test_reader_response() {
+        let response = Response::build()
+            .body(Body::from_reader(&b"streamed"[..], 8))
+            .finalize()
+            .unwrap();
+
+        let expected = "HTTP/1.1 200 OK\r\nContent-Length: 8\r\n\r\nstreamed";
+        assert_eq!(to_bytes(response), expected.as_bytes());
+    }
+
+    #[test]
+    fn test_bytes_from_method_not_allowed_response() {
+        let response = Response::MethodNotAllowed(vec![Method::GET, Method::POST]);
+        let expected = "HTTP/1.1 405 Method Not Allowed\r\nContent-Type: text/plain\r\nAllow: GET, POST\r\nContent-Length: 0\r\n\r\n";
+        assert_eq!(to_bytes(response), expected.as_bytes());
+    }
+
+    #[test]
+    fn test_builder() {
+        let response = Response::build()
+            .status(StatusCode::CREATED)
+            .header("Location", "/items/1")
+            .header("Set-Cookie", "a=1")
+            .header("Set-Cookie", "b=2")
+            .content_type(ContentType::PLAIN)
+            .body("created")
+            .finalize()
+            .unwrap();
+
+        let expected = "HTTP/1.1 201 Created\r\nLocation: /items/1\r\nSet-Cookie: a=1\r\nSet-Cookie: b=2\r\nContent-Type: text/plain\r\nContent-Length: 7\r\n\r\ncreated";
+        assert_eq!(to_bytes(response), expected.as_bytes());
+    }
+
+    #[test]
+    fn test_builder_rejects_header_injection() {
+        let response = Response::build()
+            .header("Location", "/foo\r\nSet-Cookie: evil=1")
+            .finalize();
+        assert!(response.is_err());
+
+        let response = Response::build().header("Bad Name", "value").finalize();
+        assert!(response.is_err());
+    }
+
+    #[test]
+    fn test_builder_rejects_body_on_no_content() {
+        let response = Response::build()
+            .status(StatusCode::NO_CONTENT)
+            .body("body")
+            .finalize();
+        assert!(response.is_err());
+    }
+
+    #[test]
+    fn test_redirect() {
+        let response = Response::redirect(StatusCode::PERMANENT_REDIRECT, "/new").unwrap();
+        assert_eq!(response.status, StatusCode::PERMANENT_REDIRECT);
+        assert_eq!(response.headers.get("Location"), Some("/new"));
 
-        assert_eq!(expected, format_response(status_line, html_type, body));
+        assert!(Response::redirect(StatusCode::OK, "/new").is_err());
     }
 
     #[test]
@@ -77,23 +359,23 @@ mod test {
         let body = vec![1, 2, 3];
         let response = Response::Ok(body.clone(), ContentType::HTML);
         let expected = format!(
-            "HTTP/1.0 200 OK\r\nContent-Type: text/html\r\nContent-Length: {len}\r\n\r\n",
+            "HTTP/1.1 200 OK\r\nContent-Type: text/html\r\nContent-Length: {len}\r\n\r\n",
             len = body.len()
         )
         .into_bytes();
         let mut expected = expected;
         expected.extend(&body);
-        assert_eq!(Vec::<u8>::from(response), expected);
+        assert_eq!(to_bytes(response), expected);
 
         let response = Response::NotFound(body.clone());
         let expected = format!(
-            "HTTP/1.0 404 Not Found\r\nContent-Type: text/html\r\nContent-Length: {len}\r\n\r\n",
+            "HTTP/1.1 404 Not Found\r\nContent-Type: text/html\r\nContent-Length: {len}\r\n\r\n",
             len = body.len()
         )
         .into_bytes();
         let mut expected = expected;
         expected.extend(body);
-        assert_eq!(Vec::<u8>::from(response), expected);
+        assert_eq!(to_bytes(response), expected);
     }
 
     #[test]
@@ -102,14 +384,14 @@ mod test {
         let response = Response::Ok(body.clone(), ContentType::CSS);
 
         let expected = format!(
-            "HTTP/1.0 200 OK\r\nContent-Type: text/css\r\nContent-Length: {len}\r\n\r\n",
+            "HTTP/1.1 200 OK\r\nContent-Type: text/css\r\nContent-Length: {len}\r\n\r\n",
             len = body.len()
         )
         .into_bytes();
         let mut expected = expected;
         expected.extend(&body);
 
-        assert_eq!(Vec::<u8>::from(response), expected);
+        assert_eq!(to_bytes(response), expected);
     }
 
     #[test]
@@ -118,14 +400,14 @@ mod test {
         let response = Response::Ok(body.clone(), ContentType::JS);
 
         let expected = format!(
-            "HTTP/1.0 200 OK\r\nContent-Type: application/javascript\r\nContent-Length: {len}\r\n\r\n",
+            "HTTP/1.1 200 OK\r\nContent-Type: application/javascript\r\nContent-Length: {len}\r\n\r\n",
             len = body.len()
         )
         .into_bytes();
         let mut expected = expected;
         expected.extend(&body);
 
-        assert_eq!(Vec::<u8>::from(response), expected);
+        assert_eq!(to_bytes(response), expected);
     }
 
     #[test]
@@ -134,14 +416,14 @@ mod test {
         let response = Response::Ok(body.clone(), ContentType::IMAGE);
 
         let expected = format!(
-            "HTTP/1.0 200 OK\r\nContent-Type: image/jpeg\r\nContent-Length: {len}\r\n\r\n",
+            "HTTP/1.1 200 OK\r\nContent-Type: image/jpeg\r\nContent-Length: {len}\r\n\r\n",
             len = body.len()
         )
         .into_bytes();
         let mut expected = expected;
         expected.extend(&body);
 
-        assert_eq!(Vec::<u8>::from(response), expected);
+        assert_eq!(to_bytes(response), expected);
     }
 
     #[test]
@@ -150,14 +432,14 @@ mod test {
         let response = Response::Ok(body.clone(), ContentType::PLAIN);
 
         let expected = format!(
-            "HTTP/1.0 200 OK\r\nContent-Type: text/plain\r\nContent-Length: {len}\r\n\r\n",
+            "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: {len}\r\n\r\n",
             len = body.len()
         )
         .into_bytes();
         let mut expected = expected;
         expected.extend(&body);
 
-        assert_eq!(Vec::<u8>::from(response), expected);
+        assert_eq!(to_bytes(response), expected);
     }
 
     #[test]
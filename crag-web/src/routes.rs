@@ -1,4 +1,12 @@
-#[derive(Eq, Hash, PartialEq, Debug)]
+use anyhow::{bail, Result};
+use std::collections::HashMap;
+
+use crate::query;
+
+/// Values captured from `:name` and `*name` segments of a route pattern
+pub type Params = HashMap<String, String>;
+
+#[derive(Eq, Hash, PartialEq, Clone, Debug)]
 pub struct Route {
     pub route: String,
 }
@@ -9,6 +17,15 @@ impl Route {
             route: route.as_ref().to_owned(),
         }
     }
+
+    /// Non-empty path segments, so `/users/42/` and `/users/42` are the same route
+    pub fn segments(&self) -> impl Iterator<Item = &str> {
+        segments(&self.route)
+    }
+}
+
+fn segments(path: &str) -> impl Iterator<Item = &str> {
+    path.split('/').filter(|segment| !segment.is_empty())
 }
 
 impl From<&str> for Route {
@@ -17,6 +34,166 @@ impl From<&str> for Route {
     }
 }
 
+/// Segment of a route pattern
+#[derive(Eq, PartialEq, Debug)]
+enum Segment<'a> {
+    Static(&'a str),
+    // `:name` matches exactly one segment
+    Param(&'a str),
+    // `*name` matches the rest of the path
+    Wildcard(&'a str),
+}
+
+impl<'a> Segment<'a> {
+    fn parse(segment: &'a str) -> Result<Self> {
+        let parsed = if let Some(name) = segment.strip_prefix(':') {
+            Segment::Param(name)
+        } else if let Some(name) = segment.strip_prefix('*') {
+            Segment::Wildcard(name)
+        } else {
+            return Ok(Segment::Static(segment));
+        };
+
+        match parsed {
+            Segment::Param("") | Segment::Wildcard("") => {
+                bail!("Route parameter in {segment:?} needs a name")
+            }
+            parsed => Ok(parsed),
+        }
+    }
+}
+
+/// Prefix tree of route patterns keyed by path segment.
+///
+/// Lookups prefer static segments over `:param` segments, and `:param`
+/// segments over a trailing `*wildcard`, backtracking when a more specific
+/// branch does not lead to a match.
+#[derive(Debug)]
+pub struct Router<T> {
+    root: Node<T>,
+}
+
+#[derive(Debug)]
+struct Node<T> {
+    value: Option<T>,
+    statics: HashMap<String, Node<T>>,
+    param: Option<(String, Box<Node<T>>)>,
+    wildcard: Option<(String, T)>,
+}
+
+impl<T> Default for Node<T> {
+    fn default() -> Self {
+        Node {
+            value: None,
+            statics: HashMap::new(),
+            param: None,
+            wildcard: None,
+        }
+    }
+}
+
+impl<T> Default for Router<T> {
+    fn default() -> Self {
+        Router {
+            root: Node::default(),
+        }
+    }
+}
+
+impl<T> Router<T> {
+    pub fn new() -> Self {
+        Router::default()
+    }
+
+    /// Value stored for the pattern, inserting `T::default()` if missing.
+    /// Errors if the pattern is malformed or a parameter at the same position
+    /// was registered under a different name.
+    pub fn entry(&mut self, route: &Route) -> Result<&mut T>
+    where
+        T: Default,
+    {
+        let mut node = &mut self.root;
+        let mut segments = route.segments().peekable();
+
+        while let Some(segment) = segments.next() {
+            node = match Segment::parse(segment)? {
+                Segment::Static(segment) => node.statics.entry(segment.to_owned()).or_default(),
+                Segment::Param(name) => {
+                    let (existing, child) = node
+                        .param
+                        .get_or_insert_with(|| (name.to_owned(), Box::default()));
+                    if existing != name {
+                        bail!("Route {route:?} conflicts with parameter :{existing}");
+                    }
+                    child
+                }
+                Segment::Wildcard(name) => {
+                    if segments.peek().is_some() {
+                        bail!("Wildcard must be the last segment in {route:?}");
+                    }
+                    let (existing, value) = node
+                        .wildcard
+                        .get_or_insert_with(|| (name.to_owned(), T::default()));
+                    if existing != name {
+                        bail!("Route {route:?} conflicts with wildcard *{existing}");
+                    }
+                    return Ok(value);
+                }
+            };
+        }
+
+        Ok(node.value.get_or_insert_with(T::default))
+    }
+
+    /// Find the value matching a percent-encoded request path along with
+    /// captured parameters. The path is split into segments before they
+    /// are decoded, so an encoded `/` stays part of its segment.
+    pub fn lookup(&self, path: &str) -> Option<(&T, Params)> {
+        let decoded = segments(path)
+            .map(|segment| query::percent_decode(segment, false))
+            .collect::<crate::error::Result<Vec<String>>>()
+            .ok()?;
+        let segments: Vec<&str> = decoded.iter().map(String::as_str).collect();
+        let mut params = Params::new();
+        let value = self.root.lookup(&segments, &mut params)?;
+        Some((value, params))
+    }
+}
+
+impl<T> Node<T> {
+    fn lookup<'a>(&'a self, segments: &[&str], params: &mut Params) -> Option<&'a T> {
+        let Some((first, rest)) = segments.split_first() else {
+            return self
+                .value
+                .as_ref()
+                .or_else(|| self.wildcard_match(segments, params));
+        };
+
+        if let Some(value) = self
+            .statics
+            .get(*first)
+            .and_then(|child| child.lookup(rest, params))
+        {
+            return Some(value);
+        }
+
+        if let Some((name, child)) = &self.param {
+            if let Some(value) = child.lookup(rest, params) {
+                params.insert(name.clone(), (*first).to_owned());
+                return Some(value);
+            }
+        }
+
+        self.wildcard_match(segments, params)
+    }
+
+    fn wildcard_match<'a>(&'a self, segments: &[&str], params: &mut Params) -> Option<&'a T> {
+        let (name, value) = self.wildcard.as_ref()?;
+        params.insert(name.clone(), segments.join("/"));
+        Some(value)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -32,4 +209,97 @@ mod tests {
         let route: Route = "/foo".into();
         assert_eq!(route, Route::new("/foo"));
     }
+
+    #[test]
+    fn test_route_segments() {
+        let route = Route::new("/users/42/");
+        assert_eq!(route.segments().collect::<Vec<_>>(), vec!["users", "42"]);
+        assert_eq!(Route::new("/").segments().count(), 0);
+    }
+
+    fn router(patterns: &[&str]) -> Router<String> {
+        let mut router = Router::new();
+        for pattern in patterns {
+            *router.entry(&Route::new(pattern)).unwrap() = pattern.to_string();
+        }
+        router
+    }
+
+    #[test]
+    fn test_router_static() {
+        let router = router(&["/", "/foo", "/foo/bar"]);
+        assert_eq!(router.lookup("/").unwrap().0, "/");
+        assert_eq!(router.lookup("/foo").unwrap().0, "/foo");
+        assert_eq!(router.lookup("/foo/bar").unwrap().0, "/foo/bar");
+        assert!(router.lookup("/bar").is_none());
+        assert!(router.lookup("/foo/bar/baz").is_none());
+    }
+
+    #[test]
+    fn test_router_params() {
+        let router = router(&["/users/:id", "/users/:id/posts/:post"]);
+
+        let (value, params) = router.lookup("/users/42").unwrap();
+        assert_eq!(value, "/users/:id");
+        assert_eq!(params["id"], "42");
+
+        let (value, params) = router.lookup("/users/42/posts/7").unwrap();
+        assert_eq!(value, "/users/:id/posts/:post");
+        assert_eq!(params["id"], "42");
+        assert_eq!(params["post"], "7");
+
+        assert!(router.lookup("/users").is_none());
+    }
+
+    #[test]
+    fn test_router_wildcard() {
+        let router = router(&["/static/*path"]);
+
+        let (value, params) = router.lookup("/static/css/main.css").unwrap();
+        assert_eq!(value, "/static/*path");
+        assert_eq!(params["path"], "css/main.css");
+
+        let (_, params) = router.lookup("/static").unwrap();
+        assert_eq!(params["path"], "");
+    }
+
+    #[test]
+    fn test_router_precedence() {
+        let router = router(&["/users/me", "/users/:id", "/users/*rest"]);
+        assert_eq!(router.lookup("/users/me").unwrap().0, "/users/me");
+        assert_eq!(router.lookup("/users/42").unwrap().0, "/users/:id");
+        assert_eq!(router.lookup("/users/42/x").unwrap().0, "/users/*rest");
+    }
+
+    #[test]
+    fn test_router_backtracks_from_static() {
+        let router = router(&["/users/me/settings", "/users/:id"]);
+        let (value, params) = router.lookup("/users/me").unwrap();
+        assert_eq!(value, "/users/:id");
+        assert_eq!(params["id"], "me");
+    }
+
+    #[test]
+    fn test_router_decodes_segments() {
+        let router = router(&["/users/:id", "/files/*path", "/café"]);
+        let (value, params) = router.lookup("/users/a%2Fb").unwrap();
+        assert_eq!(value, "/users/:id");
+        assert_eq!(params.get("id").unwrap(), "a/b");
+
+        let (_, params) = router.lookup("/files/a%20b/c").unwrap();
+        assert_eq!(params.get("path").unwrap(), "a b/c");
+
+        assert_eq!(router.lookup("/caf%C3%A9").unwrap().0, "/café");
+        assert!(router.lookup("/users/%zz").is_none());
+    }
+
+    #[test]
+    fn test_router_invalid_patterns() {
+        let mut router: Router<String> = Router::new();
+        assert!(router.entry(&Route::new("/users/:")).is_err());
+        assert!(router.entry(&Route::new("/static/*path/more")).is_err());
+
+        router.entry(&Route::new("/users/:id")).unwrap();
+        assert!(router.entry(&Route::new("/users/:name")).is_err());
+    }
 }
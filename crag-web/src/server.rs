@@ -1,50 +1,461 @@
-use anyhow::{anyhow, Result};
+use anyhow::Result;
+use std::any::Any;
 use std::collections::HashMap;
-use std::io::{BufRead, BufReader, Read, Write};
-use std::net::TcpListener;
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
 use std::net::ToSocketAddrs;
+use std::net::{Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::Arc;
-use tracing::error;
+use std::time::{Duration, Instant};
+use tracing::{debug, error, warn};
 
+#[cfg(feature = "compression")]
+use crate::compression;
+use crate::error::{self, Error};
 use crate::request;
 use crate::response;
 use crate::routes;
 use crate::threadpool;
-use crate::{handler, methods};
+use crate::{
+    conditional, handler, headers, methods, middleware, range, state, static_files, status,
+};
 
-type HandlerMap = HashMap<routes::Route, handler::BoxedHandler>;
+#[cfg(feature = "async")]
+mod async_server;
+mod parser;
+#[cfg(feature = "async")]
+pub use async_server::AsyncServer;
+use parser::{Parsed, RequestParser};
+
+type HandlerMap = routes::Router<RouteHandlers>;
+
+/// Pause after a failed accept, so a lasting error such as running out of
+/// file descriptors doesn't spin the accept loop
+const ACCEPT_ERROR_DELAY: Duration = Duration::from_millis(50);
+
+/// Handler registered for a route and method
+enum Endpoint {
+    Blocking(handler::BoxedHandler),
+    #[cfg(feature = "async")]
+    Async(handler::BoxedAsyncHandler),
+}
+
+impl Endpoint {
+    /// Run the handler to completion on the calling thread. Async handlers
+    /// are driven by the tokio runtime the thread belongs to, which
+    /// `AsyncServer` provides by calling blocking code on its blocking
+    /// threads.
+    fn handle(&self, req: request::Request) -> Result<response::Response> {
+        match self {
+            Endpoint::Blocking(handler) => handler.handle(req),
+            #[cfg(feature = "async")]
+            Endpoint::Async(handler) => tokio::runtime::Handle::try_current()
+                .map_err(|_| anyhow::anyhow!("Async handler called outside a tokio runtime"))?
+                .block_on(handler.handle(req)),
+        }
+    }
+}
+
+/// Handlers registered for a single route
+#[derive(Default)]
+struct RouteHandlers {
+    by_method: HashMap<methods::Method, Endpoint>,
+    // handler registered without a method, receives every method
+    any: Option<Endpoint>,
+    // middleware that only wraps this route's handlers
+    middleware: Vec<middleware::BoxedMiddleware>,
+}
+
+impl RouteHandlers {
+    /// Handler for the method, HEAD falling back to the GET handler
+    fn get(&self, method: &methods::Method) -> Option<&Endpoint> {
+        self.by_method
+            .get(method)
+            .or_else(|| match method {
+                methods::Method::HEAD => self.by_method.get(&methods::Method::GET),
+                _ => None,
+            })
+            .or(self.any.as_ref())
+    }
+
+    /// Methods with a handler, sorted for a stable Allow header
+    fn allowed_methods(&self) -> Vec<methods::Method> {
+        let mut allowed: Vec<methods::Method> = self.by_method.keys().cloned().collect();
+        if self.by_method.contains_key(&methods::Method::GET)
+            && !self.by_method.contains_key(&methods::Method::HEAD)
+        {
+            allowed.push(methods::Method::HEAD);
+        }
+        allowed.sort_by(|a, b| a.as_str().cmp(b.as_str()));
+        allowed
+    }
+}
 
 struct Handlers {
     valid_handlers: HandlerMap,
     error_handler: handler::BoxedHandler,
+    server_error_handler: handler::BoxedErrorHandler,
+    // global middleware, wraps every request including 404 and 405 responses
+    middleware: Vec<middleware::BoxedMiddleware>,
+    state: state::State,
+    #[cfg(feature = "compression")]
+    compression: Option<compression::Compression>,
 }
 
 impl Handlers {
+    /// Handle a request, turning a handler error or panic into the response
+    /// of the server error handler. Successful responses with validators are
+    /// replaced by 304 or 412 when the request's preconditions say so.
+    fn respond(&self, req: request::Request) -> response::Response {
+        let head = req.head();
+        // handlers only share state through `Sync` values, so a panic
+        // can't leave anything the next request sees half-updated
+        let result = panic::catch_unwind(AssertUnwindSafe(|| self.handle(req)))
+            .unwrap_or_else(|panic| Err(handler_panicked(&head, &*panic)));
+        self.finish(&head, result)
+    }
+
+    /// Post-process a handler's response, or answer its error
+    fn finish(
+        &self,
+        head: &request::RequestHead,
+        result: Result<response::Response>,
+    ) -> response::Response {
+        match result {
+            Ok(response) => {
+                let response = conditional::apply(head, response);
+                // ranges apply to the compressed representation
+                #[cfg(feature = "compression")]
+                let response = match &self.compression {
+                    Some(compression) => compression.apply(head, response),
+                    None => response,
+                };
+                range::apply(head, response)
+            }
+            Err(err) => self.server_error_handler.handle(&err, head),
+        }
+    }
+
+    /// Run a request through the global middleware and dispatch it
+    fn handle(&self, mut req: request::Request) -> Result<response::Response> {
+        req.state = self.state.clone();
+        middleware::Next::new(&self.middleware, &|req| self.dispatch(req)).run(req)
+    }
+
+    /// Dispatch a request to the handler registered for its method and route.
+    /// Unknown routes go to the error handler and known routes without a
+    /// handler for the method are answered with 405.
+    fn dispatch(&self, mut req: request::Request) -> Result<response::Response> {
+        let Some((route_handlers, params)) = self.valid_handlers.lookup(&req.route.route) else {
+            return self.handle_error(req);
+        };
+        req.params = params;
+
+        match route_handlers.get(&req.method) {
+            Some(handler) => middleware::Next::new(&route_handlers.middleware, &|req| {
+                self.not_found_to_error_handler(handler.handle(req))
+            })
+            .run(req),
+            None => Ok(response::Response::MethodNotAllowed(
+                route_handlers.allowed_methods(),
+            )),
+        }
+    }
+
     fn handle_error(&self, req: request::Request) -> Result<response::Response> {
         self.error_handler.handle(req)
     }
+
+    /// Hand the request of a handler that returned `handler::NotFound` to
+    /// the error handler
+    fn not_found_to_error_handler(
+        &self,
+        result: Result<response::Response>,
+    ) -> Result<response::Response> {
+        match result.map_err(|err| err.downcast::<handler::NotFound>()) {
+            Ok(response) => Ok(response),
+            Err(Ok(handler::NotFound(req))) => self.handle_error(req),
+            Err(Err(err)) => Err(err),
+        }
+    }
+}
+
+/// Log a handler panic and turn it into the error answered for it
+fn handler_panicked(head: &request::RequestHead, panic: &(dyn Any + Send)) -> anyhow::Error {
+    let message = threadpool::panic_message(panic);
+    error!(
+        "Handler for {} {} panicked: {message}",
+        head.method, head.route.route
+    );
+    anyhow::anyhow!("Handler panicked: {message}")
+}
+
+/// Settings for persistent (keep-alive) connections and socket timeouts
+#[derive(Clone, Copy, Debug)]
+struct ConnectionConfig {
+    keep_alive: bool,
+    // how long an idle connection waits for its next request
+    idle_timeout: Duration,
+    max_requests: usize,
+    // how long a single read or write may block once a request started
+    read_timeout: Duration,
+    write_timeout: Duration,
+    limits: RequestLimits,
+}
+
+impl ConnectionConfig {
+    /// Whether to keep the connection open after the response to its
+    /// `served`th request, marking the response with `Connection: close`
+    /// if not. Called after the handler so a shutdown requested while it
+    /// ran still closes the connection.
+    fn keep_alive_after(
+        &self,
+        response: &mut response::Response,
+        served: usize,
+        client_close: bool,
+        shutdown: bool,
+    ) -> bool {
+        let keep_alive =
+            self.keep_alive && served < self.max_requests && !client_close && !shutdown;
+        if !keep_alive {
+            response.headers.set("Connection", "close");
+        }
+        keep_alive
+    }
+}
+
+impl Default for ConnectionConfig {
+    fn default() -> Self {
+        ConnectionConfig {
+            keep_alive: true,
+            idle_timeout: Duration::from_secs(5),
+            max_requests: 100,
+            read_timeout: Duration::from_secs(30),
+            write_timeout: Duration::from_secs(30),
+            limits: RequestLimits::default(),
+        }
+    }
+}
+
+/// Bounds on what a client may send in a single request
+#[derive(Clone, Copy, Debug)]
+struct RequestLimits {
+    // longest request line, including the line ending
+    max_request_line: usize,
+    max_headers: usize,
+    // total size of the header lines, including their line endings but
+    // not the blank line ending the head
+    max_header_size: usize,
+    // largest request body accepted, after chunked decoding
+    max_body_size: usize,
+    // how long the client has to send the request line and headers
+    header_timeout: Duration,
+}
+
+impl Default for RequestLimits {
+    fn default() -> Self {
+        RequestLimits {
+            max_request_line: 8 * 1024,
+            max_headers: 100,
+            max_header_size: 16 * 1024,
+            max_body_size: 10 * 1024 * 1024,
+            header_timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+/// What the accept loop does with a new connection when the job queue is
+/// full
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum OverloadPolicy {
+    /// Stop accepting until a worker takes a queued connection, leaving new
+    /// connections in the listen backlog
+    Block,
+    /// Answer immediately with 503 Service Unavailable and a Retry-After
+    /// header, then close the connection
+    Reject { retry_after: Duration },
+}
+
+/// Bounds on connections waiting for a worker
+#[derive(Clone, Copy, Debug)]
+struct QueueConfig {
+    capacity: usize,
+    policy: OverloadPolicy,
+}
+
+impl Default for QueueConfig {
+    fn default() -> Self {
+        QueueConfig {
+            capacity: 1024,
+            policy: OverloadPolicy::Block,
+        }
+    }
+}
+
+/// Growth and threads of the worker pool
+#[derive(Clone, Copy, Debug)]
+struct WorkerConfig {
+    // None to keep the pool at the size given to `finalize`
+    max_workers: Option<usize>,
+    idle_timeout: Duration,
+    stack_size: Option<usize>,
+    // threads of the pool handlers submit their own jobs to
+    job_workers: usize,
+}
+
+impl Default for WorkerConfig {
+    fn default() -> Self {
+        WorkerConfig {
+            max_workers: None,
+            idle_timeout: Duration::from_secs(60),
+            stack_size: None,
+            job_workers: std::thread::available_parallelism().map_or(4, usize::from),
+        }
+    }
+}
+
+/// Handle reporting how many connections wait for a worker, for monitoring
+/// a running server from another thread
+#[derive(Clone, Debug)]
+pub struct QueueMonitor {
+    queue: Arc<threadpool::Queue>,
+    rejected: Arc<AtomicU64>,
+    jobs: threadpool::PoolHandle,
+}
+
+impl QueueMonitor {
+    /// Connections accepted but not yet taken by a worker
+    pub fn depth(&self) -> usize {
+        self.queue.len()
+    }
+
+    /// Most connections that can wait for a worker
+    pub fn capacity(&self) -> usize {
+        // the server always bounds its queue
+        self.queue.capacity().unwrap_or(usize::MAX)
+    }
+
+    /// Worker threads currently running
+    pub fn workers(&self) -> usize {
+        self.queue.workers()
+    }
+
+    /// Connections answered with 503 because the queue was full
+    pub fn rejected(&self) -> u64 {
+        self.rejected.load(Ordering::Relaxed)
+    }
+
+    /// Busy and idle connection workers and the connections they served
+    pub fn stats(&self) -> threadpool::PoolStats {
+        self.queue.stats()
+    }
+
+    /// Workers of the job pool and the jobs handlers submitted to it
+    pub fn job_stats(&self) -> threadpool::PoolStats {
+        self.jobs.stats()
+    }
+}
+
+/// Handle used to stop a running server from another thread
+#[derive(Clone, Debug)]
+pub struct ShutdownHandle {
+    inner: Arc<ShutdownState>,
+}
+
+#[derive(Debug)]
+struct ShutdownState {
+    requested: AtomicBool,
+    // address the accept loop listens on, connected to once to wake it up
+    addr: SocketAddr,
+}
+
+impl ShutdownHandle {
+    fn new(mut addr: SocketAddr) -> Self {
+        if addr.ip().is_unspecified() {
+            addr.set_ip(match addr {
+                SocketAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+                SocketAddr::V6(_) => Ipv6Addr::LOCALHOST.into(),
+            });
+        }
+        ShutdownHandle {
+            inner: Arc::new(ShutdownState {
+                requested: AtomicBool::new(false),
+                addr,
+            }),
+        }
+    }
+
+    /// Ask the server to stop accepting connections. `Server::run` then waits
+    /// for in-flight requests to finish and returns.
+    pub fn shutdown(&self) {
+        if !self.inner.requested.swap(true, Ordering::SeqCst) {
+            // the accept loop is blocked in accept(), so give it a connection
+            _ = TcpStream::connect(self.inner.addr);
+        }
+    }
+
+    pub fn is_shutdown(&self) -> bool {
+        self.inner.requested.load(Ordering::SeqCst)
+    }
+
+    /// Shut the server down on SIGINT or SIGTERM. Only one signal handler
+    /// can be installed per process.
+    #[cfg(feature = "signals")]
+    pub fn shutdown_on_signal(&self) -> Result<()> {
+        let handle = self.clone();
+        ctrlc::set_handler(move || handle.shutdown())?;
+        Ok(())
+    }
 }
 
 pub struct Server {
     tcp_listener: TcpListener,
     pool: threadpool::ThreadPool,
+    // runs the jobs of handlers, apart from the connections so a handler
+    // waiting for its jobs can't starve them of workers
+    jobs: threadpool::ThreadPool,
     handlers: Arc<Handlers>,
+    connection: ConnectionConfig,
+    queue: QueueConfig,
+    // connections rejected under `OverloadPolicy::Reject`
+    rejected: Arc<AtomicU64>,
+    shutdown: ShutdownHandle,
+    shutdown_timeout: Duration,
 }
 
 pub struct ServerBuilder {
     handlers: HandlerMap,
     error_handler: Option<handler::BoxedHandler>,
+    server_error_handler: Option<handler::BoxedErrorHandler>,
+    middleware: Vec<middleware::BoxedMiddleware>,
+    state: state::State,
+    #[cfg(feature = "compression")]
+    compression: Option<compression::Compression>,
+    // whether any route has an async handler, which only `AsyncServer` runs
+    #[cfg(feature = "async")]
+    async_handlers: bool,
+    connection: ConnectionConfig,
+    queue: QueueConfig,
+    workers: WorkerConfig,
+    shutdown_timeout: Duration,
 }
 
 impl ServerBuilder {
     /// Finalize the server builder and create a server instance
     /// an error handler must always be defined or this will err.
-    pub fn finalize(self, addr: impl ToSocketAddrs, pool_size: usize) -> Result<Server> {
-        // Check to see that there is an error_handler for 404 errors
-        let error_handler = match self.error_handler {
-            Some(handler) => handler,
-            None => anyhow::bail!("Error: No error handler defined"),
-        };
+    pub fn finalize(mut self, addr: impl ToSocketAddrs, pool_size: usize) -> Result<Server> {
+        let connection = self.connection;
+        let queue = self.queue;
+        let workers = self.workers;
+        let shutdown_timeout = self.shutdown_timeout;
+        #[cfg(feature = "async")]
+        if self.async_handlers {
+            anyhow::bail!("Async handlers are only served by finalize_async");
+        }
+        if self.state.contains::<threadpool::PoolHandle>() {
+            anyhow::bail!("State of type PoolHandle is reserved for the server's job pool");
+        }
 
         let socket_addr = addr
             .to_socket_addrs()?
@@ -52,298 +463,2135 @@ impl ServerBuilder {
             .ok_or_else(|| anyhow::anyhow!("Unable to resolve address"))?;
 
         let tcp_listener = TcpListener::bind(socket_addr)?;
-        let pool = threadpool::ThreadPool::build(pool_size)?;
-        let handlers = Arc::new(Handlers {
-            valid_handlers: self.handlers,
-            error_handler,
-        });
+        let shutdown = ShutdownHandle::new(tcp_listener.local_addr()?);
+        let max_workers = workers.max_workers.unwrap_or(pool_size);
+        if max_workers < pool_size {
+            anyhow::bail!("Maximum of {max_workers} workers is below the pool size of {pool_size}");
+        }
+        let mut pool = threadpool::PoolBuilder::new(pool_size)
+            .max_workers(max_workers)
+            .capacity(queue.capacity)
+            .idle_timeout(workers.idle_timeout);
+        if let Some(stack_size) = workers.stack_size {
+            pool = pool.stack_size(stack_size);
+        }
+        let pool = pool.build()?;
+        let mut jobs = threadpool::PoolBuilder::new(workers.job_workers);
+        if let Some(stack_size) = workers.stack_size {
+            jobs = jobs.stack_size(stack_size);
+        }
+        let jobs = jobs.build()?;
+        // handlers reach the job pool through `Request::state`
+        self.state.insert(jobs.handle());
+        let handlers = Arc::new(self.build_handlers()?);
 
         let server = Server {
             tcp_listener,
             pool,
+            jobs,
             handlers,
+            connection,
+            queue,
+            rejected: Arc::new(AtomicU64::new(0)),
+            shutdown,
+            shutdown_timeout,
         };
 
         Ok(server)
     }
 
-    pub fn register_handler(
-        mut self,
-        r: routes::Route,
-        handler: impl handler::Handler + Send + Sync + 'static,
-    ) -> Result<Self> {
-        if self.handlers.contains_key(&r) {
-            anyhow::bail!("Handler already registered for {r:?}");
+    /// Finalize the builder into a server running the same routes on tokio,
+    /// which also serves async handlers. The queue and worker settings only
+    /// apply to the blocking server; tokio's runtime runs the connections.
+    #[cfg(feature = "async")]
+    pub fn finalize_async(self, addr: impl ToSocketAddrs) -> Result<AsyncServer> {
+        let connection = self.connection;
+        let shutdown_timeout = self.shutdown_timeout;
+        let handlers = self.build_handlers()?;
+
+        let socket_addr = addr
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| anyhow::anyhow!("Unable to resolve address"))?;
+
+        let tcp_listener = TcpListener::bind(socket_addr)?;
+        let shutdown = ShutdownHandle::new(tcp_listener.local_addr()?);
+        Ok(AsyncServer::new(
+            tcp_listener,
+            handlers,
+            connection,
+            shutdown,
+            shutdown_timeout,
+        ))
+    }
+
+    /// Enable or disable HTTP/1.1 persistent connections, enabled by default
+    pub fn keep_alive(mut self, keep_alive: bool) -> Self {
+        self.connection.keep_alive = keep_alive;
+        self
+    }
+
+    /// How long a persistent connection may sit idle waiting for its next
+    /// request before it is closed, 5 seconds by default
+    pub fn keep_alive_timeout(mut self, timeout: Duration) -> Result<Self> {
+        if timeout.is_zero() {
+            anyhow::bail!("Keep-alive timeout must be greater than zero");
         }
-        self.handlers.insert(r, Box::new(handler));
+        self.connection.idle_timeout = timeout;
         Ok(self)
     }
 
-    pub fn register_error_handler(
-        mut self,
-        handler: impl handler::Handler + Send + Sync + 'static,
-    ) -> Result<Self> {
-        if self.error_handler.is_some() {
-            anyhow::bail!("Error handler already registered");
+    /// Maximum number of requests served on one connection, 100 by default
+    pub fn max_requests_per_connection(mut self, max_requests: usize) -> Result<Self> {
+        if max_requests == 0 {
+            anyhow::bail!("Max requests per connection must be at least 1");
         }
-        self.error_handler = Some(Box::new(handler));
+        self.connection.max_requests = max_requests;
         Ok(self)
     }
-}
 
-impl Server {
-    pub fn build() -> ServerBuilder {
-        ServerBuilder {
-            handlers: HashMap::new(),
-            error_handler: None,
-        }
+    /// Largest request body accepted, 10 MiB by default. Applies to the
+    /// decoded size of chunked bodies. Larger bodies are answered with 413.
+    pub fn max_body_size(mut self, max_body_size: usize) -> Self {
+        self.connection.limits.max_body_size = max_body_size;
+        self
     }
-    pub fn run(&self) -> Result<()> {
-        for stream in self.tcp_listener.incoming() {
-            let mut stream = stream?;
-            // stream.set_read_timeout(Some(Duration::from_secs(3)))?;
-            let handlers = self.handlers.clone();
 
-            // error boundary
-            // does trying to return 404 or 501 on error make sense when the error coming from
-            self.pool.execute(move || {
-                if let Err(e) = handle_connection(&handlers, &mut stream) {
-                    error!("Error handling connection: {:?}", e);
-                    _ = stream.write_all("HTTP/1.1 501 Internal Server Error\r\n\r\n".as_bytes());
-                };
-            });
+    /// Longest request line accepted, including the line ending, 8 KiB by
+    /// default. Longer request lines are answered with 414.
+    pub fn max_request_line_length(mut self, length: usize) -> Result<Self> {
+        if length == 0 {
+            anyhow::bail!("Max request line length must be at least 1");
         }
-        Ok(())
+        self.connection.limits.max_request_line = length;
+        Ok(self)
     }
-}
-
-fn handle_connection<S>(handlers: &Handlers, stream: &mut S) -> Result<()>
-where
-    S: Read + Write,
-{
-    let req = read_and_parse_request(stream)
-        .map_err(|err| anyhow!("Error parsing request: {:?}", err))?;
-
-    // build response
-    let response = match handlers.valid_handlers.get(&req.route) {
-        Some(handler) => handler.handle(req),
-        None => handlers.handle_error(req),
-    };
-    let response = response?;
-
-    // write response into TcpStream
-    stream.write_all(&Vec::<u8>::from(response))?;
-
-    Ok(())
-}
-
-fn read_and_parse_request(stream: &mut impl Read) -> Result<request::Request> {
-    // create buffer
-    let mut buffer = BufReader::new(stream);
 
-    // get header lines
-    let lines = {
-        let mut lines: Vec<String> = vec![];
-        loop {
-            let mut next_line = String::new();
-            buffer.read_line(&mut next_line)?;
-            if next_line.is_empty() || next_line == "\r\n" || next_line == "\r" {
-                break lines;
-            }
-            lines.push(next_line);
+    /// Maximum number of header fields in a request, 100 by default. Requests
+    /// with more are answered with 431.
+    pub fn max_headers(mut self, max_headers: usize) -> Result<Self> {
+        if max_headers == 0 {
+            anyhow::bail!("Max headers must be at least 1");
         }
-    };
-
-    // Parse the request and content_length for body
-    let (mut req, content_length) = parse_request(lines)?;
+        self.connection.limits.max_headers = max_headers;
+        Ok(self)
+    }
 
-    // Parse the request body based on Content-Length
-    let mut body_buffer = vec![0; content_length];
-    buffer.read_exact(&mut body_buffer)?;
+    /// Largest total size of the header lines including their line
+    /// endings, 16 KiB by default. The blank line ending the head is not
+    /// counted. Larger header sections are answered with 431.
+    pub fn max_header_size(mut self, size: usize) -> Result<Self> {
+        if size == 0 {
+            anyhow::bail!("Max header size must be at least 1");
+        }
+        self.connection.limits.max_header_size = size;
+        Ok(self)
+    }
 
-    // TODO IMPLEMENT THIS LOGIC
-    // let mut buffer: Vec<u8> = vec![0; length];
-    // let read_len = buf_reader.read(&mut buffer).unwrap();
-    // if read_len != length {
-    //     eprintln!("User inputted invalid BUFLEN");
-    //     return;
-    // }
+    /// How long a client has to send the request line and headers once it
+    /// starts a request, 10 seconds by default. Slower clients are answered
+    /// with 408.
+    pub fn header_timeout(mut self, timeout: Duration) -> Result<Self> {
+        if timeout.is_zero() {
+            anyhow::bail!("Header timeout must be greater than zero");
+        }
+        self.connection.limits.header_timeout = timeout;
+        Ok(self)
+    }
 
-    // Add body to request if POST
-    if let methods::Method::POST = req.method {
-        if content_length > 0 {
-            req.add_body(String::from_utf8(body_buffer.clone()).unwrap_or_default())?;
+    /// How long a single read from the client may block while a request is
+    /// being received, 30 seconds by default
+    pub fn read_timeout(mut self, timeout: Duration) -> Result<Self> {
+        if timeout.is_zero() {
+            anyhow::bail!("Read timeout must be greater than zero");
         }
+        self.connection.read_timeout = timeout;
+        Ok(self)
     }
 
-    Ok(req)
-}
+    /// How long a single write of the response may block, 30 seconds by
+    /// default
+    pub fn write_timeout(mut self, timeout: Duration) -> Result<Self> {
+        if timeout.is_zero() {
+            anyhow::bail!("Write timeout must be greater than zero");
+        }
+        self.connection.write_timeout = timeout;
+        Ok(self)
+    }
 
-fn parse_request<IT, S>(lines: IT) -> Result<(request::Request, usize)>
-where
-    IT: IntoIterator<Item = S>,
-    S: AsRef<str>,
-{
-    let mut lines = lines.into_iter();
-    // build request from header
-    let first_line = lines
-        .next()
-        .ok_or_else(|| anyhow!("No request line found"))?;
-    let req = request::Request::parse(first_line.as_ref())?;
-
-    // parse content length if POST else 0
-    let content_length = match req.method {
-        methods::Method::GET => 0,
-        methods::Method::POST => {
-            lines
-                .find(|line| line.as_ref().starts_with("Content-Length:"))
-                .and_then(|line| {
-                    line.as_ref()
-                        .trim()
-                        .split(':')
-                        .nth(1)
-                        .and_then(|value| value.trim().parse::<usize>().ok())
-                })
-                .unwrap_or(0)
-            // panic!("Need to read body according to content length but we are not doing that yet")
-        }
-    };
-
-    Ok((req, content_length))
-}
+    /// Most accepted connections waiting for a free worker, 1024 by default.
+    /// What happens to connections beyond that is set by `overload_policy`.
+    pub fn max_queued_connections(mut self, capacity: usize) -> Result<Self> {
+        if capacity == 0 {
+            anyhow::bail!("Queue capacity must be greater than zero");
+        }
+        self.queue.capacity = capacity;
+        Ok(self)
+    }
 
-#[cfg(test)]
-mod test {
-    use super::*;
-    use crate::handler;
-    use crate::request;
-    use crate::response;
-    use anyhow::Result;
+    /// How to handle connections while the queue is full,
+    /// `OverloadPolicy::Block` by default
+    pub fn overload_policy(mut self, policy: OverloadPolicy) -> Self {
+        self.queue.policy = policy;
+        self
+    }
 
-    // get "/hello"
-    fn hello_handler(_request: request::Request) -> Result<response::Response> {
-        Ok(response::Response::Ok(
-            "Hello, Crag-Web!".as_bytes().to_vec(),
-            response::ContentType::HTML,
-        ))
+    /// Let the pool grow beyond the size given to `finalize` while
+    /// connections wait for a worker, up to `max` threads. By default the
+    /// pool keeps a fixed size.
+    pub fn max_workers(mut self, max: usize) -> Result<Self> {
+        if max == 0 {
+            anyhow::bail!("Maximum worker count must be greater than zero");
+        }
+        self.workers.max_workers = Some(max);
+        Ok(self)
     }
 
-    #[test]
-    fn test_builder_pattern() -> Result<()> {
-        // Create server
-        let _builder = Server::build()
-            .register_error_handler(handler::default_error_404_handler)?
-            .register_handler("/".into(), |_req| {
-                Ok(response::Response::Ok(
-                    "Hello, Crag-Web!".as_bytes().to_vec(),
-                    response::ContentType::HTML,
-                ))
-            })?
-            .register_handler("/hello".into(), hello_handler)?
-            .finalize(("127.0.0.1", 8010), 4)
-            .unwrap();
-        Ok(())
+    /// How long a worker beyond the pool size waits for a connection before
+    /// its thread exits, 60 seconds by default
+    pub fn worker_idle_timeout(mut self, timeout: Duration) -> Result<Self> {
+        if timeout.is_zero() {
+            anyhow::bail!("Worker idle timeout must be greater than zero");
+        }
+        self.workers.idle_timeout = timeout;
+        Ok(self)
     }
 
-    #[test]
-    fn test_no_err_handler_fails() -> Result<()> {
-        let server = Server::build()
-            .register_handler("/".into(), |_req| {
-                Ok(response::Response::Ok(
-                    "Hello, Crag-Web!".as_bytes().to_vec(),
-                    response::ContentType::HTML,
-                ))
-            })?
-            .finalize(("127.0.0.1", 8011), 1);
-        assert!(server.is_err());
-        Ok(())
+    /// Stack size of the worker threads in bytes, the platform default
+    /// unless set
+    pub fn worker_stack_size(mut self, bytes: usize) -> Result<Self> {
+        if bytes == 0 {
+            anyhow::bail!("Worker stack size must be greater than zero");
+        }
+        self.workers.stack_size = Some(bytes);
+        Ok(self)
     }
 
-    #[test]
-    fn test_parse_request_get() -> Result<()> {
-        let lines = &["GET / HTTP/1.1"];
-        let (req, content_length) = parse_request(lines.iter())?;
-        assert_eq!(req, request::Request::new(methods::Method::GET, "/".into()));
-        assert_eq!(content_length, 0);
+    /// Threads of the pool handlers run their own jobs on, reached through
+    /// `req.state::<threadpool::PoolHandle>()`. Defaults to the available
+    /// parallelism, since the jobs are meant for CPU work.
+    pub fn job_workers(mut self, workers: usize) -> Result<Self> {
+        if workers == 0 {
+            anyhow::bail!("Job worker count must be greater than zero");
+        }
+        self.workers.job_workers = workers;
+        Ok(self)
+    }
 
-        Ok(())
+    /// How long `Server::run` waits for in-flight requests after a shutdown
+    /// is requested, 30 seconds by default
+    pub fn shutdown_timeout(mut self, timeout: Duration) -> Self {
+        self.shutdown_timeout = timeout;
+        self
     }
 
-    #[test]
-    fn test_parse_request_post() -> Result<()> {
-        let lines = &["POST / HTTP/1.1", "Content-Length: 0"];
-        let (req, content_length) = parse_request(lines.iter())?;
-        let expected_req = request::Request::new(methods::Method::POST, "/".into());
-        assert_eq!(req, expected_req);
-        assert_eq!(content_length, 0);
+    /// Compress responses with the coding negotiated from each request's
+    /// Accept-Encoding
+    #[cfg(feature = "compression")]
+    pub fn compression(mut self, compression: compression::Compression) -> Self {
+        self.compression = Some(compression);
+        self
+    }
 
-        let lines = &["POST / HTTP/1.1", "Content-Length: 10", "foobarfoob"];
-        let (req, content_length) = parse_request(lines.iter())?;
-        let expected_req = request::Request {
-            method: methods::Method::POST,
-            route: "/".into(),
-            body: None,
+    fn build_handlers(self) -> Result<Handlers> {
+        // Check to see that there is an error_handler for 404 errors
+        let error_handler = match self.error_handler {
+            Some(handler) => handler,
+            None => anyhow::bail!("Error: No error handler defined"),
         };
 
-        assert_eq!(req, expected_req);
-        assert_eq!(content_length, 10);
+        Ok(Handlers {
+            valid_handlers: self.handlers,
+            error_handler,
+            server_error_handler: self
+                .server_error_handler
+                .unwrap_or_else(|| Box::new(handler::default_server_error_handler)),
+            middleware: self.middleware,
+            state: self.state,
+            #[cfg(feature = "compression")]
+            compression: self.compression,
+        })
+    }
+
+    /// Register a handler for a method on the route, or for every method
+    /// if `method` is `None`
+    fn register_endpoint(
+        mut self,
+        method: Option<methods::Method>,
+        r: routes::Route,
+        endpoint: Endpoint,
+    ) -> Result<Self> {
+        let route_handlers = self.handlers.entry(&r)?;
+        match method {
+            None if route_handlers.any.is_some() => {
+                anyhow::bail!("Handler already registered for {r:?}")
+            }
+            None => route_handlers.any = Some(endpoint),
+            Some(method) if route_handlers.by_method.contains_key(&method) => {
+                anyhow::bail!("Handler already registered for {method} {r:?}")
+            }
+            Some(method) => {
+                route_handlers.by_method.insert(method, endpoint);
+            }
+        }
+        Ok(self)
+    }
+
+    /// Register a handler that receives every method for the route
+    pub fn register_handler(
+        self,
+        r: routes::Route,
+        handler: impl handler::Handler + Send + Sync + 'static,
+    ) -> Result<Self> {
+        self.register_endpoint(None, r, Endpoint::Blocking(Box::new(handler)))
+    }
+
+    /// Register a handler for a single method on the route
+    pub fn register_method_handler(
+        self,
+        method: methods::Method,
+        r: impl Into<routes::Route>,
+        handler: impl handler::Handler + Send + Sync + 'static,
+    ) -> Result<Self> {
+        self.register_endpoint(
+            Some(method),
+            r.into(),
+            Endpoint::Blocking(Box::new(handler)),
+        )
+    }
+
+    pub fn get(
+        self,
+        r: impl Into<routes::Route>,
+        handler: impl handler::Handler + Send + Sync + 'static,
+    ) -> Result<Self> {
+        self.register_method_handler(methods::Method::GET, r, handler)
+    }
+
+    pub fn post(
+        self,
+        r: impl Into<routes::Route>,
+        handler: impl handler::Handler + Send + Sync + 'static,
+    ) -> Result<Self> {
+        self.register_method_handler(methods::Method::POST, r, handler)
+    }
+
+    pub fn put(
+        self,
+        r: impl Into<routes::Route>,
+        handler: impl handler::Handler + Send + Sync + 'static,
+    ) -> Result<Self> {
+        self.register_method_handler(methods::Method::PUT, r, handler)
+    }
+
+    pub fn patch(
+        self,
+        r: impl Into<routes::Route>,
+        handler: impl handler::Handler + Send + Sync + 'static,
+    ) -> Result<Self> {
+        self.register_method_handler(methods::Method::PATCH, r, handler)
+    }
+
+    pub fn delete(
+        self,
+        r: impl Into<routes::Route>,
+        handler: impl handler::Handler + Send + Sync + 'static,
+    ) -> Result<Self> {
+        self.register_method_handler(methods::Method::DELETE, r, handler)
+    }
+
+    pub fn head(
+        self,
+        r: impl Into<routes::Route>,
+        handler: impl handler::Handler + Send + Sync + 'static,
+    ) -> Result<Self> {
+        self.register_method_handler(methods::Method::HEAD, r, handler)
+    }
+
+    pub fn options(
+        self,
+        r: impl Into<routes::Route>,
+        handler: impl handler::Handler + Send + Sync + 'static,
+    ) -> Result<Self> {
+        self.register_method_handler(methods::Method::OPTIONS, r, handler)
+    }
+
+    /// Register an async handler that receives every method for the route.
+    /// Async handlers are only served by `finalize_async`.
+    #[cfg(feature = "async")]
+    pub fn register_async_handler(
+        mut self,
+        r: routes::Route,
+        handler: impl handler::AsyncHandler + Send + Sync + 'static,
+    ) -> Result<Self> {
+        self.async_handlers = true;
+        self.register_endpoint(None, r, Endpoint::Async(Box::new(handler)))
+    }
+
+    /// Register an async handler for a single method on the route. Async
+    /// handlers are only served by `finalize_async`.
+    #[cfg(feature = "async")]
+    pub fn register_async_method_handler(
+        mut self,
+        method: methods::Method,
+        r: impl Into<routes::Route>,
+        handler: impl handler::AsyncHandler + Send + Sync + 'static,
+    ) -> Result<Self> {
+        self.async_handlers = true;
+        self.register_endpoint(Some(method), r.into(), Endpoint::Async(Box::new(handler)))
+    }
+
+    #[cfg(feature = "async")]
+    pub fn get_async(
+        self,
+        r: impl Into<routes::Route>,
+        handler: impl handler::AsyncHandler + Send + Sync + 'static,
+    ) -> Result<Self> {
+        self.register_async_method_handler(methods::Method::GET, r, handler)
+    }
+
+    #[cfg(feature = "async")]
+    pub fn post_async(
+        self,
+        r: impl Into<routes::Route>,
+        handler: impl handler::AsyncHandler + Send + Sync + 'static,
+    ) -> Result<Self> {
+        self.register_async_method_handler(methods::Method::POST, r, handler)
+    }
+
+    #[cfg(feature = "async")]
+    pub fn put_async(
+        self,
+        r: impl Into<routes::Route>,
+        handler: impl handler::AsyncHandler + Send + Sync + 'static,
+    ) -> Result<Self> {
+        self.register_async_method_handler(methods::Method::PUT, r, handler)
+    }
+
+    #[cfg(feature = "async")]
+    pub fn patch_async(
+        self,
+        r: impl Into<routes::Route>,
+        handler: impl handler::AsyncHandler + Send + Sync + 'static,
+    ) -> Result<Self> {
+        self.register_async_method_handler(methods::Method::PATCH, r, handler)
+    }
+
+    #[cfg(feature = "async")]
+    pub fn delete_async(
+        self,
+        r: impl Into<routes::Route>,
+        handler: impl handler::AsyncHandler + Send + Sync + 'static,
+    ) -> Result<Self> {
+        self.register_async_method_handler(methods::Method::DELETE, r, handler)
+    }
+
+    #[cfg(feature = "async")]
+    pub fn head_async(
+        self,
+        r: impl Into<routes::Route>,
+        handler: impl handler::AsyncHandler + Send + Sync + 'static,
+    ) -> Result<Self> {
+        self.register_async_method_handler(methods::Method::HEAD, r, handler)
+    }
+
+    #[cfg(feature = "async")]
+    pub fn options_async(
+        self,
+        r: impl Into<routes::Route>,
+        handler: impl handler::AsyncHandler + Send + Sync + 'static,
+    ) -> Result<Self> {
+        self.register_async_method_handler(methods::Method::OPTIONS, r, handler)
+    }
+
+    /// Serve the files of a directory below `prefix`, e.g. `/assets/app.js`
+    /// from `public/app.js` when mounted at `/assets`
+    pub fn static_files(
+        self,
+        prefix: impl Into<routes::Route>,
+        files: static_files::StaticFiles,
+    ) -> Result<Self> {
+        let pattern = format!(
+            "{}/*{}",
+            prefix.into().route.trim_end_matches('/'),
+            static_files::PATH_PARAM
+        );
+        self.get(pattern.as_str(), files)
+    }
+
+    /// Wrap every request in a middleware. Middleware registered first runs
+    /// first, so it sees the request before and the response after the rest.
+    pub fn middleware(
+        mut self,
+        middleware: impl middleware::Middleware + Send + Sync + 'static,
+    ) -> Self {
+        self.middleware.push(Box::new(middleware));
+        self
+    }
+
+    /// Wrap the handlers of a single route in a middleware, inside any
+    /// global middleware. Errors if no handler was registered for the route
+    /// yet, e.g. because of a typo in its path.
+    pub fn route_middleware(
+        mut self,
+        r: impl Into<routes::Route>,
+        middleware: impl middleware::Middleware + Send + Sync + 'static,
+    ) -> Result<Self> {
+        let r = r.into();
+        let route_handlers = self.handlers.entry(&r)?;
+        if route_handlers.by_method.is_empty() && route_handlers.any.is_none() {
+            anyhow::bail!("No handlers registered for route {:?}", r.route);
+        }
+        route_handlers.middleware.push(Box::new(middleware));
+        Ok(self)
+    }
+
+    /// Share `state` with every handler and middleware, which read it with
+    /// `Request::state::<T>()`. One value is kept per type.
+    ///
+    /// `finalize` adds the `threadpool::PoolHandle` of the server's job pool.
+    pub fn with_state<T: Send + Sync + 'static>(mut self, state: T) -> Result<Self> {
+        if self.state.insert(state).is_some() {
+            anyhow::bail!(
+                "State of type {} already registered",
+                std::any::type_name::<T>()
+            );
+        }
+        Ok(self)
+    }
+
+    pub fn register_error_handler(
+        mut self,
+        handler: impl handler::Handler + Send + Sync + 'static,
+    ) -> Result<Self> {
+        if self.error_handler.is_some() {
+            anyhow::bail!("Error handler already registered");
+        }
+        self.error_handler = Some(Box::new(handler));
+        Ok(self)
+    }
+
+    /// Register the handler that turns errors returned by handlers and
+    /// middleware into responses. Defaults to
+    /// `handler::default_server_error_handler`, a generic 500 page.
+    pub fn register_server_error_handler(
+        mut self,
+        handler: impl handler::ErrorHandler + Send + Sync + 'static,
+    ) -> Result<Self> {
+        if self.server_error_handler.is_some() {
+            anyhow::bail!("Server error handler already registered");
+        }
+        self.server_error_handler = Some(Box::new(handler));
+        Ok(self)
+    }
+}
+
+impl Server {
+    pub fn build() -> ServerBuilder {
+        ServerBuilder {
+            handlers: routes::Router::new(),
+            error_handler: None,
+            server_error_handler: None,
+            middleware: vec![],
+            state: state::State::new(),
+            #[cfg(feature = "compression")]
+            compression: None,
+            #[cfg(feature = "async")]
+            async_handlers: false,
+            connection: ConnectionConfig::default(),
+            queue: QueueConfig::default(),
+            workers: WorkerConfig::default(),
+            shutdown_timeout: Duration::from_secs(30),
+        }
+    }
+
+    /// Handle that stops `run` when `shutdown` is called on it
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    /// Handle reporting the depth of the connection queue while `run` serves
+    pub fn queue_monitor(&self) -> QueueMonitor {
+        QueueMonitor {
+            queue: Arc::clone(self.pool.queue()),
+            rejected: Arc::clone(&self.rejected),
+            jobs: self.jobs.handle(),
+        }
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.tcp_listener.local_addr()?)
+    }
+
+    /// Accept and serve connections until a shutdown is requested through a
+    /// `ShutdownHandle`, then wait for in-flight requests to finish.
+    ///
+    /// Idle keep-alive connections are closed by their idle timeout, so the
+    /// shutdown timeout should be longer than the keep-alive timeout.
+    pub fn run(self) -> Result<()> {
+        for stream in self.tcp_listener.incoming() {
+            if self.shutdown.is_shutdown() {
+                break;
+            }
+            let mut stream = match stream {
+                Ok(stream) => stream,
+                Err(err) => {
+                    // errors like running out of file descriptors or a client
+                    // aborting before it was accepted pass, and returning
+                    // would stop serving every other connection
+                    warn!("Error accepting connection: {:?}", err);
+                    std::thread::sleep(ACCEPT_ERROR_DELAY);
+                    continue;
+                }
+            };
+
+            // only this thread queues connections, handlers submit their
+            // jobs to the job pool, so the queue can't fill up between this
+            // check and `execute`
+            if let OverloadPolicy::Reject { retry_after } = self.queue.policy {
+                if self.pool.queue().is_full() {
+                    self.rejected.fetch_add(1, Ordering::Relaxed);
+                    debug!("Connection queue full, answering 503");
+                    if let Err(err) = reject_connection(stream, retry_after) {
+                        debug!("Error rejecting connection: {err}");
+                    }
+                    continue;
+                }
+            }
+
+            // read timeouts are switched per request by handle_connection
+            if let Err(err) = stream.set_write_timeout(Some(self.connection.write_timeout)) {
+                debug!("Error configuring connection: {err}");
+                continue;
+            }
+            let handlers = self.handlers.clone();
+            let connection = self.connection;
+            let shutdown = self.shutdown.clone();
+
+            // error boundary, any response for the error was already sent
+            self.pool.execute(move || {
+                if let Err(e) = handle_connection(
+                    &handlers,
+                    connection,
+                    &shutdown.inner.requested,
+                    &mut stream,
+                ) {
+                    error!("Error handling connection: {:?}", e);
+                };
+            });
+        }
+
+        let deadline = Instant::now() + self.shutdown_timeout;
+        if !self.pool.shutdown(self.shutdown_timeout) {
+            warn!("Shutdown timeout elapsed with requests still in flight");
+        }
+        // jobs still running belong to requests that already timed out
+        if !self
+            .jobs
+            .shutdown(deadline.saturating_duration_since(Instant::now()))
+        {
+            warn!("Shutdown timeout elapsed with handler jobs still running");
+        }
+        Ok(())
+    }
+}
+
+/// Serve requests on a connection until the client or the server decides
+/// to close it.
+///
+/// Requests that cannot be read are answered with the error's status before
+/// the connection is closed and the error returned.
+fn handle_connection<S>(
+    handlers: &Handlers,
+    connection: ConnectionConfig,
+    shutdown: &AtomicBool,
+    stream: &mut S,
+) -> error::Result<()>
+where
+    S: Transport,
+{
+    // the reader is kept across requests so pipelined bytes are not lost
+    let mut reader = BufReader::new(stream);
+
+    for served in 1..=connection.max_requests {
+        // wait for the next request under the idle timeout, then bound each
+        // read by the read timeout while the rest of it arrives
+        reader.get_ref().set_read_timeout(connection.idle_timeout)?;
+        match reader.fill_buf() {
+            // client closed the connection
+            Ok([]) => break,
+            Ok(_) => {}
+            Err(err) if is_idle_timeout(&err) => break,
+            Err(err) => return Err(err.into()),
+        }
+        reader.get_ref().set_read_timeout(connection.read_timeout)?;
+
+        let req = match read_and_parse_request(&mut reader, &connection.limits) {
+            Ok(Some(req)) => req,
+            // client closed the connection or it timed out while idle
+            Ok(None) => break,
+            // the connection is broken, there is no one to answer
+            Err(err @ Error::Io(_)) => return Err(err),
+            Err(err) => {
+                let stream = reader.get_mut();
+                stream.write_all(&error_response(&err))?;
+                stream.flush()?;
+                return Err(err);
+            }
+        };
+        let is_head = req.method == methods::Method::HEAD;
+        let client_close = req.headers.contains_token("Connection", "close");
+
+        // build response
+        let mut response = handlers.respond(req);
+
+        let keep_alive = connection.keep_alive_after(
+            &mut response,
+            served,
+            client_close,
+            shutdown.load(Ordering::SeqCst),
+        );
+
+        // write response into TcpStream, buffered so the head and small
+        // chunks don't each become a separate packet
+        let mut writer = BufWriter::new(reader.get_mut());
+        if is_head {
+            // HEAD responses carry the GET headers but never a body
+            writer.write_all(&response.head_bytes())?;
+        } else {
+            // the head may already be sent, so a failing body can only
+            // be reported by closing the connection
+            response.write_to(&mut writer).map_err(body_error)?;
+        }
+        writer.flush()?;
+
+        if !keep_alive {
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+/// Error of a response body that failed while being written
+fn body_error(err: anyhow::Error) -> Error {
+    match err.downcast::<std::io::Error>() {
+        Ok(err) => Error::from(err),
+        Err(err) => Error::Handler(err),
+    }
+}
+
+/// Answer to a request that failed before its response was started, with
+/// the error's status, after which the connection is closed
+fn error_response(err: &Error) -> Vec<u8> {
+    let mut response = response::Response::new(err.status());
+    response.headers.set("Connection", "close");
+    response.head_bytes()
+}
+
+/// Answer a connection the server has no capacity for with 503 without
+/// blocking the accept loop, then close it
+fn reject_connection(mut stream: TcpStream, retry_after: Duration) -> std::io::Result<()> {
+    stream.set_nonblocking(true)?;
+    // discard what the client already sent, since closing a socket with
+    // unread data resets the connection and may lose the response
+    let mut discard = [0; 4096];
+    for _ in 0..4 {
+        match stream.read(&mut discard) {
+            Ok(0) => break,
+            Ok(_) => {}
+            Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => break,
+            Err(err) => return Err(err),
+        }
+    }
+    // the response is small enough for the empty send buffer
+    write_overloaded_response(&mut stream, retry_after)?;
+    stream.shutdown(Shutdown::Write)
+}
+
+fn write_overloaded_response(
+    stream: &mut impl Write,
+    retry_after: Duration,
+) -> std::io::Result<()> {
+    // Retry-After takes whole seconds, round up so clients don't retry early
+    let seconds = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+    let mut response = response::Response::new(status::StatusCode::SERVICE_UNAVAILABLE);
+    response.headers.set("Retry-After", seconds.to_string());
+    response.headers.set("Connection", "close");
+    stream.write_all(&response.head_bytes())?;
+    stream.flush()
+}
+
+/// Stream a connection is served over
+trait Transport: Read + Write {
+    fn set_read_timeout(&self, timeout: Duration) -> std::io::Result<()>;
+}
+
+impl Transport for TcpStream {
+    fn set_read_timeout(&self, timeout: Duration) -> std::io::Result<()> {
+        TcpStream::set_read_timeout(self, Some(timeout))
+    }
+}
+
+/// Read the next request from the connection. Returns `None` if the
+/// connection is closed before a request starts.
+fn read_and_parse_request(
+    buffer: &mut impl BufRead,
+    limits: &RequestLimits,
+) -> error::Result<Option<request::Request>> {
+    let deadline = Instant::now() + limits.header_timeout;
+    let mut parser = RequestParser::new(limits);
+    loop {
+        // checked before each read so a client trickling bytes can't hold
+        // the connection indefinitely
+        if parser.in_head() && Instant::now() > deadline {
+            return Err(Error::Timeout);
+        }
+        match parser.parse(buffer.fill_buf()?)? {
+            Parsed::Partial(used) => buffer.consume(used),
+            Parsed::Complete(used, req) => {
+                buffer.consume(used);
+                return Ok(req);
+            }
+        }
+    }
+}
+
+fn is_idle_timeout(err: &std::io::Error) -> bool {
+    matches!(
+        err.kind(),
+        std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+    )
+}
+
+/// How the request body is delimited on the wire
+#[derive(Eq, PartialEq, Debug)]
+enum BodyFraming {
+    Empty,
+    Length(usize),
+    Chunked,
+}
+
+impl BodyFraming {
+    /// Determine the body framing from Transfer-Encoding and Content-Length
+    /// as described in RFC 9112 section 6.3
+    fn from_headers(headers: &headers::Headers) -> error::Result<BodyFraming> {
+        let codings: Vec<&str> = headers
+            .get_all("Transfer-Encoding")
+            .flat_map(|value| value.split(','))
+            .map(str::trim)
+            .filter(|coding| !coding.is_empty())
+            .collect();
+
+        if !codings.is_empty() && headers.contains("Content-Length") {
+            // RFC 9112 6.1: a message with both is likely an attempt at
+            // request smuggling
+            return Err(Error::malformed(
+                "Request has both Content-Length and Transfer-Encoding",
+            ));
+        }
+
+        if let Some(last) = codings.last() {
+            // the final coding of a request must be chunked for the body length
+            // to be known, anything else is unrecoverable
+            if !last.eq_ignore_ascii_case("chunked") {
+                return Err(Error::malformed(format!(
+                    "Unsupported Transfer-Encoding: {}",
+                    codings.join(", ")
+                )));
+            }
+            return Ok(BodyFraming::Chunked);
+        }
+
+        Ok(match headers.content_length()? {
+            None | Some(0) => BodyFraming::Empty,
+            Some(length) => BodyFraming::Length(length),
+        })
+    }
+}
+
+fn parse_request<IT, S>(lines: IT) -> error::Result<(request::Request, BodyFraming)>
+where
+    IT: IntoIterator<Item = S>,
+    S: AsRef<str>,
+{
+    let mut lines = lines.into_iter();
+    // build request from header
+    let first_line = lines
+        .next()
+        .ok_or_else(|| Error::malformed("No request line found"))?;
+    let mut req = request::Request::parse(first_line.as_ref())?;
+
+    // collect the remaining lines as headers
+    for line in lines {
+        let (name, value) = headers::Headers::parse_line(line)?;
+        req.headers.insert(name, value);
+    }
+
+    let framing = BodyFraming::from_headers(&req.headers)?;
+
+    Ok((req, framing))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::body;
+    use crate::handler;
+    use crate::query;
+    use crate::request;
+    use crate::response;
+    use crate::status;
+    use anyhow::Result;
+
+    // get "/hello"
+    fn hello_handler(_request: request::Request) -> Result<response::Response> {
+        Ok(response::Response::Ok(
+            "Hello, Crag-Web!".as_bytes().to_vec(),
+            response::ContentType::HTML,
+        ))
+    }
+
+    #[test]
+    fn test_builder_pattern() -> Result<()> {
+        // Create server
+        let _builder = Server::build()
+            .register_error_handler(handler::default_error_404_handler)?
+            .register_handler("/".into(), |_req| {
+                Ok(response::Response::Ok(
+                    "Hello, Crag-Web!".as_bytes().to_vec(),
+                    response::ContentType::HTML,
+                ))
+            })?
+            .register_handler("/hello".into(), hello_handler)?
+            .finalize(("127.0.0.1", 8010), 4)
+            .unwrap();
+        Ok(())
+    }
+
+    #[test]
+    fn test_no_err_handler_fails() -> Result<()> {
+        let server = Server::build()
+            .register_handler("/".into(), |_req| {
+                Ok(response::Response::Ok(
+                    "Hello, Crag-Web!".as_bytes().to_vec(),
+                    response::ContentType::HTML,
+                ))
+            })?
+            .finalize(("127.0.0.1", 8011), 1);
+        assert!(server.is_err());
+        Ok(())
+    }
+
+    fn text_handler(text: &'static str) -> impl handler::Handler + Send + Sync + 'static {
+        move |_req| {
+            Ok(response::Response::Ok(
+                text.as_bytes().to_vec(),
+                response::ContentType::PLAIN,
+            ))
+        }
+    }
+
+    fn body_of(response: response::Response) -> Vec<u8> {
+        assert_eq!(response.status, status::StatusCode::OK);
+        response.body.into_bytes().unwrap()
+    }
+
+    #[test]
+    fn test_method_routing() -> Result<()> {
+        let handlers = Server::build()
+            .register_error_handler(handler::default_error_404_handler)?
+            .get("/items", text_handler("list"))?
+            .post("/items", text_handler("create"))?
+            .build_handlers()?;
+
+        let req = request::Request::new(methods::Method::GET, "/items".into());
+        assert_eq!(body_of(handlers.handle(req)?), b"list");
+
+        let req = request::Request::new(methods::Method::POST, "/items".into());
+        assert_eq!(body_of(handlers.handle(req)?), b"create");
+
+        let req = request::Request::new(methods::Method::GET, "/missing".into());
+        assert_eq!(handlers.handle(req)?.status, status::StatusCode::NOT_FOUND);
+        Ok(())
+    }
+
+    #[test]
+    fn test_method_not_allowed() -> Result<()> {
+        let handlers = Server::build()
+            .register_error_handler(handler::default_error_404_handler)?
+            .get("/items", text_handler("list"))?
+            .build_handlers()?;
+
+        let req = request::Request::new(methods::Method::POST, "/items".into());
+        let response = handlers.handle(req)?;
+        assert_eq!(response.status, status::StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(response.headers.get("Allow"), Some("GET, HEAD"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_any_method_handler_falls_back() -> Result<()> {
+        let handlers = Server::build()
+            .register_error_handler(handler::default_error_404_handler)?
+            .register_handler("/items".into(), text_handler("any"))?
+            .post("/items", text_handler("create"))?
+            .build_handlers()?;
+
+        let req = request::Request::new(methods::Method::GET, "/items".into());
+        assert_eq!(body_of(handlers.handle(req)?), b"any");
+
+        let req = request::Request::new(methods::Method::POST, "/items".into());
+        assert_eq!(body_of(handlers.handle(req)?), b"create");
+        Ok(())
+    }
+
+    #[test]
+    fn test_route_params_reach_handler() -> Result<()> {
+        let handlers = Server::build()
+            .register_error_handler(handler::default_error_404_handler)?
+            .get("/users/:id", |req: request::Request| {
+                Ok(response::Response::Ok(
+                    req.param("id").unwrap_or_default().as_bytes().to_vec(),
+                    response::ContentType::PLAIN,
+                ))
+            })?
+            .get("/static/*path", |req: request::Request| {
+                Ok(response::Response::Ok(
+                    req.param("path").unwrap_or_default().as_bytes().to_vec(),
+                    response::ContentType::PLAIN,
+                ))
+            })?
+            .build_handlers()?;
+
+        let req = request::Request::new(methods::Method::GET, "/users/42".into());
+        assert_eq!(body_of(handlers.handle(req)?), b"42");
+
+        let req = request::Request::new(methods::Method::GET, "/static/css/main.css".into());
+        assert_eq!(body_of(handlers.handle(req)?), b"css/main.css");
+
+        // an encoded slash is part of the parameter, not a segment boundary
+        let req = request::Request::parse("GET /users/a%2Fb HTTP/1.1")?;
+        assert_eq!(body_of(handlers.handle(req)?), b"a/b");
+
+        let req = request::Request::new(methods::Method::GET, "/users".into());
+        assert_eq!(handlers.handle(req)?.status, status::StatusCode::NOT_FOUND);
+        Ok(())
+    }
+
+    #[test]
+    fn test_duplicate_method_handler_fails() -> Result<()> {
+        let builder = Server::build().get("/items", text_handler("list"))?;
+        assert!(builder.get("/items", text_handler("list")).is_err());
+
+        let builder = Server::build().register_handler("/items".into(), text_handler("any"))?;
+        assert!(builder
+            .register_handler("/items".into(), text_handler("any"))
+            .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_request_get() -> Result<()> {
+        let lines = &["GET / HTTP/1.1"];
+        let (req, framing) = parse_request(lines.iter())?;
+        assert_eq!(req, request::Request::new(methods::Method::GET, "/".into()));
+        assert_eq!(framing, BodyFraming::Empty);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_request_post() -> Result<()> {
+        let lines = &["POST / HTTP/1.1", "Content-Length: 0"];
+        let (req, framing) = parse_request(lines.iter())?;
+        let mut expected_req = request::Request::new(methods::Method::POST, "/".into());
+        expected_req.headers.insert("Content-Length", "0");
+        assert_eq!(req, expected_req);
+        assert_eq!(framing, BodyFraming::Empty);
+
+        let lines = &["POST / HTTP/1.1", "Content-Length: 10", "Host: localhost"];
+        let (req, framing) = parse_request(lines.iter())?;
+        let expected_req = request::Request {
+            method: methods::Method::POST,
+            route: "/".into(),
+            query: query::Query::new(),
+            headers: [("Content-Length", "10"), ("Host", "localhost")]
+                .into_iter()
+                .collect(),
+            params: routes::Params::new(),
+            body: None,
+            trailers: headers::Headers::new(),
+            state: state::State::new(),
+        };
+
+        assert_eq!(req, expected_req);
+        assert_eq!(framing, BodyFraming::Length(10));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_request_framing() -> Result<()> {
+        let lines = &["DELETE /items/1 HTTP/1.1", "Content-Length: 4"];
+        let (_, framing) = parse_request(lines.iter())?;
+        assert_eq!(framing, BodyFraming::Length(4));
+
+        let lines = &["PUT / HTTP/1.1", "Transfer-Encoding: chunked"];
+        let (_, framing) = parse_request(lines.iter())?;
+        assert_eq!(framing, BodyFraming::Chunked);
+
+        let lines = &["PUT / HTTP/1.1", "Transfer-Encoding: gzip, Chunked"];
+        let (_, framing) = parse_request(lines.iter())?;
+        assert_eq!(framing, BodyFraming::Chunked);
+
+        let lines = &["PUT / HTTP/1.1", "Transfer-Encoding: gzip"];
+        assert!(parse_request(lines.iter()).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_request_headers() -> Result<()> {
+        let lines = &[
+            "GET / HTTP/1.1",
+            "Host: localhost:8010",
+            "Accept: text/html",
+            "accept: application/json",
+            "Authorization: Bearer token",
+        ];
+        let (req, _) = parse_request(lines.iter())?;
+        assert_eq!(req.headers.host(), Some("localhost:8010"));
+        assert_eq!(req.headers.authorization(), Some("Bearer token"));
+        assert_eq!(
+            req.headers.get_all("Accept").collect::<Vec<_>>(),
+            vec!["text/html", "application/json"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_request_bad_header() {
+        let lines = &["GET / HTTP/1.1", "not a header"];
+        assert!(parse_request(lines.iter()).is_err());
+
+        let lines = &["POST / HTTP/1.1", "Content-Length: ten"];
+        assert!(parse_request(lines.iter()).is_err());
+    }
+
+    #[test]
+    fn test_parse_request_empty() -> Result<()> {
+        let empty: &[&str; 0] = &[];
+        let res = parse_request(empty.iter());
+        assert!(
+            matches!(res, Err(Error::MalformedRequest(message)) if message == "No request line found")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_and_parse_request_get() -> Result<()> {
+        let req = [
+            "GET / HTTP/1.1\r\n",
+            "Content-Length: 13\r\n",
+            "\r\n",
+            "Hello, World!",
+        ]
+        .join("");
+        let mut stream = req.as_bytes();
+
+        // turn stream into BufReader
+        let res = read_and_parse_request(&mut stream, &test_limits())?.unwrap();
+        let expected = request::Request {
+            route: "/".into(),
+            query: query::Query::new(),
+            method: methods::Method::GET,
+            headers: [("Content-Length", "13")].into_iter().collect(),
+            params: routes::Params::new(),
+            body: Some("Hello, World!".to_owned()),
+            trailers: headers::Headers::new(),
+            state: state::State::new(),
+        };
+        assert_eq!(res, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_and_parse_request_post() -> Result<()> {
+        let req = [
+            "POST / HTTP/1.1\r\n",
+            "Content-Length: 13\r\n",
+            "\r\n",
+            "Hello, World!",
+        ]
+        .join("");
+        let mut stream = req.as_bytes();
+
+        // turn stream into BufReader
+        let res = read_and_parse_request(&mut stream, &test_limits())?.unwrap();
+        let expected = request::Request {
+            route: "/".into(),
+            query: query::Query::new(),
+            method: methods::Method::POST,
+            headers: [("Content-Length", "13")].into_iter().collect(),
+            params: routes::Params::new(),
+            body: Some("Hello, World!".to_owned()),
+            trailers: headers::Headers::new(),
+            state: state::State::new(),
+        };
+
+        assert_eq!(res, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn test_head_falls_back_to_get() -> Result<()> {
+        let handlers = Server::build()
+            .register_error_handler(handler::default_error_404_handler)?
+            .get("/items", text_handler("list"))?
+            .delete("/items", text_handler("deleted"))?
+            .build_handlers()?;
+
+        let mut stream = TestStream::new("HEAD /items HTTP/1.1\r\nConnection: close\r\n\r\n");
+        handle_connection(
+            &handlers,
+            ConnectionConfig::default(),
+            &AtomicBool::new(false),
+            &mut stream,
+        )?;
+        let response = String::from_utf8(stream.output)?;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("Content-Length: 4\r\n"));
+        assert!(response.ends_with("\r\n\r\n"));
+
+        let req = request::Request::new(methods::Method::PUT, "/items".into());
+        let response = handlers.handle(req)?;
+        assert_eq!(response.status, status::StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(response.headers.get("Allow"), Some("DELETE, GET, HEAD"));
+        Ok(())
+    }
+
+    fn keep_alive_handlers() -> Result<Handlers> {
+        Server::build()
+            .register_error_handler(handler::default_error_404_handler)?
+            .get("/a", text_handler("a"))?
+            .post("/b", text_handler("b"))?
+            .build_handlers()
+    }
+
+    #[test]
+    fn test_keep_alive_serves_pipelined_requests() -> Result<()> {
+        let handlers = keep_alive_handlers()?;
+        let mut stream = TestStream::new(
+            "GET /a HTTP/1.1\r\n\r\nPOST /b HTTP/1.1\r\nContent-Length: 3\r\n\r\nxyzGET /a HTTP/1.1\r\n\r\n",
+        );
+        handle_connection(
+            &handlers,
+            ConnectionConfig::default(),
+            &AtomicBool::new(false),
+            &mut stream,
+        )?;
+
+        let output = String::from_utf8(stream.output)?;
+        assert_eq!(output.matches("HTTP/1.1 200 OK\r\n").count(), 3);
+        assert!(!output.contains("Connection: close"));
+        assert!(output.ends_with("\r\n\r\na"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_keep_alive_honors_connection_close() -> Result<()> {
+        let handlers = keep_alive_handlers()?;
+        let mut stream =
+            TestStream::new("GET /a HTTP/1.1\r\nConnection: close\r\n\r\nGET /a HTTP/1.1\r\n\r\n");
+        handle_connection(
+            &handlers,
+            ConnectionConfig::default(),
+            &AtomicBool::new(false),
+            &mut stream,
+        )?;
+
+        let output = String::from_utf8(stream.output)?;
+        assert_eq!(output.matches("HTTP/1.1 200 OK\r\n").count(), 1);
+        assert!(output.contains("Connection: close\r\n"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_keep_alive_max_requests() -> Result<()> {
+        let handlers = keep_alive_handlers()?;
+        let connection = ConnectionConfig {
+            max_requests: 2,
+            ..ConnectionConfig::default()
+        };
+        let mut stream = TestStream::new("GET /a HTTP/1.1\r\n\r\n".repeat(3).as_str());
+        handle_connection(&handlers, connection, &AtomicBool::new(false), &mut stream)?;
+
+        let output = String::from_utf8(stream.output)?;
+        assert_eq!(output.matches("HTTP/1.1 200 OK\r\n").count(), 2);
+        assert_eq!(output.matches("Connection: close\r\n").count(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_keep_alive_disabled() -> Result<()> {
+        let handlers = keep_alive_handlers()?;
+        let connection = ConnectionConfig {
+            keep_alive: false,
+            ..ConnectionConfig::default()
+        };
+        let mut stream = TestStream::new("GET /a HTTP/1.1\r\n\r\n".repeat(2).as_str());
+        handle_connection(&handlers, connection, &AtomicBool::new(false), &mut stream)?;
+
+        let output = String::from_utf8(stream.output)?;
+        assert_eq!(output.matches("HTTP/1.1 200 OK\r\n").count(), 1);
+        assert!(output.contains("Connection: close\r\n"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_and_parse_request_closed_connection() -> Result<()> {
+        let mut stream: &[u8] = b"";
+        assert!(read_and_parse_request(&mut stream, &test_limits())?.is_none());
 
+        let mut stream: &[u8] = b"\r\nGET / HTTP/1.1\r\n\r\n";
+        assert!(read_and_parse_request(&mut stream, &test_limits())?.is_some());
         Ok(())
     }
 
     #[test]
-    fn test_parse_request_empty() -> Result<()> {
-        let empty: &[&str; 0] = &[];
-        let res = parse_request(empty.iter());
-        assert!(res.is_err());
-        assert_eq!(res.err().unwrap().to_string(), "No request line found");
+    fn test_builder_rejects_bad_keep_alive_settings() {
+        assert!(Server::build()
+            .keep_alive_timeout(Duration::from_secs(0))
+            .is_err());
+        assert!(Server::build().max_requests_per_connection(0).is_err());
+    }
+
+    #[test]
+    fn test_read_and_parse_request_limits() -> Result<()> {
+        let limits = RequestLimits {
+            max_request_line: 32,
+            max_headers: 2,
+            max_header_size: 32,
+            ..test_limits()
+        };
+
+        // the request line limit includes the line ending
+        let mut stream: &[u8] = b"GET /0123456789abcdef HTTP/1.1\r\n\r\n";
+        assert!(read_and_parse_request(&mut stream, &limits)?.is_some());
+        let mut stream: &[u8] = b"GET /0123456789abcdefg HTTP/1.1\r\n\r\n";
+        assert!(matches!(
+            read_and_parse_request(&mut stream, &limits),
+            Err(Error::UriTooLong)
+        ));
+
+        let mut stream: &[u8] = b"GET / HTTP/1.1\r\nA: 1\r\nB: 2\r\n\r\n";
+        assert!(read_and_parse_request(&mut stream, &limits)?.is_some());
+        let mut stream: &[u8] = b"GET / HTTP/1.1\r\nA: 1\r\nB: 2\r\nC: 3\r\n\r\n";
+        assert!(matches!(
+            read_and_parse_request(&mut stream, &limits),
+            Err(Error::HeaderTooLarge)
+        ));
+
+        // header lines filling the limit exactly, the blank line after them
+        // doesn't count
+        let mut stream: &[u8] = b"GET / HTTP/1.1\r\nX-Long: 0123456789abcdef012345\r\n\r\n";
+        assert!(read_and_parse_request(&mut stream, &limits)?.is_some());
+        let mut stream: &[u8] = b"GET / HTTP/1.1\r\nX-Long: 0123456789abcdef0123456\r\n\r\n";
+        assert!(matches!(
+            read_and_parse_request(&mut stream, &limits),
+            Err(Error::HeaderTooLarge)
+        ));
+        // nor can a short header sneak in after the limit is reached
+        let mut stream: &[u8] = b"GET / HTTP/1.1\r\nX-Long: 0123456789abcdef012345\r\nA\n\r\n";
+        assert!(matches!(
+            read_and_parse_request(&mut stream, &limits),
+            Err(Error::HeaderTooLarge)
+        ));
+
+        // one header larger than the whole header section
+        let mut stream: &[u8] = b"GET / HTTP/1.1\r\nX-Long: 0123456789abcdef0123456789\r\n\r\n";
+        assert!(matches!(
+            read_and_parse_request(&mut stream, &limits),
+            Err(Error::HeaderTooLarge)
+        ));
         Ok(())
     }
 
     #[test]
-    fn test_read_and_parse_request_get() -> Result<()> {
-        let req = vec![
-            "GET / HTTP/1.1\r\n",
-            "Content-Length: 13\r\n",
-            "\r\n",
-            "Hello, World!",
-        ]
-        .join("");
-        let mut stream = req.as_bytes();
+    fn test_read_and_parse_request_header_timeout() {
+        /// Reader that sends one byte at a time, slowly
+        struct SlowReader(&'static [u8]);
 
-        // turn stream into BufReader
-        let res = read_and_parse_request(&mut stream)?;
-        let expected = request::Request {
-            route: "/".into(),
-            method: methods::Method::GET,
-            body: None,
+        impl Read for SlowReader {
+            fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+                std::thread::sleep(Duration::from_millis(5));
+                self.0.read(&mut buf[..1])
+            }
+        }
+
+        let mut stream =
+            BufReader::with_capacity(1, SlowReader(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n"));
+        let limits = RequestLimits {
+            header_timeout: Duration::from_millis(50),
+            ..test_limits()
         };
-        assert_eq!(res, expected);
+        assert!(matches!(
+            read_and_parse_request(&mut stream, &limits),
+            Err(Error::Timeout)
+        ));
+    }
+
+    #[test]
+    fn test_read_and_parse_request_truncated_body() {
+        let mut stream: &[u8] = b"POST / HTTP/1.1\r\nContent-Length: 10\r\n\r\nHello";
+        assert!(matches!(
+            read_and_parse_request(&mut stream, &test_limits()),
+            Err(Error::Io(_))
+        ));
+    }
+
+    #[test]
+    fn test_builder_rejects_zero_limits() {
+        assert!(Server::build().max_request_line_length(0).is_err());
+        assert!(Server::build().max_headers(0).is_err());
+        assert!(Server::build().max_header_size(0).is_err());
+        assert!(Server::build().header_timeout(Duration::ZERO).is_err());
+        assert!(Server::build().read_timeout(Duration::ZERO).is_err());
+        assert!(Server::build().write_timeout(Duration::ZERO).is_err());
+    }
+
+    fn body_limit(max_body_size: usize) -> RequestLimits {
+        RequestLimits {
+            max_body_size,
+            ..RequestLimits::default()
+        }
+    }
+
+    fn test_limits() -> RequestLimits {
+        body_limit(1024)
+    }
+
+    #[test]
+    fn test_read_and_parse_request_chunked() -> Result<()> {
+        let mut stream: &[u8] = b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5;ext=1\r\nHello\r\n8\r\n, World!\r\n0\r\nX-Trailer: done\r\n\r\n";
+        let req = read_and_parse_request(&mut stream, &test_limits())?.unwrap();
+        assert_eq!(req.body, Some("Hello, World!".to_owned()));
+        assert_eq!(req.trailers.get("x-trailer"), Some("done"));
+        assert!(stream.is_empty());
         Ok(())
     }
 
     #[test]
-    fn test_read_and_parse_request_post() -> Result<()> {
-        let req = vec![
-            "POST / HTTP/1.1\r\n",
-            "Content-Length: 13\r\n",
-            "\r\n",
-            "Hello, World!",
-        ]
-        .join("");
-        let mut stream = req.as_bytes();
+    fn test_read_and_parse_request_binary_body() {
+        // handlers get the body as text, so a binary one can't be passed on
+        let mut stream: &[u8] = b"POST / HTTP/1.1\r\nContent-Length: 4\r\n\r\n\xff\xfe\x00\x01";
+        let res = read_and_parse_request(&mut stream, &test_limits());
+        assert!(matches!(res, Err(Error::MalformedRequest(_))));
 
-        // turn stream into BufReader
-        let res = read_and_parse_request(&mut stream)?;
-        let expected = request::Request {
-            route: "/".into(),
-            method: methods::Method::POST,
-            body: Some("Hello, World!".to_owned()),
+        let mut stream: &[u8] =
+            b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n2\r\n\xc3\x28\r\n0\r\n\r\n";
+        let res = read_and_parse_request(&mut stream, &test_limits());
+        assert!(matches!(res, Err(Error::MalformedRequest(_))));
+    }
+
+    #[test]
+    fn test_read_and_parse_request_body_too_large() {
+        let mut stream: &[u8] =
+            b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nHello\r\n0\r\n\r\n";
+        assert!(matches!(
+            read_and_parse_request(&mut stream, &body_limit(4)),
+            Err(Error::BodyTooLarge { limit: 4 })
+        ));
+
+        let mut stream: &[u8] = b"POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\nHello";
+        assert!(matches!(
+            read_and_parse_request(&mut stream, &body_limit(4)),
+            Err(Error::BodyTooLarge { limit: 4 })
+        ));
+    }
+
+    #[test]
+    fn test_request_errors_answered_with_status() -> Result<()> {
+        let handlers = keep_alive_handlers()?;
+        let long_uri = format!("GET /{} HTTP/1.1\r\n\r\n", "a".repeat(9000));
+        let many_headers = format!("GET /a HTTP/1.1\r\n{}\r\n", "X-A: 1\r\n".repeat(101));
+        let cases = [
+            (long_uri.as_str(), "HTTP/1.1 414 URI Too Long\r\n"),
+            (
+                many_headers.as_str(),
+                "HTTP/1.1 431 Request Header Fields Too Large\r\n",
+            ),
+            (
+                "GET /a HTTP/1.0\r\n\r\n",
+                "HTTP/1.1 505 HTTP Version Not Supported\r\n",
+            ),
+            (
+                "GET /a HTTP/1.1\r\nbad header\r\n\r\n",
+                "HTTP/1.1 400 Bad Request\r\n",
+            ),
+            ("G(T /a HTTP/1.1\r\n\r\n", "HTTP/1.1 400 Bad Request\r\n"),
+            (
+                "POST /b HTTP/1.1\r\nContent-Length: +13\r\n\r\nHello, World!",
+                "HTTP/1.1 400 Bad Request\r\n",
+            ),
+            (
+                "POST /b HTTP/1.1\r\nContent-Length: 2048\r\n\r\n",
+                "HTTP/1.1 413 Content Too Large\r\n",
+            ),
+        ];
+
+        for (input, status_line) in cases {
+            let mut stream = TestStream::new(input);
+            let result = handle_connection(
+                &handlers,
+                ConnectionConfig {
+                    limits: test_limits(),
+                    ..ConnectionConfig::default()
+                },
+                &AtomicBool::new(false),
+                &mut stream,
+            );
+            assert!(result.is_err());
+
+            let output = String::from_utf8(stream.output)?;
+            assert!(output.starts_with(status_line), "{output}");
+            assert!(output.contains("Connection: close\r\n"));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_handler_error_answered_with_500() -> Result<()> {
+        let handlers = Server::build()
+            .register_error_handler(handler::default_error_404_handler)?
+            .get("/fail", |_req| anyhow::bail!("database unavailable"))?
+            .build_handlers()?;
+
+        // the request was read in full so the connection stays usable
+        let mut stream = TestStream::new("GET /fail HTTP/1.1\r\n\r\nGET /fail HTTP/1.1\r\n\r\n");
+        handle_connection(
+            &handlers,
+            ConnectionConfig::default(),
+            &AtomicBool::new(false),
+            &mut stream,
+        )?;
+
+        let output = String::from_utf8(stream.output)?;
+        assert_eq!(
+            output
+                .matches("HTTP/1.1 500 Internal Server Error\r\nContent-Type: text/html\r\n")
+                .count(),
+            2
+        );
+        assert!(!output.contains("database unavailable"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_handler_panic_answered_with_500() -> Result<()> {
+        let handlers = Server::build()
+            .register_error_handler(handler::default_error_404_handler)?
+            .get("/panic", |_req| -> Result<response::Response> {
+                panic!("handler bug")
+            })?
+            .get("/a", text_handler("a"))?
+            .build_handlers()?;
+
+        let mut stream = TestStream::new("GET /panic HTTP/1.1\r\n\r\nGET /a HTTP/1.1\r\n\r\n");
+        handle_connection(
+            &handlers,
+            ConnectionConfig::default(),
+            &AtomicBool::new(false),
+            &mut stream,
+        )?;
+
+        let output = String::from_utf8(stream.output)?;
+        assert!(output.starts_with("HTTP/1.1 500 Internal Server Error\r\n"));
+        assert!(output.contains("HTTP/1.1 200 OK\r\n"));
+        assert!(output.ends_with("\r\n\r\na"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_custom_server_error_handler() -> Result<()> {
+        let handlers = Server::build()
+            .register_error_handler(handler::default_error_404_handler)?
+            .register_server_error_handler(|err: &anyhow::Error, req: &request::RequestHead| {
+                let body = format!("{} {} failed: {err}", req.method, req.route.route);
+                response::Response::build()
+                    .status(status::StatusCode::SERVICE_UNAVAILABLE)
+                    .body(body)
+                    .finalize()
+                    .unwrap()
+            })?
+            .get("/fail", |_req| anyhow::bail!("database unavailable"))?
+            .build_handlers()?;
+
+        let req = request::Request::new(methods::Method::GET, "/fail".into());
+        let response = handlers.respond(req);
+        assert_eq!(response.status, status::StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(
+            response.body.into_bytes()?,
+            b"GET /fail failed: database unavailable"
+        );
+
+        assert!(Server::build()
+            .register_server_error_handler(handler::default_server_error_handler)?
+            .register_server_error_handler(handler::default_server_error_handler)
+            .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_request_rejects_length_and_chunked() {
+        let lines = &[
+            "POST / HTTP/1.1",
+            "Content-Length: 5",
+            "Transfer-Encoding: chunked",
+        ];
+        let res = parse_request(lines.iter());
+        assert!(matches!(res, Err(Error::MalformedRequest(_))));
+    }
+
+    #[test]
+    fn test_keep_alive_after_chunked_request() -> Result<()> {
+        let handlers = keep_alive_handlers()?;
+        let mut stream = TestStream::new(
+            "POST /b HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nxyz\r\n0\r\n\r\nGET /a HTTP/1.1\r\n\r\n",
+        );
+        handle_connection(
+            &handlers,
+            ConnectionConfig::default(),
+            &AtomicBool::new(false),
+            &mut stream,
+        )?;
+
+        let output = String::from_utf8(stream.output)?;
+        assert_eq!(output.matches("HTTP/1.1 200 OK\r\n").count(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_streamed_response_on_keep_alive_connection() -> Result<()> {
+        let handlers = Server::build()
+            .register_error_handler(handler::default_error_404_handler)?
+            .get("/report", |_req| {
+                let rows = (1..=3).map(|i| Ok(format!("row {i}\n")));
+                response::Response::build()
+                    .content_type(response::ContentType::PLAIN)
+                    .body(body::Body::from_chunks(rows))
+                    .finalize()
+            })?
+            .build_handlers()?;
+
+        let mut stream = TestStream::new("GET /report HTTP/1.1\r\n\r\n".repeat(2).as_str());
+        handle_connection(
+            &handlers,
+            ConnectionConfig::default(),
+            &AtomicBool::new(false),
+            &mut stream,
+        )?;
+
+        let output = String::from_utf8(stream.output)?;
+        assert_eq!(output.matches("Transfer-Encoding: chunked\r\n").count(), 2);
+        assert_eq!(output.matches("6\r\nrow 3\n\r\n0\r\n\r\n").count(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_shutdown_closes_keep_alive_connection() -> Result<()> {
+        let handlers = keep_alive_handlers()?;
+        let mut stream = TestStream::new("GET /a HTTP/1.1\r\n\r\n".repeat(2).as_str());
+        handle_connection(
+            &handlers,
+            ConnectionConfig::default(),
+            &AtomicBool::new(true),
+            &mut stream,
+        )?;
+
+        let output = String::from_utf8(stream.output)?;
+        assert_eq!(output.matches("HTTP/1.1 200 OK\r\n").count(), 1);
+        assert!(output.contains("Connection: close\r\n"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_graceful_shutdown() -> Result<()> {
+        let server = Server::build()
+            .register_error_handler(handler::default_error_404_handler)?
+            .get("/slow", |_req| {
+                std::thread::sleep(Duration::from_millis(200));
+                Ok(response::Response::Ok(
+                    b"done".to_vec(),
+                    response::ContentType::PLAIN,
+                ))
+            })?
+            .finalize(("127.0.0.1", 0), 2)?;
+        let addr = server.local_addr()?;
+        let handle = server.shutdown_handle();
+        let server_thread = std::thread::spawn(move || server.run());
+
+        // start a slow request, then shut down while it is in flight
+        let mut client = TcpStream::connect(addr)?;
+        client.write_all(b"GET /slow HTTP/1.1\r\n\r\n")?;
+        std::thread::sleep(Duration::from_millis(50));
+        handle.shutdown();
+
+        let mut response = String::new();
+        client.read_to_string(&mut response)?;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("Connection: close\r\n"));
+        assert!(response.ends_with("done"));
+
+        server_thread.join().unwrap()?;
+        assert!(TcpStream::connect(addr).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_overloaded_response() -> Result<()> {
+        let mut written = vec![];
+        write_overloaded_response(&mut written, Duration::from_millis(1500))?;
+        let written = String::from_utf8(written)?;
+        assert!(written.starts_with("HTTP/1.1 503 Service Unavailable\r\n"));
+        assert!(written.contains("Retry-After: 2\r\n"));
+        assert!(written.contains("Connection: close\r\n"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_queue_capacity_validation() -> Result<()> {
+        assert!(Server::build().max_queued_connections(0).is_err());
+        assert!(Server::build().max_queued_connections(1).is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn test_worker_config_validation() -> Result<()> {
+        assert!(Server::build().max_workers(0).is_err());
+        assert!(Server::build().worker_idle_timeout(Duration::ZERO).is_err());
+        assert!(Server::build().worker_stack_size(0).is_err());
+        assert!(Server::build().job_workers(0).is_err());
+
+        let below_pool_size = Server::build()
+            .register_error_handler(handler::default_error_404_handler)?
+            .max_workers(2)?
+            .finalize(("127.0.0.1", 0), 4);
+        assert!(below_pool_size.is_err());
+
+        let server = Server::build()
+            .register_error_handler(handler::default_error_404_handler)?
+            .max_workers(8)?
+            .worker_stack_size(512 * 1024)?
+            .job_workers(3)?
+            .finalize(("127.0.0.1", 0), 2)?;
+        assert_eq!(server.queue_monitor().workers(), 2);
+        assert_eq!(server.queue_monitor().job_stats().workers, 3);
+        Ok(())
+    }
+
+    #[test]
+    fn test_rejects_connections_when_queue_full() -> Result<()> {
+        use std::sync::mpsc;
+        use std::sync::Mutex;
+
+        let (started, wait_started) = mpsc::channel();
+        let (release, released) = mpsc::channel::<()>();
+        let (started, released) = (Mutex::new(started), Mutex::new(released));
+        let server = Server::build()
+            .register_error_handler(handler::default_error_404_handler)?
+            .get("/slow", move |_req| {
+                started.lock().unwrap().send(()).unwrap();
+                _ = released.lock().unwrap().recv();
+                Ok(response::Response::Ok(
+                    b"done".to_vec(),
+                    response::ContentType::PLAIN,
+                ))
+            })?
+            .max_queued_connections(1)?
+            .overload_policy(OverloadPolicy::Reject {
+                retry_after: Duration::from_secs(3),
+            })
+            .finalize(("127.0.0.1", 0), 1)?;
+        let addr = server.local_addr()?;
+        let handle = server.shutdown_handle();
+        let monitor = server.queue_monitor();
+        assert_eq!(monitor.capacity(), 1);
+        let server_thread = std::thread::spawn(move || server.run());
+
+        // occupy the only worker, then fill the queue
+        let mut busy = TcpStream::connect(addr)?;
+        busy.write_all(b"GET /slow HTTP/1.1\r\nConnection: close\r\n\r\n")?;
+        wait_started.recv()?;
+        let mut queued = TcpStream::connect(addr)?;
+        queued.write_all(b"GET /slow HTTP/1.1\r\nConnection: close\r\n\r\n")?;
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while monitor.depth() < 1 && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        assert_eq!(monitor.depth(), 1);
+
+        let mut rejected = TcpStream::connect(addr)?;
+        rejected.write_all(b"GET /slow HTTP/1.1\r\n\r\n")?;
+        let mut response = String::new();
+        rejected.read_to_string(&mut response)?;
+        assert!(response.starts_with("HTTP/1.1 503 Service Unavailable\r\n"));
+        assert!(response.contains("Retry-After: 3\r\n"));
+        assert_eq!(monitor.rejected(), 1);
+
+        // the accepted connections are still served
+        release.send(())?;
+        release.send(())?;
+        for mut client in [busy, queued] {
+            let mut response = String::new();
+            client.read_to_string(&mut response)?;
+            assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        }
+        assert_eq!(monitor.depth(), 0);
+
+        handle.shutdown();
+        server_thread.join().unwrap()?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_handlers_use_the_job_pool() -> Result<()> {
+        let server = Server::build()
+            .register_error_handler(handler::default_error_404_handler)?
+            .get("/sum", |req: request::Request| {
+                let pool = req.state::<threadpool::PoolHandle>().unwrap();
+                let numbers: Vec<u64> = (1..=100).collect();
+                let sum = pool.scope(|scope| {
+                    let halves: Vec<_> = numbers
+                        .chunks(50)
+                        .map(|chunk| scope.spawn(move || chunk.iter().sum::<u64>()))
+                        .collect();
+                    halves
+                        .into_iter()
+                        .map(|half| half.join().unwrap())
+                        .sum::<u64>()
+                });
+                Ok(response::Response::Ok(
+                    sum.to_string().into_bytes(),
+                    response::ContentType::PLAIN,
+                ))
+            })?
+            .job_workers(2)?
+            .max_queued_connections(1)?
+            .overload_policy(OverloadPolicy::Reject {
+                retry_after: Duration::from_secs(1),
+            })
+            // the only connection worker waits for the jobs, which run on
+            // the job pool instead of queueing behind connections
+            .finalize(("127.0.0.1", 0), 1)?;
+        let addr = server.local_addr()?;
+        let handle = server.shutdown_handle();
+        let monitor = server.queue_monitor();
+        let server_thread = std::thread::spawn(move || server.run());
+
+        let mut client = TcpStream::connect(addr)?;
+        client.write_all(b"GET /sum HTTP/1.1\r\nConnection: close\r\n\r\n")?;
+        let mut response = String::new();
+        client.read_to_string(&mut response)?;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with("\r\n\r\n5050"));
+
+        handle.shutdown();
+        server_thread.join().unwrap()?;
+        assert_eq!(monitor.stats().completed, 1);
+        assert_eq!(monitor.job_stats().completed, 2);
+        assert_eq!(monitor.job_stats().panicked, 0);
+        Ok(())
+    }
+
+    fn tag(tag: &'static str) -> impl middleware::Middleware + Send + Sync + 'static {
+        move |req, next: middleware::Next<'_>| {
+            let mut response: response::Response = next.run(req)?;
+            response.headers.insert("X-Tag", tag);
+            Ok(response)
+        }
+    }
+
+    #[test]
+    fn test_global_and_route_middleware() -> Result<()> {
+        let handlers = Server::build()
+            .register_error_handler(handler::default_error_404_handler)?
+            .middleware(tag("global"))
+            .get("/a", text_handler("a"))?
+            .get("/b", text_handler("b"))?
+            .route_middleware("/b", tag("route"))?
+            .build_handlers()?;
+
+        let tags = |path: &str| -> Result<Vec<String>> {
+            let req = request::Request::new(methods::Method::GET, path.into());
+            let response = handlers.handle(req)?;
+            Ok(response
+                .headers
+                .get_all("X-Tag")
+                .map(str::to_owned)
+                .collect())
         };
 
-        assert_eq!(res, expected);
+        assert_eq!(tags("/a")?, vec!["global"]);
+        assert_eq!(tags("/b")?, vec!["route", "global"]);
+        // global middleware also wraps 404s
+        assert_eq!(tags("/missing")?, vec!["global"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_route_middleware_requires_handlers() -> Result<()> {
+        assert!(Server::build()
+            .get("/b", text_handler("b"))?
+            .route_middleware("/typo", tag("route"))
+            .is_err());
+        assert!(Server::build()
+            .route_middleware("/b", tag("route"))
+            .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_middleware_short_circuits() -> Result<()> {
+        let handlers = Server::build()
+            .register_error_handler(handler::default_error_404_handler)?
+            .get("/private", text_handler("secret"))?
+            .route_middleware(
+                "/private",
+                |req: request::Request, next: middleware::Next<'_>| {
+                    if req.headers.authorization() != Some("Bearer token") {
+                        return Ok(response::Response::new(status::StatusCode::UNAUTHORIZED));
+                    }
+                    next.run(req)
+                },
+            )?
+            .build_handlers()?;
+
+        let req = request::Request::new(methods::Method::GET, "/private".into());
+        assert_eq!(
+            handlers.handle(req)?.status,
+            status::StatusCode::UNAUTHORIZED
+        );
+
+        let mut req = request::Request::new(methods::Method::GET, "/private".into());
+        req.headers.insert("Authorization", "Bearer token");
+        assert_eq!(body_of(handlers.handle(req)?), b"secret");
+        Ok(())
+    }
+
+    #[test]
+    fn test_conditional_put() -> Result<()> {
+        use crate::conditional::{self, ETag};
+        use std::sync::Mutex;
+
+        // a document updated with If-Match to prevent lost updates
+        let document = Mutex::new(String::from("v1"));
+        let handlers = Server::build()
+            .register_error_handler(handler::default_error_404_handler)?
+            .put("/doc", move |req: request::Request| {
+                let mut document = document.lock().unwrap();
+                let etag = ETag::strong(document.as_str())?;
+                if let Some(status) =
+                    conditional::evaluate(&req.method, &req.headers, Some(&etag), None)
+                {
+                    return Ok(response::Response::new(status));
+                }
+                *document = req.body.unwrap_or_default();
+                response::Response::build()
+                    .etag(&ETag::strong(document.as_str())?)
+                    .finalize()
+            })?
+            .build_handlers()?;
+
+        let put = |if_match: &str, body: &str| {
+            let mut req = request::Request::new(methods::Method::PUT, "/doc".into());
+            req.headers.insert("If-Match", if_match);
+            req.body = Some(body.into());
+            handlers.respond(req)
+        };
+
+        // a stale tag doesn't reach the update
+        let response = put("\"v0\"", "lost");
+        assert_eq!(response.status, status::StatusCode::PRECONDITION_FAILED);
+
+        // the response's new ETag doesn't match the request's If-Match, yet
+        // the committed update isn't reported as failed
+        let response = put("\"v1\"", "v2");
+        assert_eq!(response.status, status::StatusCode::OK);
+        assert_eq!(response.headers.get("ETag"), Some("\"v2\""));
+
+        let response = put("\"v1\"", "lost");
+        assert_eq!(response.status, status::StatusCode::PRECONDITION_FAILED);
+        Ok(())
+    }
+
+    #[test]
+    fn test_static_files() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("crag-web-mount-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        std::fs::write(dir.join("app.js"), "run()")?;
+
+        let handlers = Server::build()
+            .register_error_handler(text_handler("custom 404"))?
+            .static_files("/assets/", static_files::StaticFiles::new(&dir)?)?
+            .build_handlers()?;
+
+        let req = request::Request::new(methods::Method::GET, "/assets/app.js".into());
+        let response = handlers.handle(req)?;
+        assert_eq!(
+            response.headers.content_type(),
+            Some("application/javascript")
+        );
+        assert_eq!(response.body.len(), Some(5));
+
+        // validators are added to files and checked automatically
+        let etag = response.etag().unwrap();
+        let mut req = request::Request::new(methods::Method::GET, "/assets/app.js".into());
+        req.headers.insert("If-None-Match", etag.to_string());
+        let response = handlers.respond(req);
+        assert_eq!(response.status, status::StatusCode::NOT_MODIFIED);
+        assert_eq!(response.etag(), Some(etag));
+
+        // and so are ranges
+        let mut req = request::Request::new(methods::Method::GET, "/assets/app.js".into());
+        req.headers.insert("Range", "bytes=1-3");
+        let response = handlers.respond(req);
+        assert_eq!(response.status, status::StatusCode::PARTIAL_CONTENT);
+        assert_eq!(response.headers.get("Content-Range"), Some("bytes 1-3/5"));
+        assert_eq!(response.body.into_bytes()?, b"un(");
+
+        // missing files go to the application's error handler
+        let req = request::Request::new(methods::Method::GET, "/assets/missing.js".into());
+        assert_eq!(body_of(handlers.handle(req)?), b"custom 404");
+        let req = request::Request::parse("GET /assets/a%00.js HTTP/1.1")?;
+        assert_eq!(body_of(handlers.handle(req)?), b"custom 404");
+
+        let req = request::Request::new(methods::Method::POST, "/assets/app.js".into());
+        assert_eq!(
+            handlers.handle(req)?.status,
+            status::StatusCode::METHOD_NOT_ALLOWED
+        );
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    fn test_compression() -> Result<()> {
+        use std::io::Read;
+
+        let handlers = Server::build()
+            .register_error_handler(handler::default_error_404_handler)?
+            .compression(crate::compression::Compression::new().min_size(16))
+            .get("/report", |_req| {
+                response::Response::build()
+                    .header("Content-Type", "text/plain")
+                    .body("row\n".repeat(100))
+                    .finalize()
+            })?
+            .build_handlers()?;
+
+        let mut req = request::Request::new(methods::Method::GET, "/report".into());
+        req.headers.insert("Accept-Encoding", "gzip");
+        let response = handlers.respond(req);
+        assert_eq!(response.headers.get("Content-Encoding"), Some("gzip"));
+
+        let mut decoded = String::new();
+        flate2::read::GzDecoder::new(&response.body.into_bytes()?[..])
+            .read_to_string(&mut decoded)?;
+        assert_eq!(decoded, "row\n".repeat(100));
+        Ok(())
+    }
+
+    #[test]
+    fn test_shared_state() -> Result<()> {
+        use std::sync::atomic::AtomicUsize;
+
+        struct Greeting(&'static str);
+
+        let handlers = Server::build()
+            .register_error_handler(handler::default_error_404_handler)?
+            .with_state(Greeting("hello"))?
+            .with_state(AtomicUsize::new(0))?
+            .get("/greet", |req: request::Request| {
+                let hits = req.state::<AtomicUsize>().unwrap();
+                let greeting = req.state::<Greeting>().unwrap();
+                let body = format!("{} {}", greeting.0, hits.fetch_add(1, Ordering::SeqCst));
+                Ok(response::Response::Ok(
+                    body.into_bytes(),
+                    response::ContentType::PLAIN,
+                ))
+            })?
+            .build_handlers()?;
+
+        for expected in ["hello 0", "hello 1"] {
+            let req = request::Request::new(methods::Method::GET, "/greet".into());
+            assert_eq!(body_of(handlers.handle(req)?), expected.as_bytes());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_duplicate_state() {
+        let result = Server::build()
+            .with_state(1_u32)
+            .and_then(|builder| builder.with_state(2_u32));
+        assert!(result
+            .err()
+            .unwrap()
+            .to_string()
+            .contains("State of type u32 already registered"));
+    }
+
+    #[test]
+    fn test_pool_handle_state_is_reserved() -> Result<()> {
+        let other_pool = threadpool::ThreadPool::build(1)?;
+        let result = Server::build()
+            .register_error_handler(handler::default_error_404_handler)?
+            .with_state(other_pool.handle())?
+            .finalize(("127.0.0.1", 0), 1);
+        assert!(result
+            .err()
+            .unwrap()
+            .to_string()
+            .contains("reserved for the server's job pool"));
         Ok(())
     }
 
-    // #[test]
+    /// In-memory stream that reads from a fixed request and records writes
+    struct TestStream {
+        input: std::io::Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl TestStream {
+        fn new(input: &str) -> Self {
+            TestStream {
+                input: std::io::Cursor::new(input.as_bytes().to_vec()),
+                output: vec![],
+            }
+        }
+    }
+
+    impl Transport for TestStream {
+        fn set_read_timeout(&self, _timeout: Duration) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Read for TestStream {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for TestStream {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
 }
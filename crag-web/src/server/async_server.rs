@@ -0,0 +1,668 @@
+use anyhow::Result;
+use std::future::Future;
+use std::io;
+use std::net::{SocketAddr, TcpListener};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::io::{
+    AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader, BufWriter,
+    ReadBuf,
+};
+use tokio::net::TcpStream;
+use tokio::task::{JoinError, JoinSet};
+use tokio::time::Sleep;
+use tracing::{error, warn};
+
+use super::{
+    body_error, error_response, handler_panicked, is_idle_timeout, ConnectionConfig, Endpoint,
+    Handlers, Parsed, RequestLimits, RequestParser, ShutdownHandle, ACCEPT_ERROR_DELAY,
+};
+use crate::body::Body;
+use crate::error::{self, Error};
+use crate::{handler, methods, request, response};
+
+/// Server running the routes of a `ServerBuilder` on tokio, created by
+/// `ServerBuilder::finalize_async`.
+///
+/// Every connection is served by a task rather than a worker thread, so
+/// slow clients only hold a little memory while they send their request or
+/// read the response. Requests to an async handler with no middleware in
+/// between run on the runtime. Blocking handlers and middleware, which may
+/// block, run on tokio's blocking threads, where async handlers reached
+/// through middleware are driven to completion.
+pub struct AsyncServer {
+    tcp_listener: TcpListener,
+    handlers: Arc<Handlers>,
+    connection: ConnectionConfig,
+    shutdown: ShutdownHandle,
+    shutdown_timeout: Duration,
+}
+
+impl AsyncServer {
+    pub(super) fn new(
+        tcp_listener: TcpListener,
+        handlers: Handlers,
+        connection: ConnectionConfig,
+        shutdown: ShutdownHandle,
+        shutdown_timeout: Duration,
+    ) -> Self {
+        AsyncServer {
+            tcp_listener,
+            handlers: Arc::new(handlers),
+            connection,
+            shutdown,
+            shutdown_timeout,
+        }
+    }
+
+    /// Handle that stops `run` when `shutdown` is called on it
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.tcp_listener.local_addr()?)
+    }
+
+    /// Accept and serve connections until a shutdown is requested through a
+    /// `ShutdownHandle`, then wait for in-flight requests to finish. Must
+    /// be awaited on a tokio runtime.
+    ///
+    /// Idle keep-alive connections are closed by their idle timeout, so the
+    /// shutdown timeout should be longer than the keep-alive timeout.
+    pub async fn run(self) -> Result<()> {
+        self.tcp_listener.set_nonblocking(true)?;
+        let listener = tokio::net::TcpListener::from_std(self.tcp_listener)?;
+        let mut connections = JoinSet::new();
+
+        loop {
+            let stream = match listener.accept().await {
+                Ok((stream, _)) => stream,
+                Err(err) => {
+                    // errors like running out of file descriptors or a client
+                    // aborting before it was accepted pass, and returning
+                    // would abort every connection in flight
+                    warn!("Error accepting connection: {:?}", err);
+                    if self.shutdown.is_shutdown() {
+                        break;
+                    }
+                    tokio::time::sleep(ACCEPT_ERROR_DELAY).await;
+                    continue;
+                }
+            };
+            if self.shutdown.is_shutdown() {
+                break;
+            }
+            // forget the connections that were closed since
+            while connections.try_join_next().is_some() {}
+
+            let handlers = Arc::clone(&self.handlers);
+            let connection = self.connection;
+            let shutdown = self.shutdown.clone();
+
+            // error boundary, any response for the error was already sent
+            connections.spawn(async move {
+                if let Err(e) = handle_connection(handlers, connection, &shutdown, stream).await {
+                    error!("Error handling connection: {:?}", e);
+                }
+            });
+        }
+
+        let drained = tokio::time::timeout(self.shutdown_timeout, async {
+            while connections.join_next().await.is_some() {}
+        })
+        .await;
+        if drained.is_err() {
+            warn!("Shutdown timeout elapsed with requests still in flight");
+            // like the workers of the blocking server, let them finish
+            connections.detach_all();
+        }
+        Ok(())
+    }
+}
+
+impl Handlers {
+    /// Handle a request like `respond`, awaiting an async handler on the
+    /// runtime if no middleware would run before it and running everything
+    /// else on a blocking thread
+    async fn respond_async(self: Arc<Self>, mut req: request::Request) -> response::Response {
+        let head = req.head();
+        if let Some(handler) = self.async_endpoint(&mut req) {
+            // a task turns a panicking handler into an error instead of
+            // unwinding through the connection
+            let result = task_result(&head, tokio::spawn(handler.handle(req)).await);
+            let result = match result.map_err(|err| err.downcast::<handler::NotFound>()) {
+                Ok(response) => Ok(response),
+                // the error handler is a blocking handler
+                Err(Ok(handler::NotFound(req))) => {
+                    let handlers = Arc::clone(&self);
+                    let joined =
+                        tokio::task::spawn_blocking(move || handlers.handle_error(req)).await;
+                    task_result(&head, joined)
+                }
+                Err(Err(err)) => Err(err),
+            };
+            return self.finish(&head, result);
+        }
+
+        let handlers = Arc::clone(&self);
+        match tokio::task::spawn_blocking(move || handlers.respond(req)).await {
+            Ok(response) => response,
+            // `respond` catches panics, so the runtime is shutting down
+            Err(err) => self.finish(&head, Err(err.into())),
+        }
+    }
+
+    /// Async handler the request is dispatched to without passing through
+    /// any middleware, with the request prepared as `dispatch` does
+    fn async_endpoint(&self, req: &mut request::Request) -> Option<&handler::BoxedAsyncHandler> {
+        if !self.middleware.is_empty() {
+            return None;
+        }
+        let (route_handlers, params) = self.valid_handlers.lookup(&req.route.route)?;
+        match route_handlers.get(&req.method) {
+            Some(Endpoint::Async(handler)) if route_handlers.middleware.is_empty() => {
+                req.state = self.state.clone();
+                req.params = params;
+                Some(handler)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Result of a task running a handler, with a panic turned into an error
+fn task_result(
+    head: &request::RequestHead,
+    joined: std::result::Result<Result<response::Response>, JoinError>,
+) -> Result<response::Response> {
+    match joined {
+        Ok(result) => result,
+        Err(err) => match err.try_into_panic() {
+            Ok(panic) => Err(handler_panicked(head, &*panic)),
+            Err(err) => Err(err.into()),
+        },
+    }
+}
+
+/// Serve requests on a connection until the client or the server decides
+/// to close it, like the blocking `handle_connection`
+async fn handle_connection(
+    handlers: Arc<Handlers>,
+    connection: ConnectionConfig,
+    shutdown: &ShutdownHandle,
+    stream: TcpStream,
+) -> error::Result<()> {
+    // the reader is kept across requests so pipelined bytes are not lost
+    let mut reader = BufReader::new(Timed::new(
+        stream,
+        connection.idle_timeout,
+        connection.write_timeout,
+    ));
+
+    for served in 1..=connection.max_requests {
+        // wait for the next request under the idle timeout, then bound each
+        // read by the read timeout while the rest of it arrives
+        reader.get_mut().set_read_timeout(connection.idle_timeout);
+        match reader.fill_buf().await {
+            // client closed the connection
+            Ok([]) => break,
+            Ok(_) => {}
+            Err(err) if is_idle_timeout(&err) => break,
+            Err(err) => return Err(err.into()),
+        }
+        reader.get_mut().set_read_timeout(connection.read_timeout);
+
+        let req = match read_and_parse_request(&mut reader, &connection.limits).await {
+            Ok(Some(req)) => req,
+            // client closed the connection or it timed out while idle
+            Ok(None) => break,
+            // the connection is broken, there is no one to answer
+            Err(err @ Error::Io(_)) => return Err(err),
+            Err(err) => {
+                reader.get_mut().write_all(&error_response(&err)).await?;
+                return Err(err);
+            }
+        };
+        let is_head = req.method == methods::Method::HEAD;
+        let client_close = req.headers.contains_token("Connection", "close");
+
+        let mut response = Arc::clone(&handlers).respond_async(req).await;
+
+        let keep_alive = connection.keep_alive_after(
+            &mut response,
+            served,
+            client_close,
+            shutdown.is_shutdown(),
+        );
+
+        write_response(reader.get_mut(), response, is_head).await?;
+
+        if !keep_alive {
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+/// Write a response, pulling the chunks of streamed bodies on a blocking
+/// thread since readers, files and chunk iterators may block
+async fn write_response(
+    stream: &mut (impl AsyncWrite + Unpin),
+    response: response::Response,
+    is_head: bool,
+) -> error::Result<()> {
+    // buffered so the head and small chunks don't each become a separate
+    // packet
+    let mut writer = BufWriter::new(stream);
+    writer.write_all(&response.head_bytes()).await?;
+
+    // HEAD responses carry the GET headers but never a body
+    if !is_head && !response.status.forbids_body() {
+        match response.body {
+            Body::Bytes(bytes) => writer.write_all(&bytes).await?,
+            body => {
+                let mut frames = body.into_frames();
+                loop {
+                    let (rest, frame) = tokio::task::spawn_blocking(move || {
+                        let frame = frames.next();
+                        (frames, frame)
+                    })
+                    .await
+                    .map_err(|err| Error::Handler(err.into()))?;
+                    frames = rest;
+                    let Some(frame) = frame else {
+                        break;
+                    };
+                    // the head may already be sent, so a failing body can
+                    // only be reported by closing the connection
+                    writer.write_all(&frame.map_err(body_error)?).await?;
+                }
+            }
+        }
+    }
+    writer.flush().await?;
+    Ok(())
+}
+
+/// Read the next request from the connection, like the blocking
+/// `read_and_parse_request`. Returns `None` if the connection is closed
+/// before a request starts.
+async fn read_and_parse_request(
+    reader: &mut (impl AsyncBufRead + Unpin),
+    limits: &RequestLimits,
+) -> error::Result<Option<request::Request>> {
+    let deadline = Instant::now() + limits.header_timeout;
+    let mut parser = RequestParser::new(limits);
+    loop {
+        let input = if parser.in_head() {
+            // reads still pending at the deadline are abandoned, so a
+            // client trickling bytes gets a timeout right then
+            tokio::time::timeout_at(deadline.into(), reader.fill_buf())
+                .await
+                .map_err(|_| Error::Timeout)??
+        } else {
+            reader.fill_buf().await?
+        };
+        match parser.parse(input)? {
+            Parsed::Partial(used) => reader.consume(used),
+            Parsed::Complete(used, req) => {
+                reader.consume(used);
+                return Ok(req);
+            }
+        }
+    }
+}
+
+/// Stream whose reads and writes fail with `TimedOut` once a single one
+/// has been pending longer than its timeout, like a socket with read and
+/// write timeouts
+struct Timed<S> {
+    stream: S,
+    read: Timer,
+    write: Timer,
+}
+
+impl<S> Timed<S> {
+    fn new(stream: S, read_timeout: Duration, write_timeout: Duration) -> Self {
+        Timed {
+            stream,
+            read: Timer::new(read_timeout),
+            write: Timer::new(write_timeout),
+        }
+    }
+
+    fn set_read_timeout(&mut self, timeout: Duration) {
+        self.read.timeout = timeout;
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Timed<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        let poll = Pin::new(&mut this.stream).poll_read(cx, buf);
+        this.read.poll(cx, poll)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Timed<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        let poll = Pin::new(&mut this.stream).poll_write(cx, buf);
+        this.write.poll(cx, poll)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        let poll = Pin::new(&mut this.stream).poll_flush(cx);
+        this.write.poll(cx, poll)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        let poll = Pin::new(&mut this.stream).poll_shutdown(cx);
+        this.write.poll(cx, poll)
+    }
+}
+
+/// Timeout of the read or write a `Timed` stream has pending
+struct Timer {
+    timeout: Duration,
+    sleep: Pin<Box<Sleep>>,
+    // whether `sleep` was started for the operation pending now
+    armed: bool,
+}
+
+impl Timer {
+    fn new(timeout: Duration) -> Self {
+        Timer {
+            timeout,
+            sleep: Box::pin(tokio::time::sleep(timeout)),
+            armed: false,
+        }
+    }
+
+    /// Pass on the poll of an operation, starting the timer when it first
+    /// returns pending and failing it once the timer elapsed
+    fn poll<T>(&mut self, cx: &mut Context<'_>, poll: Poll<io::Result<T>>) -> Poll<io::Result<T>> {
+        if poll.is_ready() {
+            self.armed = false;
+            return poll;
+        }
+        if !self.armed {
+            self.sleep
+                .as_mut()
+                .reset(tokio::time::Instant::now() + self.timeout);
+            self.armed = true;
+        }
+        match self.sleep.as_mut().poll(cx) {
+            Poll::Ready(()) => {
+                self.armed = false;
+                Poll::Ready(Err(io::ErrorKind::TimedOut.into()))
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::middleware;
+    use crate::server::{Server, ServerBuilder};
+    use tokio::io::AsyncReadExt;
+    use tokio::task::JoinHandle;
+
+    fn text(body: impl Into<String>) -> response::Response {
+        response::Response::Ok(body.into().into_bytes(), response::ContentType::PLAIN)
+    }
+
+    async fn greet(req: request::Request) -> Result<response::Response> {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        Ok(text(format!(
+            "hello {}",
+            req.param("name").unwrap_or_default()
+        )))
+    }
+
+    async fn echo(req: request::Request) -> Result<response::Response> {
+        Ok(text(req.body.unwrap_or_default()))
+    }
+
+    async fn sleepy(_req: request::Request) -> Result<response::Response> {
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        Ok(text("awake"))
+    }
+
+    fn builder() -> Result<ServerBuilder> {
+        Server::build().register_error_handler(crate::handler::default_error_404_handler)
+    }
+
+    /// Serve on a free port, returning the address and a handle stopping
+    /// the server
+    fn serve(
+        builder: ServerBuilder,
+    ) -> Result<(SocketAddr, ShutdownHandle, JoinHandle<Result<()>>)> {
+        let server = builder.finalize_async(("127.0.0.1", 0))?;
+        let addr = server.local_addr()?;
+        let handle = server.shutdown_handle();
+        Ok((addr, handle, tokio::spawn(server.run())))
+    }
+
+    async fn stop(handle: ShutdownHandle, server: JoinHandle<Result<()>>) -> Result<()> {
+        tokio::task::spawn_blocking(move || handle.shutdown()).await?;
+        server.await?
+    }
+
+    /// Send raw requests and read until the server closes the connection
+    async fn send(addr: SocketAddr, requests: &str) -> Result<String> {
+        let mut stream = TcpStream::connect(addr).await?;
+        stream.write_all(requests.as_bytes()).await?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await?;
+        Ok(response)
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_async_and_blocking_handlers() -> Result<()> {
+        let builder = builder()?
+            .get_async("/greet/:name", greet)?
+            .post_async("/echo", echo)?
+            .get("/blocking", |_req| Ok(text("blocking")))?
+            .get_async("/gone", |req| async move {
+                Err(anyhow::Error::new(handler::NotFound(req)))
+            })?;
+        let (addr, handle, server) = serve(builder)?;
+
+        let response = send(
+            addr,
+            "GET /greet/crag HTTP/1.1\r\nConnection: close\r\n\r\n",
+        )
+        .await?;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with("\r\n\r\nhello crag"));
+
+        let response = send(addr, "GET /blocking HTTP/1.1\r\nConnection: close\r\n\r\n").await?;
+        assert!(response.ends_with("\r\n\r\nblocking"));
+
+        let response = send(addr, "GET /missing HTTP/1.1\r\nConnection: close\r\n\r\n").await?;
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+
+        // an async handler can hand its request to the 404 handler
+        let response = send(addr, "GET /gone HTTP/1.1\r\nConnection: close\r\n\r\n").await?;
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+
+        let response = send(addr, "DELETE /echo HTTP/1.1\r\nConnection: close\r\n\r\n").await?;
+        assert!(response.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"));
+
+        // HEAD falls back to the async GET handler without sending a body
+        let response = send(addr, "HEAD /greet/x HTTP/1.1\r\nConnection: close\r\n\r\n").await?;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with("\r\n\r\n"));
+
+        stop(handle, server).await
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_keep_alive_and_request_bodies() -> Result<()> {
+        let builder = builder()?.post_async("/echo", echo)?;
+        let (addr, handle, server) = serve(builder)?;
+
+        // pipelined on one connection, the last one closing it
+        let response = send(
+            addr,
+            "POST /echo HTTP/1.1\r\nContent-Length: 5\r\n\r\nfirst\
+             POST /echo HTTP/1.1\r\nTransfer-Encoding: chunked\r\nConnection: close\r\n\r\n\
+             3\r\nsec\r\n3\r\nond\r\n0\r\n\r\n",
+        )
+        .await?;
+        assert_eq!(response.matches("HTTP/1.1 200 OK\r\n").count(), 2);
+        assert!(response.contains("\r\n\r\nfirstHTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with("\r\n\r\nsecond"));
+
+        let response = send(addr, "GET / HTTP/1.1\r\nContent-Length: x\r\n\r\n").await?;
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+
+        stop(handle, server).await
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_streamed_response_bodies() -> Result<()> {
+        let builder = builder()?
+            .get("/chunks", |_req| {
+                response::Response::build()
+                    .body(crate::body::Body::from_chunks(
+                        ["Hello, ", "chunked ", "world"].map(Ok),
+                    ))
+                    .finalize()
+            })?
+            .get("/reader", |_req| {
+                response::Response::build()
+                    .body(crate::body::Body::from_reader(&b"from a reader"[..], 13))
+                    .finalize()
+            })?;
+        let (addr, handle, server) = serve(builder)?;
+
+        let response = send(addr, "GET /chunks HTTP/1.1\r\nConnection: close\r\n\r\n").await?;
+        assert!(response.contains("Transfer-Encoding: chunked\r\n"));
+        assert!(
+            response.ends_with("\r\n\r\n7\r\nHello, \r\n8\r\nchunked \r\n5\r\nworld\r\n0\r\n\r\n")
+        );
+
+        let response = send(addr, "GET /reader HTTP/1.1\r\nConnection: close\r\n\r\n").await?;
+        assert!(response.contains("Content-Length: 13\r\n"));
+        assert!(response.ends_with("\r\n\r\nfrom a reader"));
+
+        stop(handle, server).await
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_async_handler_behind_middleware() -> Result<()> {
+        let builder = builder()?
+            .middleware(|req, next: middleware::Next<'_>| {
+                let mut response: response::Response = next.run(req)?;
+                response.headers.insert("X-Tag", "global");
+                Ok(response)
+            })
+            .get_async("/greet/:name", greet)?;
+        let (addr, handle, server) = serve(builder)?;
+
+        let response = send(
+            addr,
+            "GET /greet/crag HTTP/1.1\r\nConnection: close\r\n\r\n",
+        )
+        .await?;
+        assert!(response.contains("X-Tag: global\r\n"));
+        assert!(response.ends_with("\r\n\r\nhello crag"));
+
+        stop(handle, server).await
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_async_handler_panic() -> Result<()> {
+        async fn panics(_req: request::Request) -> Result<response::Response> {
+            panic!("handler failed")
+        }
+        let builder = builder()?.get_async("/panic", panics)?;
+        let (addr, handle, server) = serve(builder)?;
+
+        let response = send(addr, "GET /panic HTTP/1.1\r\nConnection: close\r\n\r\n").await?;
+        assert!(response.starts_with("HTTP/1.1 500 Internal Server Error\r\n"));
+
+        stop(handle, server).await
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_slow_clients_dont_hold_up_others() -> Result<()> {
+        let builder = builder()?.get_async("/sleep", sleepy)?;
+        let (addr, handle, server) = serve(builder)?;
+
+        // clients that never finish their request
+        let mut idle = vec![];
+        for _ in 0..64 {
+            let mut stream = TcpStream::connect(addr).await?;
+            stream.write_all(b"GET /sleep HTTP/1.1\r\n").await?;
+            idle.push(stream);
+        }
+
+        let start = Instant::now();
+        let requests: Vec<_> = (0..64)
+            .map(|_| {
+                tokio::spawn(send(
+                    addr,
+                    "GET /sleep HTTP/1.1\r\nConnection: close\r\n\r\n",
+                ))
+            })
+            .collect();
+        for request in requests {
+            assert!(request.await??.ends_with("\r\n\r\nawake"));
+        }
+        // served one after the other they would take 12.8s
+        assert!(start.elapsed() < Duration::from_secs(3));
+
+        drop(idle);
+        stop(handle, server).await
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_header_timeout() -> Result<()> {
+        let builder = builder()?.header_timeout(Duration::from_millis(100))?;
+        let (addr, handle, server) = serve(builder)?;
+
+        let response = send(addr, "GET / HTTP/1.1\r\n").await?;
+        assert!(response.starts_with("HTTP/1.1 408 Request Timeout\r\n"));
+
+        stop(handle, server).await
+    }
+
+    #[test]
+    fn test_blocking_server_rejects_async_handlers() -> Result<()> {
+        let result = builder()?
+            .get_async("/greet/:name", greet)?
+            .finalize(("127.0.0.1", 0), 1);
+        assert!(result
+            .err()
+            .unwrap()
+            .to_string()
+            .contains("only served by finalize_async"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_duplicate_async_handler_fails() -> Result<()> {
+        let builder = builder()?.get("/greet/:name", |_req| Ok(text("blocking")))?;
+        assert!(builder.get_async("/greet/:name", greet).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,204 @@
+use std::io;
+use std::mem;
+
+use super::{parse_request, BodyFraming, RequestLimits};
+use crate::chunked;
+use crate::error::{self, Error};
+use crate::request::Request;
+
+/// Parser of one request that is fed the bytes read from the connection
+/// instead of reading them itself, so the blocking and async servers share
+/// the request limits and body framing.
+pub(super) struct RequestParser<'a> {
+    limits: &'a RequestLimits,
+    line: chunked::LineBuffer,
+    state: State,
+}
+
+enum State {
+    Head(HeadLines),
+    Body {
+        req: Request,
+        body: Vec<u8>,
+        remaining: usize,
+    },
+    Chunked {
+        req: Request,
+        decoder: chunked::Decoder,
+    },
+    // the request was returned
+    Done,
+}
+
+/// Progress of a `RequestParser` after it was fed
+pub(super) enum Parsed {
+    /// Bytes used, more are needed to complete the request
+    Partial(usize),
+    /// Bytes used and the request, `None` if the stream ended before a
+    /// request started
+    Complete(usize, Option<Request>),
+}
+
+impl<'a> RequestParser<'a> {
+    pub(super) fn new(limits: &'a RequestLimits) -> Self {
+        RequestParser {
+            limits,
+            line: chunked::LineBuffer::default(),
+            state: State::Head(HeadLines::default()),
+        }
+    }
+
+    /// Whether the request line and headers are still being received,
+    /// which the header timeout bounds
+    pub(super) fn in_head(&self) -> bool {
+        matches!(self.state, State::Head(_))
+    }
+
+    /// Parse `input`, the bytes received and not used yet, which is empty
+    /// at the end of the stream. Bytes after the request are left unused
+    /// for the next one.
+    pub(super) fn parse(&mut self, input: &[u8]) -> error::Result<Parsed> {
+        match mem::replace(&mut self.state, State::Done) {
+            State::Head(head) => self.parse_head(head, input),
+            State::Body {
+                req,
+                mut body,
+                remaining,
+            } => {
+                if input.is_empty() {
+                    return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+                }
+                let used = remaining.min(input.len());
+                body.extend_from_slice(&input[..used]);
+                if used == remaining {
+                    return Ok(Parsed::Complete(used, Some(with_body(req, body)?)));
+                }
+                self.state = State::Body {
+                    req,
+                    body,
+                    remaining: remaining - used,
+                };
+                Ok(Parsed::Partial(used))
+            }
+            State::Chunked {
+                mut req,
+                mut decoder,
+            } => {
+                let (used, done) = decoder.decode(input)?;
+                if !done {
+                    self.state = State::Chunked { req, decoder };
+                    return Ok(Parsed::Partial(used));
+                }
+                let (body, trailers) = decoder.finish();
+                req.trailers = trailers;
+                Ok(Parsed::Complete(used, Some(with_body(req, body)?)))
+            }
+            State::Done => panic!("RequestParser fed after its request was complete"),
+        }
+    }
+
+    fn parse_head(&mut self, mut head: HeadLines, input: &[u8]) -> error::Result<Parsed> {
+        let (used, complete) = if input.is_empty() {
+            // the stream ended, so what was received is the whole head
+            if let Some(line) = self.line.take_partial() {
+                head.push(head_line(line)?, self.limits)?;
+            }
+            (0, true)
+        } else {
+            let (max_length, too_long) = head.line_limit(self.limits);
+            match self.line.take(input, max_length, too_long)? {
+                (used, Some(line)) => (used, head.push(head_line(line)?, self.limits)?),
+                (used, None) => (used, false),
+            }
+        };
+        if !complete {
+            self.state = State::Head(head);
+            return Ok(Parsed::Partial(used));
+        }
+        if head.lines.is_empty() {
+            return Ok(Parsed::Complete(used, None));
+        }
+
+        // Parse the request and how its body is framed
+        let (req, framing) = parse_request(head.lines)?;
+        self.state = match framing {
+            BodyFraming::Empty => return Ok(Parsed::Complete(used, Some(req))),
+            BodyFraming::Length(length) => {
+                if length > self.limits.max_body_size {
+                    return Err(Error::BodyTooLarge {
+                        limit: self.limits.max_body_size,
+                    });
+                }
+                // grow the body as bytes arrive instead of trusting the header
+                State::Body {
+                    req,
+                    body: vec![],
+                    remaining: length,
+                }
+            }
+            BodyFraming::Chunked => State::Chunked {
+                req,
+                // trailers are bound by the same limits as the headers
+                decoder: chunked::Decoder::new(
+                    self.limits.max_body_size,
+                    self.limits.max_headers,
+                    self.limits.max_header_size,
+                ),
+            },
+        };
+        Ok(Parsed::Partial(used))
+    }
+}
+
+fn head_line(line: Vec<u8>) -> error::Result<String> {
+    String::from_utf8(line).map_err(|_| Error::malformed("Request head is not valid UTF-8"))
+}
+
+/// The request with its body, which handlers receive as text
+fn with_body(mut req: Request, body: Vec<u8>) -> error::Result<Request> {
+    let body =
+        String::from_utf8(body).map_err(|_| Error::malformed("Request body is not valid UTF-8"))?;
+    req.body = Some(body);
+    Ok(req)
+}
+
+/// Request line and header lines of a request, collected one line at a
+/// time while enforcing the request limits
+#[derive(Default)]
+struct HeadLines {
+    lines: Vec<String>,
+    // size of the header lines so far, the request line excluded
+    header_size: usize,
+}
+
+impl HeadLines {
+    /// Longest next line accepted and the error for a longer one
+    fn line_limit(&self, limits: &RequestLimits) -> (usize, fn() -> Error) {
+        if self.lines.is_empty() {
+            (limits.max_request_line, || Error::UriTooLong)
+        } else {
+            // the blank line ending the head doesn't count, so it always fits
+            let remaining = limits.max_header_size - self.header_size;
+            (remaining.max(2), || Error::HeaderTooLarge)
+        }
+    }
+
+    /// Add the next line received. Returns whether the head is complete.
+    fn push(&mut self, line: String, limits: &RequestLimits) -> error::Result<bool> {
+        if line.trim_end_matches(['\r', '\n']).is_empty() {
+            // RFC 9112 2.2: ignore empty lines received before the request line
+            return Ok(!self.lines.is_empty());
+        }
+        if !self.lines.is_empty() {
+            // the request line is not a header
+            if self.lines.len() > limits.max_headers
+                || self.header_size + line.len() > limits.max_header_size
+            {
+                return Err(Error::HeaderTooLarge);
+            }
+            self.header_size += line.len();
+        }
+        self.lines.push(line);
+        Ok(false)
+    }
+}
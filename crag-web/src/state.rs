@@ -0,0 +1,117 @@
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+
+/// Application state registered with `ServerBuilder::with_state`, keyed by
+/// type.
+///
+/// The values live in an `Arc` so every request gets a cheap clone of the
+/// same map. Values that need to be mutated must bring their own
+/// synchronization, e.g. a `Mutex` or an atomic.
+#[derive(Clone, Default)]
+pub struct State {
+    values: Arc<HashMap<TypeId, Box<dyn Any + Send + Sync>>>,
+}
+
+impl State {
+    pub fn new() -> Self {
+        State::default()
+    }
+
+    /// Store `value`, returning the value of the same type it replaced.
+    ///
+    /// Only used while building the server, before the map is shared.
+    pub(crate) fn insert<T: Send + Sync + 'static>(&mut self, value: T) -> Option<T> {
+        Arc::get_mut(&mut self.values)
+            .expect("state is only modified before it is shared")
+            .insert(TypeId::of::<T>(), Box::new(value))
+            .and_then(|previous| previous.downcast().ok())
+            .map(|previous| *previous)
+    }
+
+    /// Value of type `T`, if one was registered
+    pub fn get<T: Send + Sync + 'static>(&self) -> Option<&T> {
+        self.values
+            .get(&TypeId::of::<T>())
+            .and_then(|value| value.downcast_ref())
+    }
+
+    pub fn contains<T: Send + Sync + 'static>(&self) -> bool {
+        self.get::<T>().is_some()
+    }
+
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+}
+
+impl fmt::Debug for State {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("State").field("len", &self.len()).finish()
+    }
+}
+
+// two states are equal when they share the same values
+impl PartialEq for State {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.values, &other.values) || (self.is_empty() && other.is_empty())
+    }
+}
+
+impl Eq for State {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    struct Config {
+        name: &'static str,
+    }
+
+    #[test]
+    fn test_get_by_type() {
+        let mut state = State::new();
+        assert!(state.insert(Config { name: "crag" }).is_none());
+        assert!(state.insert(AtomicUsize::new(0)).is_none());
+
+        assert_eq!(state.get::<Config>().unwrap().name, "crag");
+        state
+            .get::<AtomicUsize>()
+            .unwrap()
+            .fetch_add(1, Ordering::SeqCst);
+        assert_eq!(
+            state.get::<AtomicUsize>().unwrap().load(Ordering::SeqCst),
+            1
+        );
+        assert!(state.get::<String>().is_none());
+        assert_eq!(state.len(), 2);
+    }
+
+    #[test]
+    fn test_insert_replaces() {
+        let mut state = State::new();
+        state.insert(Config { name: "first" });
+        let previous = state.insert(Config { name: "second" }).unwrap();
+        assert_eq!(previous.name, "first");
+        assert_eq!(state.get::<Config>().unwrap().name, "second");
+    }
+
+    #[test]
+    fn test_clones_share_values() {
+        let mut state = State::new();
+        state.insert(Config { name: "crag" });
+        let clone = state.clone();
+        assert_eq!(state, clone);
+
+        let mut other = State::new();
+        other.insert(Config { name: "crag" });
+        assert_ne!(state, other);
+        assert_eq!(State::new(), State::new());
+    }
+}
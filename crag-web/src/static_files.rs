@@ -0,0 +1,509 @@
+use anyhow::{anyhow, bail, Result};
+use std::fs::{self, File};
+use std::io;
+use std::path::{Component, Path, PathBuf};
+
+use crate::body::Body;
+#[cfg(feature = "compression")]
+use crate::compression::{self, Encoding};
+use crate::conditional::ETag;
+use crate::handler::{Handler, NotFound};
+use crate::query;
+use crate::request::Request;
+use crate::response::Response;
+use crate::status::StatusCode;
+
+/// Name of the wildcard parameter holding the path below the mount prefix
+pub(crate) const PATH_PARAM: &str = "path";
+
+/// Handler serving the files of a directory, mounted at a prefix with
+/// `ServerBuilder::static_files`.
+///
+/// Requests for a directory are answered with its `index.html`, or with a
+/// listing of its entries when enabled. Paths containing `..` and files
+/// resolving outside the directory, e.g. through a symlink, are not found.
+/// Requests that find nothing are answered by the error handler registered
+/// on the server.
+#[derive(Clone, Debug)]
+pub struct StaticFiles {
+    // canonical, so resolved paths can be checked with `starts_with`
+    root: PathBuf,
+    listing: bool,
+    #[cfg(feature = "compression")]
+    precompressed: bool,
+}
+
+impl StaticFiles {
+    /// Serve the files below `root`, which must be an existing directory
+    pub fn new(root: impl AsRef<Path>) -> Result<Self> {
+        let root = root.as_ref();
+        let canonical =
+            fs::canonicalize(root).map_err(|err| anyhow!("Cannot serve {root:?}: {err}"))?;
+        if !canonical.is_dir() {
+            bail!("Cannot serve {root:?}: not a directory");
+        }
+        Ok(StaticFiles {
+            root: canonical,
+            listing: false,
+            #[cfg(feature = "compression")]
+            precompressed: false,
+        })
+    }
+
+    /// Answer requests for directories without an `index.html` with a
+    /// listing of their entries, disabled by default
+    pub fn directory_listing(mut self, enabled: bool) -> Self {
+        self.listing = enabled;
+        self
+    }
+
+    /// Serve `name.br` or `name.gz` next to a requested file when the
+    /// client accepts that coding, disabled by default
+    #[cfg(feature = "compression")]
+    pub fn precompressed(mut self, enabled: bool) -> Self {
+        self.precompressed = enabled;
+        self
+    }
+
+    fn serve(&self, request: Request) -> Result<Response> {
+        let relative = request.param(PATH_PARAM).unwrap_or_default();
+        let path = match self.resolve(relative) {
+            Ok(Some(path)) => path,
+            Ok(None) => return Err(NotFound(request).into()),
+            Err(err) if err.kind() == io::ErrorKind::PermissionDenied => {
+                return Ok(Response::new(StatusCode::FORBIDDEN))
+            }
+            Err(err) => return Err(err.into()),
+        };
+
+        if !path.is_dir() {
+            return self.file_response(&request, &path);
+        }
+
+        // relative links in the index page or listing only resolve against
+        // the directory when its URL ends with a slash
+        if !request.route.route.ends_with('/') {
+            let location = format!("{}/", request.route.route);
+            return Response::redirect(StatusCode::MOVED_PERMANENTLY, location);
+        }
+
+        if let Some(index) = self.contained(&path.join("index.html"))? {
+            if index.is_file() {
+                return self.file_response(&request, &index);
+            }
+        }
+        if self.listing {
+            return listing_response(&path, &request.path());
+        }
+        Err(NotFound(request).into())
+    }
+
+    // the request is only needed to negotiate precompressed files
+    #[cfg_attr(not(feature = "compression"), allow(unused_variables))]
+    fn file_response(&self, request: &Request, path: &Path) -> Result<Response> {
+        #[cfg(feature = "compression")]
+        if self.precompressed {
+            return self.precompressed_response(request, path);
+        }
+        file_response(path)
+    }
+
+    /// Response with the precompressed sibling of the file the client
+    /// prefers, or with the file itself
+    #[cfg(feature = "compression")]
+    fn precompressed_response(&self, request: &Request, path: &Path) -> Result<Response> {
+        let mut siblings = vec![];
+        for encoding in [Encoding::Brotli, Encoding::Gzip] {
+            let Some(extension) = encoding.extension() else {
+                continue;
+            };
+            let mut name = path.as_os_str().to_owned();
+            name.push(".");
+            name.push(extension);
+            if let Some(sibling) = self.contained(Path::new(&name))? {
+                if sibling.is_file() {
+                    siblings.push((encoding, sibling));
+                }
+            }
+        }
+        if siblings.is_empty() {
+            return file_response(path);
+        }
+
+        let available: Vec<Encoding> = siblings.iter().map(|(encoding, _)| *encoding).collect();
+        let chosen = request
+            .headers
+            .get("Accept-Encoding")
+            .and_then(|accept| compression::negotiate(accept, &available));
+        let mut response = match siblings
+            .iter()
+            .find(|(encoding, _)| Some(*encoding) == chosen)
+        {
+            Some((encoding, sibling)) => {
+                let mut response = file_response(sibling)?;
+                response.headers.set("Content-Type", mime_type(path));
+                response
+                    .headers
+                    .insert("Content-Encoding", encoding.as_str());
+                response
+            }
+            None => file_response(path)?,
+        };
+        compression::add_vary(&mut response.headers, "Accept-Encoding");
+        Ok(response)
+    }
+
+    /// Map the path below the prefix to a file or directory inside the root
+    fn resolve(&self, relative: &str) -> io::Result<Option<PathBuf>> {
+        let mut path = self.root.clone();
+        for segment in relative.split('/').filter(|segment| !segment.is_empty()) {
+            // rejects `..` and `.` as well as anything the platform would
+            // treat as a root or prefix, e.g. `C:` on Windows
+            let mut components = Path::new(segment).components();
+            match (components.next(), components.next()) {
+                (Some(Component::Normal(name)), None) if !segment.contains('\\') => path.push(name),
+                _ => return Ok(None),
+            }
+        }
+        self.contained(&path)
+    }
+
+    /// Canonical form of `path`, if it exists and is inside the root once
+    /// symlinks are resolved
+    fn contained(&self, path: &Path) -> io::Result<Option<PathBuf>> {
+        match fs::canonicalize(path) {
+            Ok(canonical) if canonical.starts_with(&self.root) => Ok(Some(canonical)),
+            Ok(_) => Ok(None),
+            // a path with a NUL byte can't name a file
+            Err(err)
+                if matches!(
+                    err.kind(),
+                    io::ErrorKind::NotFound | io::ErrorKind::InvalidInput
+                ) =>
+            {
+                Ok(None)
+            }
+            Err(err) => Err(err),
+        }
+    }
+}
+
+impl Handler for StaticFiles {
+    fn handle(&self, request: Request) -> Result<Response> {
+        self.serve(request)
+    }
+}
+
+/// Response streaming the file, with validators from its metadata so
+/// conditional requests can be answered without reading it
+fn file_response(path: &Path) -> Result<Response> {
+    let file = File::open(path)?;
+    let metadata = file.metadata()?;
+    let length = metadata.len();
+
+    let mut response = Response::build().header("Content-Type", mime_type(path));
+    if let Ok(modified) = metadata.modified() {
+        response = response
+            .etag(&ETag::from_metadata(length, modified))
+            .last_modified(modified);
+    }
+    response.body(Body::from_file(file)?).finalize()
+}
+
+/// HTML page linking to the entries of the directory at `route`
+fn listing_response(dir: &Path, route: &str) -> Result<Response> {
+    let mut entries = vec![];
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        // follows symlinks, so a link to a directory is listed as one
+        let is_dir = entry.path().is_dir();
+        entries.push((name, is_dir));
+    }
+    entries.sort();
+
+    let title = escape_html(route);
+    let mut page = format!(
+        "<!DOCTYPE html>\n<html lang=\"en\">\n<head>\n    <meta charset=\"UTF-8\">\n    <title>Index of {title}</title>\n</head>\n    <body>\n        <h1>Index of {title}</h1>\n        <ul>\n"
+    );
+    if route != "/" {
+        page.push_str("            <li><a href=\"../\">../</a></li>\n");
+    }
+    for (name, is_dir) in entries {
+        let slash = if is_dir { "/" } else { "" };
+        page.push_str(&format!(
+            "            <li><a href=\"{}{slash}\">{}{slash}</a></li>\n",
+            query::percent_encode(&name),
+            escape_html(&name)
+        ));
+    }
+    page.push_str("        </ul>\n    </body>\n</html>\n");
+
+    Response::build()
+        .header("Content-Type", "text/html")
+        .body(page)
+        .finalize()
+}
+
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Guess the MIME type of a file from its extension
+pub fn mime_type(path: impl AsRef<Path>) -> &'static str {
+    let extension = path
+        .as_ref()
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .unwrap_or_default()
+        .to_ascii_lowercase();
+
+    match extension.as_str() {
+        "html" | "htm" => "text/html",
+        "css" => "text/css",
+        "js" | "mjs" => "application/javascript",
+        "json" | "map" => "application/json",
+        "txt" => "text/plain",
+        "md" => "text/markdown",
+        "csv" => "text/csv",
+        "xml" => "application/xml",
+        "wasm" => "application/wasm",
+        "pdf" => "application/pdf",
+        "zip" => "application/zip",
+        "gz" => "application/gzip",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "svg" => "image/svg+xml",
+        "webp" => "image/webp",
+        "avif" => "image/avif",
+        "ico" => "image/x-icon",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        "ttf" => "font/ttf",
+        "otf" => "font/otf",
+        "mp3" => "audio/mpeg",
+        "ogg" => "audio/ogg",
+        "wav" => "audio/wav",
+        "mp4" => "video/mp4",
+        "webm" => "video/webm",
+        _ => "application/octet-stream",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::methods::Method;
+
+    /// Directory under the system temp dir, removed when dropped
+    struct TestDir(PathBuf);
+
+    impl TestDir {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("crag-web-{name}-{}", std::process::id()));
+            _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(&dir).unwrap();
+            TestDir(dir)
+        }
+
+        fn write(&self, path: &str, contents: &str) {
+            let path = self.0.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, contents).unwrap();
+        }
+    }
+
+    impl Drop for TestDir {
+        fn drop(&mut self) {
+            _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn site(name: &str) -> TestDir {
+        let dir = TestDir::new(name);
+        dir.write("public/index.html", "<h1>home</h1>");
+        dir.write("public/style.css", "body {}");
+        dir.write("public/docs/a b.txt", "spaced");
+        dir.write("public/docs/<b>.txt", "tagged");
+        dir.write("secret.txt", "secret");
+        dir
+    }
+
+    fn request(route: &str, path: &str) -> Request {
+        let mut request = Request::new(Method::GET, route.into());
+        request
+            .params
+            .insert(PATH_PARAM.to_owned(), path.to_owned());
+        request
+    }
+
+    fn get(files: &StaticFiles, route: &str, path: &str) -> Response {
+        files.handle(request(route, path)).unwrap()
+    }
+
+    /// Whether the request is handed back to go to the 404 handler
+    fn not_found(files: &StaticFiles, route: &str, path: &str) -> bool {
+        let err = files.handle(request(route, path)).unwrap_err();
+        err.downcast_ref::<NotFound>()
+            .is_some_and(|NotFound(request)| request.route.route == route)
+    }
+
+    fn text(response: Response) -> String {
+        String::from_utf8(response.body.into_bytes().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_serve_file() {
+        let dir = site("serve-file");
+        let files = StaticFiles::new(dir.0.join("public")).unwrap();
+
+        let response = get(&files, "/static/style.css", "style.css");
+        assert_eq!(response.status, StatusCode::OK);
+        assert_eq!(response.headers.content_type(), Some("text/css"));
+        assert_eq!(response.body.len(), Some(7));
+        assert!(response.etag().unwrap().is_weak());
+        assert!(response.last_modified().is_some());
+        assert_eq!(text(response), "body {}");
+
+        let response = get(&files, "/static/docs/a b.txt", "docs/a b.txt");
+        assert_eq!(text(response), "spaced");
+
+        assert!(not_found(&files, "/static/missing.css", "missing.css"));
+        // a NUL byte can't be part of a file name
+        assert!(not_found(&files, "/static/a%00.css", "a\0.css"));
+    }
+
+    #[test]
+    fn test_index_html() {
+        let dir = site("index-html");
+        let files = StaticFiles::new(dir.0.join("public")).unwrap();
+
+        let response = get(&files, "/static/", "");
+        assert_eq!(response.headers.content_type(), Some("text/html"));
+        assert_eq!(text(response), "<h1>home</h1>");
+
+        let response = get(&files, "/static", "");
+        assert_eq!(response.status, StatusCode::MOVED_PERMANENTLY);
+        assert_eq!(response.headers.get("Location"), Some("/static/"));
+    }
+
+    #[test]
+    fn test_directory_listing() {
+        let dir = site("listing");
+        let files = StaticFiles::new(dir.0.join("public")).unwrap();
+
+        assert!(not_found(&files, "/static/docs/", "docs"));
+
+        let files = files.directory_listing(true);
+        let response = get(&files, "/static/docs/", "docs");
+        assert_eq!(response.status, StatusCode::OK);
+        let page = text(response);
+        assert!(page.contains("<a href=\"../\">"));
+        assert!(page.contains("<a href=\"a%20b.txt\">a b.txt</a>"));
+        assert!(page.contains("<a href=\"%3Cb%3E.txt\">&lt;b&gt;.txt</a>"));
+
+        let response = get(&files, "/static/docs", "docs");
+        assert_eq!(response.status, StatusCode::MOVED_PERMANENTLY);
+    }
+
+    #[test]
+    fn test_rejects_traversal() {
+        let dir = site("traversal");
+        let files = StaticFiles::new(dir.0.join("public")).unwrap();
+
+        for path in ["../secret.txt", "docs/../../secret.txt", "./style.css"] {
+            assert!(not_found(&files, "/static/x", path), "{path}");
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_rejects_symlink_escape() {
+        let dir = site("symlink");
+        std::os::unix::fs::symlink(dir.0.join("secret.txt"), dir.0.join("public/link.txt"))
+            .unwrap();
+        std::os::unix::fs::symlink(dir.0.join("public/style.css"), dir.0.join("public/ok.css"))
+            .unwrap();
+        let files = StaticFiles::new(dir.0.join("public")).unwrap();
+
+        assert!(not_found(&files, "/static/link.txt", "link.txt"));
+
+        // links that stay inside the directory are fine
+        let response = get(&files, "/static/ok.css", "ok.css");
+        assert_eq!(text(response), "body {}");
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    fn test_precompressed() {
+        let dir = site("precompressed");
+        dir.write("public/style.css.gz", "gzipped");
+        dir.write("public/style.css.br", "brotli");
+        let files = StaticFiles::new(dir.0.join("public"))
+            .unwrap()
+            .precompressed(true);
+        let get_encoded = |accept_encoding: &str| {
+            let mut request = Request::new(Method::GET, "/static/style.css".into());
+            request.headers.insert("Accept-Encoding", accept_encoding);
+            request
+                .params
+                .insert(PATH_PARAM.to_owned(), "style.css".to_owned());
+            files.handle(request).unwrap()
+        };
+
+        let response = get_encoded("gzip, br");
+        assert_eq!(response.headers.get("Content-Encoding"), Some("br"));
+        assert_eq!(response.headers.content_type(), Some("text/css"));
+        assert_eq!(response.headers.get("Vary"), Some("Accept-Encoding"));
+        assert_eq!(text(response), "brotli");
+
+        let response = get_encoded("gzip");
+        assert_eq!(response.headers.get("Content-Encoding"), Some("gzip"));
+        assert_eq!(text(response), "gzipped");
+
+        let response = get_encoded("identity");
+        assert!(!response.headers.contains("Content-Encoding"));
+        assert_eq!(response.headers.get("Vary"), Some("Accept-Encoding"));
+        assert_eq!(text(response), "body {}");
+
+        // files without siblings are served as they are
+        let response = get(&files, "/static/docs/a b.txt", "docs/a b.txt");
+        assert!(!response.headers.contains("Vary"));
+    }
+
+    #[test]
+    fn test_new_requires_directory() {
+        let dir = site("new");
+        assert!(StaticFiles::new(dir.0.join("missing")).is_err());
+        assert!(StaticFiles::new(dir.0.join("secret.txt")).is_err());
+    }
+
+    #[test]
+    fn test_mime_type() {
+        assert_eq!(mime_type("index.HTML"), "text/html");
+        assert_eq!(mime_type("app.js"), "application/javascript");
+        assert_eq!(mime_type("photo.jpeg"), "image/jpeg");
+        assert_eq!(mime_type("font.woff2"), "font/woff2");
+        assert_eq!(mime_type("README"), "application/octet-stream");
+    }
+
+    #[test]
+    fn test_escape_html() {
+        assert_eq!(
+            escape_html("<a href=\"x\">'&'</a>"),
+            "&lt;a href=&quot;x&quot;&gt;&#39;&amp;&#39;&lt;/a&gt;"
+        );
+    }
+}
@@ -0,0 +1,183 @@
+use anyhow::{bail, Result};
+use std::fmt;
+
+/// HTTP response status code
+#[derive(Eq, PartialEq, Hash, Clone, Copy, Debug, PartialOrd, Ord)]
+pub struct StatusCode(u16);
+
+impl StatusCode {
+    pub const CONTINUE: StatusCode = StatusCode(100);
+    pub const SWITCHING_PROTOCOLS: StatusCode = StatusCode(101);
+
+    pub const OK: StatusCode = StatusCode(200);
+    pub const CREATED: StatusCode = StatusCode(201);
+    pub const ACCEPTED: StatusCode = StatusCode(202);
+    pub const NO_CONTENT: StatusCode = StatusCode(204);
+    pub const PARTIAL_CONTENT: StatusCode = StatusCode(206);
+
+    pub const MOVED_PERMANENTLY: StatusCode = StatusCode(301);
+    pub const FOUND: StatusCode = StatusCode(302);
+    pub const SEE_OTHER: StatusCode = StatusCode(303);
+    pub const NOT_MODIFIED: StatusCode = StatusCode(304);
+    pub const TEMPORARY_REDIRECT: StatusCode = StatusCode(307);
+    pub const PERMANENT_REDIRECT: StatusCode = StatusCode(308);
+
+    pub const BAD_REQUEST: StatusCode = StatusCode(400);
+    pub const UNAUTHORIZED: StatusCode = StatusCode(401);
+    pub const FORBIDDEN: StatusCode = StatusCode(403);
+    pub const NOT_FOUND: StatusCode = StatusCode(404);
+    pub const METHOD_NOT_ALLOWED: StatusCode = StatusCode(405);
+    pub const NOT_ACCEPTABLE: StatusCode = StatusCode(406);
+    pub const REQUEST_TIMEOUT: StatusCode = StatusCode(408);
+    pub const CONFLICT: StatusCode = StatusCode(409);
+    pub const GONE: StatusCode = StatusCode(410);
+    pub const LENGTH_REQUIRED: StatusCode = StatusCode(411);
+    pub const PRECONDITION_FAILED: StatusCode = StatusCode(412);
+    pub const PAYLOAD_TOO_LARGE: StatusCode = StatusCode(413);
+    pub const URI_TOO_LONG: StatusCode = StatusCode(414);
+    pub const UNSUPPORTED_MEDIA_TYPE: StatusCode = StatusCode(415);
+    pub const RANGE_NOT_SATISFIABLE: StatusCode = StatusCode(416);
+    pub const UNPROCESSABLE_ENTITY: StatusCode = StatusCode(422);
+    pub const TOO_MANY_REQUESTS: StatusCode = StatusCode(429);
+    pub const REQUEST_HEADER_FIELDS_TOO_LARGE: StatusCode = StatusCode(431);
+
+    pub const INTERNAL_SERVER_ERROR: StatusCode = StatusCode(500);
+    pub const NOT_IMPLEMENTED: StatusCode = StatusCode(501);
+    pub const BAD_GATEWAY: StatusCode = StatusCode(502);
+    pub const SERVICE_UNAVAILABLE: StatusCode = StatusCode(503);
+    pub const GATEWAY_TIMEOUT: StatusCode = StatusCode(504);
+    pub const HTTP_VERSION_NOT_SUPPORTED: StatusCode = StatusCode(505);
+
+    /// Create a status code from its numeric value, which must be three digits
+    pub fn from_u16(code: u16) -> Result<StatusCode> {
+        if !(100..=999).contains(&code) {
+            bail!("Invalid status code: {code}");
+        }
+        Ok(StatusCode(code))
+    }
+
+    pub fn as_u16(&self) -> u16 {
+        self.0
+    }
+
+    /// Canonical reason phrase, empty for unregistered codes
+    pub fn reason_phrase(&self) -> &'static str {
+        match self.0 {
+            100 => "Continue",
+            101 => "Switching Protocols",
+            200 => "OK",
+            201 => "Created",
+            202 => "Accepted",
+            204 => "No Content",
+            206 => "Partial Content",
+            301 => "Moved Permanently",
+            302 => "Found",
+            303 => "See Other",
+            304 => "Not Modified",
+            307 => "Temporary Redirect",
+            308 => "Permanent Redirect",
+            400 => "Bad Request",
+            401 => "Unauthorized",
+            403 => "Forbidden",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            406 => "Not Acceptable",
+            408 => "Request Timeout",
+            409 => "Conflict",
+            410 => "Gone",
+            411 => "Length Required",
+            412 => "Precondition Failed",
+            413 => "Content Too Large",
+            414 => "URI Too Long",
+            415 => "Unsupported Media Type",
+            416 => "Range Not Satisfiable",
+            422 => "Unprocessable Content",
+            429 => "Too Many Requests",
+            431 => "Request Header Fields Too Large",
+            500 => "Internal Server Error",
+            501 => "Not Implemented",
+            502 => "Bad Gateway",
+            503 => "Service Unavailable",
+            504 => "Gateway Timeout",
+            505 => "HTTP Version Not Supported",
+            _ => "",
+        }
+    }
+
+    pub fn is_informational(&self) -> bool {
+        (100..200).contains(&self.0)
+    }
+
+    pub fn is_success(&self) -> bool {
+        (200..300).contains(&self.0)
+    }
+
+    pub fn is_redirection(&self) -> bool {
+        (300..400).contains(&self.0)
+    }
+
+    pub fn is_client_error(&self) -> bool {
+        (400..500).contains(&self.0)
+    }
+
+    pub fn is_server_error(&self) -> bool {
+        (500..600).contains(&self.0)
+    }
+
+    /// Whether responses with this status never carry a body (RFC 9110 6.4.1)
+    pub fn forbids_body(&self) -> bool {
+        self.is_informational()
+            || *self == StatusCode::NO_CONTENT
+            || *self == StatusCode::NOT_MODIFIED
+    }
+}
+
+impl fmt::Display for StatusCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.0, self.reason_phrase())
+    }
+}
+
+impl TryFrom<u16> for StatusCode {
+    type Error = anyhow::Error;
+
+    fn try_from(code: u16) -> Result<StatusCode> {
+        StatusCode::from_u16(code)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_u16() {
+        assert_eq!(StatusCode::from_u16(201).unwrap(), StatusCode::CREATED);
+        assert_eq!(StatusCode::from_u16(299).unwrap().as_u16(), 299);
+        assert!(StatusCode::from_u16(99).is_err());
+        assert!(StatusCode::from_u16(1000).is_err());
+    }
+
+    #[test]
+    fn test_display() {
+        assert_eq!(StatusCode::OK.to_string(), "200 OK");
+        assert_eq!(StatusCode::NOT_FOUND.to_string(), "404 Not Found");
+        assert_eq!(StatusCode::from_u16(299).unwrap().to_string(), "299 ");
+    }
+
+    #[test]
+    fn test_classes() {
+        assert!(StatusCode::CONTINUE.is_informational());
+        assert!(StatusCode::NO_CONTENT.is_success());
+        assert!(StatusCode::PERMANENT_REDIRECT.is_redirection());
+        assert!(StatusCode::CONFLICT.is_client_error());
+        assert!(StatusCode::SERVICE_UNAVAILABLE.is_server_error());
+    }
+
+    #[test]
+    fn test_forbids_body() {
+        assert!(StatusCode::NO_CONTENT.forbids_body());
+        assert!(StatusCode::NOT_MODIFIED.forbids_body());
+        assert!(!StatusCode::OK.forbids_body());
+    }
+}
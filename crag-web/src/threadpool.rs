@@ -1,26 +1,138 @@
+use std::any::Any;
+use std::collections::VecDeque;
 use std::error;
 use std::fmt;
-use std::sync::mpsc;
-use std::sync::{Arc, Mutex};
+use std::io;
+use std::marker::PhantomData;
+use std::mem;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError};
 use std::thread;
+use std::time::{Duration, Instant};
+use tracing::{debug, error, warn};
 
 #[derive(Debug)]
 pub enum PoolCreationError {
     ZeroSize,
+    ZeroCapacity,
+    /// The maximum number of workers is below the minimum
+    MaxBelowMin,
+    /// The OS refused to create a worker thread
+    Spawn(io::Error),
 }
 
 impl fmt::Display for PoolCreationError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{:?}", self)
+        match self {
+            PoolCreationError::Spawn(err) => write!(f, "Failed to spawn worker thread: {err}"),
+            _ => write!(f, "{:?}", self),
+        }
+    }
+}
+
+impl error::Error for PoolCreationError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            PoolCreationError::Spawn(err) => Some(err),
+            _ => None,
+        }
     }
 }
 
-impl error::Error for PoolCreationError {}
+/// Settings for a ThreadPool that grows from `min_workers` threads up to
+/// `max_workers` under load and retires the extra threads once they have
+/// been idle for `idle_timeout`
+#[derive(Clone, Copy, Debug)]
+pub struct PoolBuilder {
+    min_workers: usize,
+    max_workers: usize,
+    // None for an unbounded queue
+    capacity: Option<usize>,
+    idle_timeout: Duration,
+    // None for the platform default
+    stack_size: Option<usize>,
+}
+
+impl PoolBuilder {
+    /// Pool of a fixed `workers` threads with an unbounded queue, until
+    /// configured otherwise
+    pub fn new(workers: usize) -> PoolBuilder {
+        PoolBuilder {
+            min_workers: workers,
+            max_workers: workers,
+            capacity: None,
+            idle_timeout: Duration::from_secs(60),
+            stack_size: None,
+        }
+    }
+
+    /// Most threads the pool grows to while jobs wait for a worker
+    pub fn max_workers(mut self, workers: usize) -> Self {
+        self.max_workers = workers;
+        self
+    }
+
+    /// Most jobs waiting for a worker. `execute` blocks while the queue is
+    /// full.
+    pub fn capacity(mut self, capacity: usize) -> Self {
+        self.capacity = Some(capacity);
+        self
+    }
+
+    /// How long a thread beyond the minimum waits for a job before exiting
+    pub fn idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_timeout = timeout;
+        self
+    }
+
+    /// Stack size of the worker threads in bytes
+    pub fn stack_size(mut self, bytes: usize) -> Self {
+        self.stack_size = Some(bytes);
+        self
+    }
+
+    /// Create the pool and spawn its minimum number of workers
+    pub fn build(self) -> Result<ThreadPool, PoolCreationError> {
+        if self.min_workers < 1 {
+            return Err(PoolCreationError::ZeroSize);
+        }
+        if self.capacity == Some(0) {
+            return Err(PoolCreationError::ZeroCapacity);
+        }
+        if self.max_workers < self.min_workers {
+            return Err(PoolCreationError::MaxBelowMin);
+        }
+
+        let pool = ThreadPool {
+            handle: PoolHandle {
+                shared: Arc::new(Shared {
+                    workers: Mutex::new(Vec::with_capacity(self.min_workers)),
+                    queue: Arc::new(Queue::new(&self)),
+                    stack_size: self.stack_size,
+                    next_id: AtomicUsize::new(0),
+                }),
+            },
+        };
+        for _ in 0..self.min_workers {
+            // dropping the pool on error stops the workers already spawned
+            pool.handle.shared.queue.lock().workers += 1;
+            pool.handle
+                .spawn_worker()
+                .map_err(PoolCreationError::Spawn)?;
+        }
+        Ok(pool)
+    }
+}
 
+/// Pool of worker threads running jobs from a shared queue.
+///
+/// The pool owns its threads: `shutdown` or dropping it stops them once the
+/// queued jobs ran. `handle` gives out clonable handles for submitting jobs
+/// from elsewhere, e.g. from request handlers.
 #[derive(Debug)]
 pub struct ThreadPool {
-    _workers: Vec<Worker>,
-    sender: mpsc::Sender<Job>,
+    handle: PoolHandle,
 }
 
 impl ThreadPool {
@@ -28,74 +140,1025 @@ impl ThreadPool {
     ///
     /// The size is the number of threads in the pool.
     pub fn build(size: usize) -> Result<ThreadPool, PoolCreationError> {
-        if size < 1 {
-            return Err(PoolCreationError::ZeroSize);
-        }
+        PoolBuilder::new(size).build()
+    }
+
+    /// Clonable handle submitting jobs to this pool
+    pub fn handle(&self) -> PoolHandle {
+        self.handle.clone()
+    }
+
+    /// Jobs waiting for a worker, shared so their number can be monitored
+    pub(crate) fn queue(&self) -> &Arc<Queue> {
+        &self.handle.shared.queue
+    }
+
+    /// Execute a request in the stream by being passed in the
+    /// handle_connection function as a closure, waiting for space if the
+    /// queue is bounded and full. A new worker is started first if no idle
+    /// worker is left to take the job and the pool is below its maximum.
+    pub fn execute<F>(&self, f: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.handle.execute(f)
+    }
 
-        // use mpsc channel to send requests to workers
-        let (sender, receiver) = mpsc::channel();
+    /// Run `f` on a worker, returning a handle to wait for its result
+    pub fn spawn<F, T>(&self, f: F) -> JobHandle<T>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        self.handle.spawn(f)
+    }
 
-        // workers will need a Mutex to share the receiver
-        // across threads so we wrap in thread safe
-        // smart pointer Arc
-        let receiver = Arc::new(Mutex::new(receiver));
+    /// Run jobs that borrow from the caller, see `PoolHandle::scope`
+    pub fn scope<'env, F, R>(&self, f: F) -> R
+    where
+        F: for<'scope> FnOnce(&'scope Scope<'scope, 'env>) -> R,
+    {
+        self.handle.scope(f)
+    }
 
-        let mut workers = Vec::with_capacity(size);
+    pub fn stats(&self) -> PoolStats {
+        self.handle.stats()
+    }
 
-        for id in 0..size {
-            // give each worker a clone of the Arc that holds the receiver
-            workers.push(Worker::new(id, Arc::clone(&receiver)));
+    /// Stop accepting jobs, let workers finish the jobs already queued and
+    /// wait up to `timeout` for them to exit.
+    ///
+    /// Returns false if some workers were still busy at the deadline; those
+    /// threads are detached and exit once their current job completes.
+    pub fn shutdown(self, timeout: Duration) -> bool {
+        // workers exit once the queue is closed and drained
+        self.queue().close();
+
+        let deadline = Instant::now() + timeout;
+        let mut workers = mem::take(&mut *self.handle.lock_workers());
+        loop {
+            workers.retain_mut(|worker| !worker.try_join());
+            if workers.is_empty() || Instant::now() >= deadline {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
         }
 
-        Ok(ThreadPool {
-            _workers: workers,
-            sender,
-        })
+        for worker in &workers {
+            warn!("Worker {} still busy at shutdown deadline", worker.id);
+        }
+        workers.is_empty()
     }
-    /// Execute a request in the stream by being passed in the
-    /// handle_connection function as a closure
+}
+
+impl Drop for ThreadPool {
+    fn drop(&mut self) {
+        self.queue().close();
+
+        for worker in self.handle.lock_workers().iter_mut() {
+            if let Some(thread) = worker.thread.take() {
+                _ = thread.join();
+            }
+        }
+    }
+}
+
+/// Clonable handle submitting jobs to a ThreadPool.
+///
+/// Jobs submitted after the pool shut down are not run: `execute` drops
+/// them and the handles returned by `spawn` report a panic.
+///
+/// Waiting for a job from another job of the same pool, with
+/// `JobHandle::join` or by ending a scope, blocks that worker. If every
+/// worker waits, the jobs they wait for only run once the pool may grow.
+#[derive(Clone, Debug)]
+pub struct PoolHandle {
+    shared: Arc<Shared>,
+}
+
+#[derive(Debug)]
+struct Shared {
+    // includes workers that retired but haven't been joined yet
+    workers: Mutex<Vec<Worker>>,
+    queue: Arc<Queue>,
+    stack_size: Option<usize>,
+    // numbers the worker threads, never reused
+    next_id: AtomicUsize,
+}
+
+impl PoolHandle {
+    /// Run `f` on a worker without waiting for it
     pub fn execute<F>(&self, f: F)
     where
         F: FnOnce() + Send + 'static,
     {
-        let job: Job = Box::new(f);
-        self.sender.send(job).expect("sender error");
+        let job: Job = Box::new(move || {
+            f();
+            true
+        });
+        if self.submit(job).is_err() {
+            warn!("Pool is shut down, dropping job");
+        }
+    }
+
+    /// Run `f` on a worker, returning a handle to wait for its result
+    pub fn spawn<F, T>(&self, f: F) -> JobHandle<T>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let packet = Arc::new(Packet::new(None));
+        if let Err(job) = self.submit(packaged(f, Arc::clone(&packet))) {
+            drop(job);
+            packet.set(Err(Box::new("pool is shut down")));
+        }
+        JobHandle { packet }
+    }
+
+    /// Run jobs that borrow from the caller's stack, like
+    /// `std::thread::scope` does with threads.
+    ///
+    /// Returns once every job spawned on the scope has finished. If a job
+    /// panicked and its panic wasn't received through `join`, the scope
+    /// panics after that.
+    pub fn scope<'env, F, R>(&self, f: F) -> R
+    where
+        F: for<'scope> FnOnce(&'scope Scope<'scope, 'env>) -> R,
+    {
+        let scope = Scope {
+            pool: self,
+            data: Arc::new(ScopeData::default()),
+            scope: PhantomData,
+            env: PhantomData,
+        };
+        let result = panic::catch_unwind(AssertUnwindSafe(|| f(&scope)));
+        // jobs may borrow what the caller owns, so wait even if `f` panicked
+        scope.data.wait();
+        match result {
+            Err(panic) => panic::resume_unwind(panic),
+            Ok(_) if scope.data.unhandled_panic.load(Ordering::SeqCst) => {
+                panic!("a scoped job panicked")
+            }
+            Ok(result) => result,
+        }
+    }
+
+    /// What the pool's workers are doing right now
+    pub fn stats(&self) -> PoolStats {
+        self.shared.queue.stats()
+    }
+
+    /// Queue a job, starting a new worker first if needed. Returns the job
+    /// if the pool is shut down.
+    fn submit(&self, job: Job) -> Result<(), Job> {
+        if self.shared.queue.reserve_worker() {
+            // the job still runs once an existing worker is free
+            if let Err(err) = self.spawn_worker() {
+                warn!("Unable to grow the pool: {err}");
+            }
+        }
+        self.shared.queue.push(job)
+    }
+
+    /// Start a worker counted by the queue, uncounting it if the thread
+    /// can't be created
+    fn spawn_worker(&self) -> io::Result<()> {
+        let shared = &self.shared;
+        let id = shared.next_id.fetch_add(1, Ordering::Relaxed);
+        match Worker::new(id, Arc::clone(&shared.queue), shared.stack_size) {
+            Ok(worker) => {
+                let mut workers = self.lock_workers();
+                // forget the handles of workers that retired while idle
+                workers.retain_mut(|worker| !worker.try_join());
+                workers.push(worker);
+                Ok(())
+            }
+            Err(err) => {
+                shared.queue.lock().workers -= 1;
+                Err(err)
+            }
+        }
+    }
+
+    fn lock_workers(&self) -> MutexGuard<'_, Vec<Worker>> {
+        self.shared
+            .workers
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Jobs spawned with `PoolHandle::scope`, which may borrow anything that
+/// outlives the scope
+pub struct Scope<'scope, 'env: 'scope> {
+    pool: &'scope PoolHandle,
+    data: Arc<ScopeData>,
+    // invariant lifetimes, as in `std::thread::Scope`
+    scope: PhantomData<&'scope mut &'scope ()>,
+    env: PhantomData<&'env mut &'env ()>,
+}
+
+impl<'scope> Scope<'scope, '_> {
+    /// Run `f` on a worker of the pool; the scope waits for it to finish
+    pub fn spawn<F, T>(&'scope self, f: F) -> ScopedJobHandle<'scope, T>
+    where
+        F: FnOnce() -> T + Send + 'scope,
+        T: Send + 'scope,
+    {
+        let packet = Arc::new(Packet::new(Some(Arc::clone(&self.data))));
+        let job = packaged(f, Arc::clone(&packet));
+        let data = Arc::clone(&self.data);
+        data.started();
+        let scoped: Box<dyn FnOnce() -> bool + Send + 'scope> = Box::new(move || {
+            // the packaged job and everything it borrowed are dropped by
+            // the time the scope is told it finished
+            let completed = job();
+            data.finished();
+            completed
+        });
+        // SAFETY: `PoolHandle::scope` doesn't return before every job of the
+        // scope called `finished`, after which nothing borrowed for 'scope
+        // is used again
+        let scoped: Job =
+            unsafe { mem::transmute::<Box<dyn FnOnce() -> bool + Send + 'scope>, Job>(scoped) };
+        if let Err(job) = self.pool.submit(scoped) {
+            drop(job);
+            packet.set(Err(Box::new("pool is shut down")));
+            self.data.finished();
+        }
+        ScopedJobHandle {
+            packet,
+            scope: PhantomData,
+        }
+    }
+}
+
+impl fmt::Debug for Scope<'_, '_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Scope")
+            .field("pool", &self.pool)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Jobs of a scope still running, and whether one panicked unnoticed
+#[derive(Default)]
+struct ScopeData {
+    running: Mutex<usize>,
+    all_finished: Condvar,
+    unhandled_panic: AtomicBool,
+}
+
+impl ScopeData {
+    fn started(&self) {
+        *self.running.lock().unwrap_or_else(PoisonError::into_inner) += 1;
+    }
+
+    fn finished(&self) {
+        let mut running = self.running.lock().unwrap_or_else(PoisonError::into_inner);
+        *running -= 1;
+        if *running == 0 {
+            self.all_finished.notify_all();
+        }
+    }
+
+    fn wait(&self) {
+        let mut running = self.running.lock().unwrap_or_else(PoisonError::into_inner);
+        while *running > 0 {
+            running = self
+                .all_finished
+                .wait(running)
+                .unwrap_or_else(PoisonError::into_inner);
+        }
+    }
+}
+
+/// Handle to the result of a job started with `spawn`
+pub struct JobHandle<T> {
+    packet: Arc<Packet<T>>,
+}
+
+impl<T> JobHandle<T> {
+    /// Wait for the job to finish, returning its result or, if it
+    /// panicked, the panic payload
+    pub fn join(self) -> thread::Result<T> {
+        self.packet.wait()
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.packet.is_finished()
+    }
+}
+
+impl<T> fmt::Debug for JobHandle<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("JobHandle")
+            .field("finished", &self.is_finished())
+            .finish()
+    }
+}
+
+/// Handle to the result of a job started with `Scope::spawn`
+pub struct ScopedJobHandle<'scope, T> {
+    packet: Arc<Packet<T>>,
+    scope: PhantomData<&'scope ()>,
+}
+
+impl<T> ScopedJobHandle<'_, T> {
+    /// Wait for the job to finish, returning its result or, if it
+    /// panicked, the panic payload
+    pub fn join(self) -> thread::Result<T> {
+        self.packet.wait()
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.packet.is_finished()
+    }
+}
+
+impl<T> fmt::Debug for ScopedJobHandle<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ScopedJobHandle")
+            .field("finished", &self.is_finished())
+            .finish()
     }
 }
 
+/// Result of a spawned job, shared by the job and its handle
+struct Packet<T> {
+    result: Mutex<Option<thread::Result<T>>>,
+    done: Condvar,
+    // the scope of a scoped job, told about panics no handle received
+    scope: Option<Arc<ScopeData>>,
+}
+
+impl<T> Packet<T> {
+    fn new(scope: Option<Arc<ScopeData>>) -> Self {
+        Packet {
+            result: Mutex::new(None),
+            done: Condvar::new(),
+            scope,
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Option<thread::Result<T>>> {
+        self.result.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn set(&self, result: thread::Result<T>) {
+        *self.lock() = Some(result);
+        self.done.notify_all();
+    }
+
+    fn wait(&self) -> thread::Result<T> {
+        let mut result = self.lock();
+        loop {
+            if let Some(result) = result.take() {
+                return result;
+            }
+            result = self
+                .done
+                .wait(result)
+                .unwrap_or_else(PoisonError::into_inner);
+        }
+    }
+
+    fn is_finished(&self) -> bool {
+        self.lock().is_some()
+    }
+}
+
+impl<T> Drop for Packet<T> {
+    fn drop(&mut self) {
+        let result = self
+            .result
+            .get_mut()
+            .unwrap_or_else(PoisonError::into_inner);
+        if let (Some(scope), Some(Err(_))) = (&self.scope, result) {
+            scope.unhandled_panic.store(true, Ordering::SeqCst);
+        }
+    }
+}
+
+/// Job storing the result of `f`, or its panic, in `packet`
+fn packaged<'a, F, T>(f: F, packet: Arc<Packet<T>>) -> Box<dyn FnOnce() -> bool + Send + 'a>
+where
+    F: FnOnce() -> T + Send + 'a,
+    T: Send + 'a,
+{
+    Box::new(move || {
+        let result = panic::catch_unwind(AssertUnwindSafe(f));
+        let completed = result.is_ok();
+        packet.set(result);
+        completed
+    })
+}
+
+/// Snapshot of what the workers of a pool are doing
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct PoolStats {
+    /// Worker threads running
+    pub workers: usize,
+    /// Workers running a job
+    pub active: usize,
+    /// Workers waiting for a job
+    pub idle: usize,
+    /// Jobs waiting for a worker
+    pub queued: usize,
+    /// Jobs that returned
+    pub completed: u64,
+    /// Jobs that panicked
+    pub panicked: u64,
+}
+
 #[derive(Debug)]
 struct Worker {
-    _id: usize,
-    _thread: thread::JoinHandle<()>,
+    id: usize,
+    thread: Option<thread::JoinHandle<()>>,
 }
 
 impl Worker {
-    /// Create a new Worker with a receiver clone
-    /// and spawns a thread that loops over jobs sent over the
-    /// receiver and executes the job
-    fn new(id: usize, receiver: Arc<Mutex<mpsc::Receiver<Job>>>) -> Worker {
-        // if OS cannot create a new thread, thread::spawn will panic
-        // TODO: Change to thread::Builder which returns Result
-        let thread = thread::spawn(move || loop {
-            // blocks all other threads trying to aquire lock
-            // until it goes out of scope
-            match receiver.lock().unwrap().recv() {
-                Ok(job) => {
-                    job();
-                }
-                Err(_) => {
-                    println!("Worker {id} shutting down.");
-                    // break;
+    /// Create a new Worker and spawn a thread named `crag-worker-{id}` that
+    /// takes jobs from the queue and executes them until the queue is
+    /// closed or the worker retires
+    fn new(id: usize, queue: Arc<Queue>, stack_size: Option<usize>) -> io::Result<Worker> {
+        let mut builder = thread::Builder::new().name(format!("crag-worker-{id}"));
+        if let Some(stack_size) = stack_size {
+            builder = builder.stack_size(stack_size);
+        }
+        let thread = builder.spawn(move || {
+            // the queue's lock is released by `pop` before the job runs, so
+            // workers only contend for it while taking a job
+            while let Some(job) = queue.pop() {
+                // a panicking job must not kill the worker thread
+                let completed =
+                    panic::catch_unwind(AssertUnwindSafe(job)).unwrap_or_else(|panic| {
+                        error!("Worker {id} job panicked: {}", panic_message(&*panic));
+                        false
+                    });
+                queue.finish(completed);
+            }
+            debug!("Worker {id} shutting down.");
+        })?;
+
+        Ok(Worker {
+            id,
+            thread: Some(thread),
+        })
+    }
+
+    /// Join the thread if it has exited, returning whether it is gone
+    fn try_join(&mut self) -> bool {
+        match self.thread.take() {
+            Some(thread) if thread.is_finished() => {
+                _ = thread.join();
+                true
+            }
+            Some(thread) => {
+                self.thread = Some(thread);
+                false
+            }
+            None => true,
+        }
+    }
+}
+
+/// Type alias for the closure arument to ThreadPool.execute(), returning
+/// false if the job panicked
+type Job = Box<dyn FnOnce() -> bool + Send + 'static>;
+
+/// Jobs waiting for a worker, shared by every worker of a pool along with
+/// the count of workers
+#[derive(Debug)]
+pub(crate) struct Queue {
+    state: Mutex<QueueState>,
+    // signalled when a job is pushed or the queue is closed
+    available: Condvar,
+    // signalled when a worker takes a job from a full queue, or the queue
+    // is closed
+    space: Condvar,
+    capacity: Option<usize>,
+    min_workers: usize,
+    max_workers: usize,
+    idle_timeout: Duration,
+}
+
+#[derive(Default)]
+struct QueueState {
+    jobs: VecDeque<Job>,
+    // set at shutdown; workers drain the remaining jobs and exit
+    closed: bool,
+    // running workers, including those being spawned
+    workers: usize,
+    // workers running a job
+    busy: usize,
+    completed: u64,
+    panicked: u64,
+}
+
+impl fmt::Debug for QueueState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("QueueState")
+            .field("jobs", &self.jobs.len())
+            .field("closed", &self.closed)
+            .field("workers", &self.workers)
+            .field("busy", &self.busy)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Queue {
+    fn new(config: &PoolBuilder) -> Queue {
+        Queue {
+            state: Mutex::default(),
+            available: Condvar::new(),
+            space: Condvar::new(),
+            capacity: config.capacity,
+            min_workers: config.min_workers,
+            max_workers: config.max_workers,
+            idle_timeout: config.idle_timeout,
+        }
+    }
+
+    /// Number of jobs waiting for a worker
+    pub(crate) fn len(&self) -> usize {
+        self.lock().jobs.len()
+    }
+
+    /// Most jobs the queue holds, `None` if unbounded
+    pub(crate) fn capacity(&self) -> Option<usize> {
+        self.capacity
+    }
+
+    /// Number of running workers
+    pub(crate) fn workers(&self) -> usize {
+        self.lock().workers
+    }
+
+    pub(crate) fn is_full(&self) -> bool {
+        self.capacity.is_some_and(|capacity| self.len() >= capacity)
+    }
+
+    pub(crate) fn stats(&self) -> PoolStats {
+        let state = self.lock();
+        PoolStats {
+            workers: state.workers,
+            active: state.busy,
+            idle: state.workers - state.busy,
+            queued: state.jobs.len(),
+            completed: state.completed,
+            panicked: state.panicked,
+        }
+    }
+
+    /// Jobs can't poison the lock since it's never held while they run, but
+    /// recover from a poisoned lock anyway rather than taking down every
+    /// worker
+    fn lock(&self) -> MutexGuard<'_, QueueState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Count a new worker if the next job would find no free worker and
+    /// the pool may grow, returning whether the caller should spawn it
+    fn reserve_worker(&self) -> bool {
+        let mut state = self.lock();
+        // every queued job is already claimed by one of the free workers
+        let free = state.workers - state.busy;
+        let grow = !state.closed && free <= state.jobs.len() && state.workers < self.max_workers;
+        if grow {
+            state.workers += 1;
+        }
+        grow
+    }
+
+    /// Queue a job, waiting while the queue is full. Returns the job if the
+    /// queue is closed.
+    fn push(&self, job: Job) -> Result<(), Job> {
+        let mut state = self.lock();
+        while !state.closed
+            && self
+                .capacity
+                .is_some_and(|capacity| state.jobs.len() >= capacity)
+        {
+            state = self
+                .space
+                .wait(state)
+                .unwrap_or_else(PoisonError::into_inner);
+        }
+        if state.closed {
+            return Err(job);
+        }
+        state.jobs.push_back(job);
+        drop(state);
+        self.available.notify_one();
+        Ok(())
+    }
+
+    /// Wait for the next job, returning `None` once the queue is closed and
+    /// empty, or when a worker beyond the minimum was idle for the idle
+    /// timeout. The lock is released before returning, so the caller runs
+    /// the job while other workers take the next ones.
+    fn pop(&self) -> Option<Job> {
+        let mut state = self.lock();
+        loop {
+            if let Some(job) = state.jobs.pop_front() {
+                state.busy += 1;
+                if self.capacity.is_some() {
+                    drop(state);
+                    self.space.notify_one();
                 }
+                return Some(job);
             }
-        });
+            if state.closed {
+                state.workers -= 1;
+                return None;
+            }
+
+            let timed_out = if state.workers > self.min_workers {
+                let (guard, result) = self
+                    .available
+                    .wait_timeout(state, self.idle_timeout)
+                    .unwrap_or_else(PoisonError::into_inner);
+                state = guard;
+                result.timed_out()
+            } else {
+                state = self
+                    .available
+                    .wait(state)
+                    .unwrap_or_else(PoisonError::into_inner);
+                false
+            };
+
+            // retire, unless a job arrived or other workers retired first
+            if timed_out
+                && state.jobs.is_empty()
+                && !state.closed
+                && state.workers > self.min_workers
+            {
+                state.workers -= 1;
+                return None;
+            }
+        }
+    }
 
-        Worker {
-            _id: id,
-            _thread: thread,
+    /// Mark a job taken by `pop` as finished
+    fn finish(&self, completed: bool) {
+        let mut state = self.lock();
+        state.busy -= 1;
+        if completed {
+            state.completed += 1;
+        } else {
+            state.panicked += 1;
         }
     }
+
+    fn close(&self) {
+        self.lock().closed = true;
+        self.available.notify_all();
+        self.space.notify_all();
+    }
 }
 
-/// Type alias for the closure arument to ThreadPool.execute()
-type Job = Box<dyn FnOnce() + Send + 'static>;
+/// Message passed to `panic!`, if it was a string
+pub(crate) fn panic_message(panic: &(dyn Any + Send)) -> &str {
+    if let Some(message) = panic.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = panic.downcast_ref::<String>() {
+        message
+    } else {
+        "Box<dyn Any>"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn test_zero_size_pool() {
+        assert!(matches!(
+            ThreadPool::build(0),
+            Err(PoolCreationError::ZeroSize)
+        ));
+    }
+
+    #[test]
+    fn test_zero_capacity_pool() {
+        assert!(matches!(
+            PoolBuilder::new(1).capacity(0).build(),
+            Err(PoolCreationError::ZeroCapacity)
+        ));
+    }
+
+    #[test]
+    fn test_max_below_min() {
+        assert!(matches!(
+            PoolBuilder::new(2).max_workers(1).build(),
+            Err(PoolCreationError::MaxBelowMin)
+        ));
+    }
+
+    #[test]
+    fn test_grows_and_retires_workers() {
+        let pool = PoolBuilder::new(1)
+            .max_workers(4)
+            .idle_timeout(Duration::from_millis(100))
+            .build()
+            .unwrap();
+        assert_eq!(pool.queue().workers(), 1);
+
+        // every job blocks until all four run at once
+        let barrier = Arc::new(std::sync::Barrier::new(5));
+        for _ in 0..4 {
+            let barrier = Arc::clone(&barrier);
+            pool.execute(move || {
+                barrier.wait();
+            });
+        }
+        barrier.wait();
+        assert_eq!(pool.queue().workers(), 4);
+
+        // the extra workers retire once idle, down to the minimum
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while pool.queue().workers() > 1 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(20));
+        }
+        assert_eq!(pool.queue().workers(), 1);
+
+        // and the pool grows again when needed
+        let done = Arc::new(AtomicUsize::new(0));
+        for _ in 0..3 {
+            let done = Arc::clone(&done);
+            pool.execute(move || {
+                done.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+        assert!(pool.shutdown(Duration::from_secs(5)));
+        assert_eq!(done.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn test_idle_workers_take_jobs_first() {
+        let pool = PoolBuilder::new(2).max_workers(8).build().unwrap();
+        for _ in 0..10 {
+            let (done, wait_done) = std::sync::mpsc::channel();
+            pool.execute(move || done.send(()).unwrap());
+            wait_done.recv().unwrap();
+            // let the worker finish up after signalling
+            thread::sleep(Duration::from_millis(10));
+        }
+        // one job at a time never needs more than the idle workers
+        assert_eq!(pool.queue().workers(), 2);
+    }
+
+    #[test]
+    fn test_worker_threads() {
+        let pool = PoolBuilder::new(1).stack_size(256 * 1024).build().unwrap();
+        let (name, thread_name) = std::sync::mpsc::channel();
+        pool.execute(move || {
+            name.send(thread::current().name().map(str::to_owned))
+                .unwrap();
+        });
+        assert_eq!(
+            thread_name.recv().unwrap().as_deref(),
+            Some("crag-worker-0")
+        );
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_spawn_error() {
+        // larger than the address space, so the OS refuses the thread
+        let err = PoolBuilder::new(1).stack_size(1 << 50).build().unwrap_err();
+        assert!(matches!(err, PoolCreationError::Spawn(_)), "{err:?}");
+    }
+
+    #[test]
+    fn test_bounded_queue_blocks_when_full() {
+        let pool = PoolBuilder::new(1).capacity(1).build().unwrap();
+        let (release, released) = std::sync::mpsc::channel::<()>();
+        let (started, wait_started) = std::sync::mpsc::channel();
+        pool.execute(move || {
+            started.send(()).unwrap();
+            _ = released.recv();
+        });
+        wait_started.recv().unwrap();
+
+        // the worker is busy, so this job waits in the queue
+        pool.execute(|| {});
+        assert_eq!(pool.queue().len(), 1);
+        assert!(pool.queue().is_full());
+        assert_eq!(pool.queue().capacity(), Some(1));
+
+        // a third job blocks until the worker takes the second one
+        let start = Instant::now();
+        let releaser = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(100));
+            release.send(()).unwrap();
+        });
+        pool.execute(|| {});
+        assert!(start.elapsed() >= Duration::from_millis(100));
+
+        releaser.join().unwrap();
+        assert!(pool.shutdown(Duration::from_secs(5)));
+    }
+
+    #[test]
+    fn test_shutdown_finishes_queued_jobs() {
+        let pool = ThreadPool::build(2).unwrap();
+        let done = Arc::new(AtomicUsize::new(0));
+
+        for _ in 0..4 {
+            let done = Arc::clone(&done);
+            pool.execute(move || {
+                thread::sleep(Duration::from_millis(20));
+                done.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+
+        assert!(pool.shutdown(Duration::from_secs(5)));
+        assert_eq!(done.load(Ordering::SeqCst), 4);
+    }
+
+    #[test]
+    fn test_shutdown_deadline() {
+        let pool = ThreadPool::build(1).unwrap();
+        pool.execute(|| thread::sleep(Duration::from_millis(500)));
+        // give the worker time to pick up the job
+        thread::sleep(Duration::from_millis(50));
+
+        let start = Instant::now();
+        assert!(!pool.shutdown(Duration::from_millis(50)));
+        assert!(start.elapsed() < Duration::from_millis(400));
+    }
+
+    #[test]
+    fn test_worker_survives_panicking_job() {
+        let pool = ThreadPool::build(1).unwrap();
+        let done = Arc::new(AtomicUsize::new(0));
+
+        pool.execute(|| panic!("job failed"));
+        for _ in 0..2 {
+            let done = Arc::clone(&done);
+            pool.execute(move || {
+                done.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+
+        assert!(pool.shutdown(Duration::from_secs(5)));
+        assert_eq!(done.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_panic_message() {
+        let panic = panic::catch_unwind(|| panic!("static message")).unwrap_err();
+        assert_eq!(panic_message(&*panic), "static message");
+
+        let panic = panic::catch_unwind(|| panic!("formatted {}", 42)).unwrap_err();
+        assert_eq!(panic_message(&*panic), "formatted 42");
+    }
+
+    #[test]
+    fn test_jobs_run_concurrently() {
+        let pool = ThreadPool::build(4).unwrap();
+        let start = Instant::now();
+        for _ in 0..4 {
+            pool.execute(|| thread::sleep(Duration::from_millis(200)));
+        }
+        assert!(pool.shutdown(Duration::from_secs(5)));
+
+        // serialized jobs would take 800ms
+        assert!(start.elapsed() < Duration::from_millis(600));
+    }
+
+    #[test]
+    fn test_drop_joins_workers() {
+        let done = Arc::new(AtomicUsize::new(0));
+        {
+            let pool = ThreadPool::build(2).unwrap();
+            for _ in 0..2 {
+                let done = Arc::clone(&done);
+                pool.execute(move || {
+                    thread::sleep(Duration::from_millis(20));
+                    done.fetch_add(1, Ordering::SeqCst);
+                });
+            }
+        }
+        assert_eq!(done.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_spawn_returns_result() {
+        let pool = ThreadPool::build(2).unwrap();
+        let handles: Vec<_> = (0..4_u64).map(|n| pool.spawn(move || n * n)).collect();
+        let squares: Vec<u64> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+        assert_eq!(squares, [0, 1, 4, 9]);
+    }
+
+    #[test]
+    fn test_spawn_returns_panic_payload() {
+        let pool = ThreadPool::build(1).unwrap();
+        let panic = pool.spawn(|| panic!("job failed")).join().unwrap_err();
+        assert_eq!(panic_message(&*panic), "job failed");
+
+        // the worker keeps running jobs
+        assert_eq!(pool.spawn(|| 7).join().unwrap(), 7);
+    }
+
+    #[test]
+    fn test_spawn_after_shutdown() {
+        let pool = ThreadPool::build(1).unwrap();
+        let handle = pool.handle();
+        assert!(pool.shutdown(Duration::from_secs(5)));
+
+        let panic = handle.spawn(|| 1).join().unwrap_err();
+        assert_eq!(panic_message(&*panic), "pool is shut down");
+        // dropped, not run
+        handle.execute(|| unreachable!());
+    }
+
+    #[test]
+    fn test_scope_borrows_local_data() {
+        let pool = ThreadPool::build(4).unwrap();
+        let numbers: Vec<u64> = (1..=1000).collect();
+        let mut total = 0;
+
+        pool.scope(|scope| {
+            let handles: Vec<_> = numbers
+                .chunks(100)
+                .map(|chunk| scope.spawn(move || chunk.iter().sum::<u64>()))
+                .collect();
+            total = handles.into_iter().map(|h| h.join().unwrap()).sum();
+        });
+        assert_eq!(total, 500_500);
+    }
+
+    #[test]
+    fn test_scope_waits_for_jobs() {
+        let pool = ThreadPool::build(2).unwrap();
+        let done = AtomicUsize::new(0);
+
+        pool.scope(|scope| {
+            for _ in 0..4 {
+                // handles dropped without joining
+                scope.spawn(|| {
+                    thread::sleep(Duration::from_millis(20));
+                    done.fetch_add(1, Ordering::SeqCst);
+                });
+            }
+        });
+        assert_eq!(done.load(Ordering::SeqCst), 4);
+    }
+
+    #[test]
+    fn test_scope_panics_on_unjoined_panic() {
+        let pool = ThreadPool::build(1).unwrap();
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            pool.scope(|scope| {
+                scope.spawn(|| panic!("job failed"));
+            })
+        }));
+        assert_eq!(
+            panic_message(&*result.unwrap_err()),
+            "a scoped job panicked"
+        );
+
+        // a panic received through `join` is handled
+        let panicked = pool.scope(|scope| scope.spawn(|| panic!("job failed")).join().is_err());
+        assert!(panicked);
+    }
+
+    #[test]
+    fn test_stats() {
+        let pool = ThreadPool::build(2).unwrap();
+        assert_eq!(
+            pool.stats(),
+            PoolStats {
+                workers: 2,
+                idle: 2,
+                ..PoolStats::default()
+            }
+        );
+
+        let (release, released) = std::sync::mpsc::channel::<()>();
+        let (started, wait_started) = std::sync::mpsc::channel();
+        let blocked = pool.spawn(move || {
+            started.send(()).unwrap();
+            released.recv().unwrap();
+        });
+        wait_started.recv().unwrap();
+        let stats = pool.stats();
+        assert_eq!((stats.active, stats.idle), (1, 1));
+
+        _ = pool.spawn(|| panic!("job failed")).join();
+        release.send(()).unwrap();
+        blocked.join().unwrap();
+
+        let handle = pool.handle();
+        assert!(pool.shutdown(Duration::from_secs(5)));
+        let stats = handle.stats();
+        assert_eq!((stats.completed, stats.panicked), (1, 1));
+        assert_eq!((stats.workers, stats.active, stats.queued), (0, 0, 0));
+    }
+}